@@ -0,0 +1,126 @@
+use std::time::Instant;
+
+use config::Config;
+use riker::actors::*;
+
+use crate::{CommandResult, ControlMsg, Entity, EntityMsg};
+
+/// When instances reply to the sender of a command
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AckMode {
+    /// Reply as soon as the command is accepted, before its events are
+    /// persisted. This is the default.
+    ///
+    /// The sender may receive `CommandResult::Accepted` for events that
+    /// are never persisted if the system stops or the event store fails
+    /// before they are stored.
+    Accepted,
+
+    /// Reply once all events produced by the command have been persisted,
+    /// i.e. applied by the instance. Rejected commands and commands that
+    /// produce no events are replied to immediately.
+    Persisted,
+}
+
+impl Default for AckMode {
+    fn default() -> Self {
+        AckMode::Accepted
+    }
+}
+
+/// Ack mode configured for the named entity.
+///
+/// Reads `cqrs.entities.<name>.ack_mode`, falling back to `cqrs.ack_mode`.
+/// Either "accepted" or "persisted", defaults to `AckMode::Accepted`.
+pub fn ack_mode(config: &Config, name: &str) -> AckMode {
+    let mode = config.get_str(&format!("cqrs.entities.{}.ack_mode", name))
+                    .or_else(|_| config.get_str("cqrs.ack_mode"));
+
+    match mode {
+        Ok(ref mode) if mode == "persisted" => AckMode::Persisted,
+        _ => AckMode::Accepted,
+    }
+}
+
+/// Replies to commands according to an `AckMode`.
+///
+/// Instances reply to commands using `Acks::reply` instead of
+/// `Entity::reply` and call `Acks::persisted` from `apply_event`.
+/// riker processes no other messages between persisting an event and
+/// applying it, so at most one command is waiting for its events.
+///
+/// `Acks` also measures how long events take to persist, from the call
+/// to `reply` to the call to `persisted` for each event, and reports it
+/// to the entity manager. See `EntityStats::persist_latency`.
+pub struct Acks<Msg: Message> {
+    mode: AckMode,
+    pending: Option<(Option<ActorRef<Msg>>, CommandResult<Msg>, usize)>,
+    // when the events of the last command were replied to,
+    // and the number of them not yet persisted
+    persisting: Option<(Instant, usize)>,
+}
+
+impl<Msg: EntityMsg> Acks<Msg> {
+    pub fn new(mode: AckMode) -> Self {
+        Acks {
+            mode,
+            pending: None,
+            persisting: None,
+        }
+    }
+
+    /// Replies to the sender of a command with the outcome of the command,
+    /// or waits until its events are persisted in `AckMode::Persisted`
+    pub fn reply(&mut self,
+                ctx: &Context<Msg>,
+                id: &str,
+                sender: Option<ActorRef<Msg>>,
+                result: CommandResult<Msg>) {
+        let events = match result {
+            CommandResult::Accepted(ref events) => events.len(),
+            _ => 0
+        };
+
+        if events > 0 {
+            self.persisting = Some((Instant::now(), events));
+        }
+
+        if self.mode == AckMode::Persisted && events > 0 && sender.is_some() {
+            self.pending = Some((sender, result, events));
+        } else {
+            Entity::reply(ctx, id, sender, result);
+        }
+    }
+
+    /// Notifies that an event was persisted. Call from `apply_event`.
+    pub fn persisted(&mut self, ctx: &Context<Msg>, id: &str) {
+        let latency = match self.persisting {
+            Some((started, ref mut remaining)) => {
+                *remaining -= 1;
+                Some(started.elapsed())
+            }
+            None => None
+        };
+
+        if let Some(latency) = latency {
+            ctx.myself().parent().tell(ControlMsg::EventPersisted(id.to_string(), latency), Some(ctx.myself()));
+            if let Some((_, 0)) = self.persisting {
+                self.persisting = None;
+            }
+        }
+
+        let done = match self.pending {
+            Some((_, _, ref mut remaining)) => {
+                *remaining -= 1;
+                *remaining == 0
+            }
+            None => false
+        };
+
+        if done {
+            if let Some((sender, result, _)) = self.pending.take() {
+                Entity::reply(ctx, id, sender, result);
+            }
+        }
+    }
+}
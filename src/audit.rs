@@ -0,0 +1,47 @@
+use std::fmt;
+use std::time::SystemTime;
+
+/// What happened to an instance, see `AuditEntry`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AuditKind {
+    /// The instance was woken and replays its events
+    Woken,
+
+    /// The instance was passivated, by a sweep or on request
+    Passivated,
+
+    /// The instance was stopped other than by passivation, e.g. after a
+    /// failure or an init timeout
+    Stopped,
+}
+
+/// A record of an instance becoming active or inactive
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuditEntry {
+    pub entity: String,
+    pub id: String,
+    pub kind: AuditKind,
+    pub at: SystemTime,
+}
+
+/// Receives an entry each time an instance of the entity is woken,
+/// passivated or stopped, e.g. to keep an audit trail of when each
+/// aggregate was active. Set using `EntityActorConfig::audit_sink`.
+///
+/// `record` is called on the entity manager, in the order the manager
+/// wakes and stops instances, before the instance replays its events and
+/// right after it is asked to stop. The manager routes no commands while
+/// `record` runs, so a sink that blocks until the entry is stored slows
+/// the entity down but records every transition the manager makes, while
+/// a sink that hands entries off, e.g. to a channel, is best-effort.
+/// Nothing is recorded for instances stopped along with the manager, and
+/// entries may be lost if the process dies.
+pub trait AuditSink: Send + Sync {
+    fn record(&self, entry: AuditEntry);
+}
+
+impl fmt::Debug for dyn AuditSink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("AuditSink")
+    }
+}
@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// A limit on the number of instances running at once, shared by
+/// entity managers.
+///
+/// Each manager configured with the same budget, using
+/// `EntityActorConfig::residency_budget`, takes a token from it for every
+/// instance it wakes and returns it when the instance is passivated.
+/// Commands that would wake an instance while no tokens are left wait
+/// in the manager, as with `EntityActorConfig::max_concurrent_wakes`.
+///
+/// Tokens are not handed out fairly. When a token is returned, every
+/// manager waiting for one is notified and the first to wake an instance
+/// takes it, so a manager with a steady stream of wakes can hold most of
+/// the budget while others wait.
+#[derive(Clone)]
+pub struct ResidencyBudget {
+    inner: Arc<Mutex<Budget>>,
+}
+
+// managers are identified by their actor path, as managers in different
+// groups can have the same name
+struct Budget {
+    capacity: usize,
+    held: HashMap<String, usize>,
+    waiting: HashMap<String, Box<dyn Fn() + Send>>,
+}
+
+impl Budget {
+    fn used(&self) -> usize {
+        self.held.values().sum()
+    }
+}
+
+impl ResidencyBudget {
+    pub fn new(capacity: usize) -> Self {
+        let budget = Budget {
+            capacity,
+            held: HashMap::new(),
+            waiting: HashMap::new(),
+        };
+
+        ResidencyBudget {
+            inner: Arc::new(Mutex::new(budget))
+        }
+    }
+
+    /// Maximum number of instances running at once
+    pub fn capacity(&self) -> usize {
+        self.inner.lock().unwrap().capacity
+    }
+
+    /// Number of instances running across all managers using the budget
+    pub fn used(&self) -> usize {
+        self.inner.lock().unwrap().used()
+    }
+
+    // whether a token is available, otherwise `notify` is called
+    // once one is returned
+    pub(crate) fn available<F>(&self, manager: &str, notify: F) -> bool
+        where F: Fn() + Send + 'static
+    {
+        let mut budget = self.inner.lock().unwrap();
+        if budget.used() < budget.capacity {
+            true
+        } else {
+            budget.waiting.insert(manager.to_string(), Box::new(notify));
+            false
+        }
+    }
+
+    // takes a token for the manager with the given path if one is
+    // available, otherwise `notify` is called once one is returned
+    pub(crate) fn try_acquire<F>(&self, manager: &str, notify: F) -> bool
+        where F: Fn() + Send + 'static
+    {
+        let mut budget = self.inner.lock().unwrap();
+        if budget.used() < budget.capacity {
+            *budget.held.entry(manager.to_string()).or_insert(0) += 1;
+            true
+        } else {
+            budget.waiting.insert(manager.to_string(), Box::new(notify));
+            false
+        }
+    }
+
+    // returns a token taken by the manager with the given path
+    pub(crate) fn release(&self, manager: &str) {
+        let waiting = {
+            let mut budget = self.inner.lock().unwrap();
+            if let Some(held) = budget.held.get_mut(manager) {
+                *held = held.saturating_sub(1);
+            }
+            budget.waiting.drain().collect::<Vec<_>>()
+        };
+
+        for (_, notify) in waiting.into_iter() {
+            notify();
+        }
+    }
+
+    // returns all tokens taken by the manager with the given path, e.g.
+    // when it stops or restarts without passivating its instances
+    pub(crate) fn reset(&self, manager: &str) {
+        let waiting = {
+            let mut budget = self.inner.lock().unwrap();
+            budget.waiting.remove(manager);
+            match budget.held.remove(manager) {
+                Some(held) if held > 0 => budget.waiting.drain().collect::<Vec<_>>(),
+                _ => Vec::new()
+            }
+        };
+
+        for (_, notify) in waiting.into_iter() {
+            notify();
+        }
+    }
+}
+
+impl fmt::Debug for ResidencyBudget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let budget = self.inner.lock().unwrap();
+        f.debug_struct("ResidencyBudget")
+            .field("capacity", &budget.capacity)
+            .field("used", &budget.used())
+            .finish()
+    }
+}
@@ -0,0 +1,130 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use riker::actors::*;
+
+/// Bounded cache of the last known state of instances, see
+/// `EntityActorConfig::state_cache`
+#[derive(Clone, Debug)]
+pub struct StateCache {
+    /// Maximum number of cached states. The oldest is evicted first.
+    pub capacity: usize,
+
+    /// How long a cached state can be served for
+    pub ttl: Duration,
+}
+
+pub(crate) struct CachedStates<Msg> {
+    conf: StateCache,
+    states: HashMap<String, (Option<Box<Msg>>, Instant)>,
+    seq: u64,
+    // sequence numbers of the `GetState` queries routed to each instance
+    // and not yet answered, and of the last command routed after them
+    queries: HashMap<String, VecDeque<u64>>,
+    writes: HashMap<String, u64>,
+    // instances stopped without a command since, whose final state
+    // can be cached when they hand it off
+    stopped: HashMap<String, Instant>,
+}
+
+impl<Msg: Message> CachedStates<Msg> {
+    pub(crate) fn new(conf: &StateCache) -> Self {
+        CachedStates {
+            conf: conf.clone(),
+            states: HashMap::new(),
+            seq: 0,
+            queries: HashMap::new(),
+            writes: HashMap::new(),
+            stopped: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn get(&mut self, id: &str) -> Option<Option<Box<Msg>>> {
+        let expired = match self.states.get(id) {
+            Some((_, cached)) => cached.elapsed() > self.conf.ttl,
+            None => return None
+        };
+
+        if expired {
+            self.states.remove(id);
+            None
+        } else {
+            self.states.get(id).map(|(state, _)| state.clone())
+        }
+    }
+
+    pub(crate) fn queried(&mut self, id: &str) {
+        self.seq += 1;
+        self.queries.entry(id.to_string()).or_default().push_back(self.seq);
+    }
+
+    // a command invalidates the cached state, and the answers
+    // to queries that were routed before it
+    pub(crate) fn written(&mut self, id: &str) {
+        self.seq += 1;
+        self.states.remove(id);
+        self.stopped.remove(id);
+        if self.queries.contains_key(id) {
+            self.writes.insert(id.to_string(), self.seq);
+        }
+    }
+
+    // instances answer queries in the order they were routed
+    pub(crate) fn answered(&mut self, id: &str, state: Option<Box<Msg>>) {
+        let query = match self.queries.get_mut(id) {
+            Some(queries) => queries.pop_front(),
+            None => None
+        };
+
+        if let Some(query) = query {
+            let current = self.writes.get(id).map(|write| *write < query).unwrap_or(true);
+            if current {
+                self.insert(id, state);
+            }
+        }
+
+        if self.queries.get(id).map(|queries| queries.is_empty()).unwrap_or(false) {
+            self.forget(id);
+        }
+    }
+
+    // instances that never hand off their state are dropped after the ttl
+    pub(crate) fn stopped(&mut self, id: &str) {
+        let ttl = self.conf.ttl;
+        if self.stopped.len() >= self.conf.capacity {
+            self.stopped.retain(|_, stopped| stopped.elapsed() <= ttl);
+        }
+        self.stopped.insert(id.to_string(), Instant::now());
+    }
+
+    // the final state of a stopped instance, unless a command
+    // was received for the ID since it was stopped
+    pub(crate) fn handed_off(&mut self, id: &str, state: Option<Box<Msg>>) {
+        if let Some(stopped) = self.stopped.remove(id) {
+            if stopped.elapsed() <= self.conf.ttl {
+                self.insert(id, state);
+            }
+        }
+    }
+
+    fn insert(&mut self, id: &str, state: Option<Box<Msg>>) {
+        if !self.states.contains_key(id) && self.states.len() >= self.conf.capacity {
+            let oldest = self.states.iter()
+                                    .min_by_key(|(_, (_, cached))| *cached)
+                                    .map(|(id, _)| id.clone());
+            if let Some(oldest) = oldest {
+                self.states.remove(&oldest);
+            }
+        }
+
+        if self.conf.capacity > 0 {
+            self.states.insert(id.to_string(), (state, Instant::now()));
+        }
+    }
+
+    // queries to a passivated instance are never answered
+    pub(crate) fn forget(&mut self, id: &str) {
+        self.queries.remove(id);
+        self.writes.remove(id);
+    }
+}
@@ -0,0 +1,100 @@
+use std::fmt;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+
+/// A single timer that tells entity managers when to run their
+/// passivation sweep, shared by managers instead of each scheduling its
+/// own tick.
+///
+/// Each manager configured with the same coordinator, using
+/// `EntityActorConfig::sweep_coordinator`, registers with it when it
+/// starts and is removed when it stops. Sweeps are staggered: the
+/// coordinator tells one manager at a time, spread evenly over the
+/// interval, so that each manager sweeps once per interval and their
+/// passivations don't all happen at once.
+///
+/// The timer runs on its own thread, which ends once the coordinator
+/// and every manager using it are dropped. Managers without a
+/// coordinator schedule their own tick.
+#[derive(Clone)]
+pub struct SweepCoordinator {
+    inner: Arc<Mutex<Coordinator>>,
+}
+
+struct Coordinator {
+    interval: Duration,
+    managers: Vec<(String, Box<dyn Fn() + Send>)>,
+    next: usize,
+}
+
+impl SweepCoordinator {
+    pub fn new(interval: Duration) -> Self {
+        let coordinator = Coordinator {
+            interval,
+            managers: Vec::new(),
+            next: 0,
+        };
+
+        let inner = Arc::new(Mutex::new(coordinator));
+        let weak = Arc::downgrade(&inner);
+        thread::spawn(move || run(weak));
+
+        SweepCoordinator { inner }
+    }
+
+    /// How often each manager sweeps
+    pub fn interval(&self) -> Duration {
+        self.inner.lock().unwrap().interval
+    }
+
+    /// Number of managers registered
+    pub fn managers(&self) -> usize {
+        self.inner.lock().unwrap().managers.len()
+    }
+
+    // registers the named manager, replacing an earlier registration,
+    // e.g. when it restarts. `sweep` is called when it should sweep
+    pub(crate) fn register<F>(&self, manager: &str, sweep: F)
+        where F: Fn() + Send + 'static
+    {
+        let mut coordinator = self.inner.lock().unwrap();
+        coordinator.managers.retain(|(name, _)| name != manager);
+        coordinator.managers.push((manager.to_string(), Box::new(sweep)));
+    }
+
+    pub(crate) fn unregister(&self, manager: &str) {
+        self.inner.lock().unwrap().managers.retain(|(name, _)| name != manager);
+    }
+}
+
+// tells the managers to sweep in turn until the coordinator is dropped
+fn run(coordinator: Weak<Mutex<Coordinator>>) {
+    loop {
+        let step = match coordinator.upgrade() {
+            Some(coordinator) => {
+                let mut coordinator = coordinator.lock().unwrap();
+                let count = coordinator.managers.len();
+                if count > 0 {
+                    let next = coordinator.next % count;
+                    (coordinator.managers[next].1)();
+                    coordinator.next = next + 1;
+                }
+                coordinator.interval / count.max(1) as u32
+            }
+            None => return
+        };
+
+        thread::sleep(step);
+    }
+}
+
+impl fmt::Debug for SweepCoordinator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let coordinator = self.inner.lock().unwrap();
+        f.debug_struct("SweepCoordinator")
+            .field("interval", &coordinator.interval)
+            .field("managers", &coordinator.managers.len())
+            .finish()
+    }
+}
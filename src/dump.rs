@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use riker::actors::*;
+
+use crate::{ControlMsg, EntityMsg};
+
+// longest wait for the instances to reply with their state
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The state of running instances, received in response to
+/// `ControlMsg::DumpState`. Requires the `diagnostics` feature.
+#[derive(Clone, Debug)]
+pub struct StateDump<Msg> {
+    /// (ID, State) of each instance that replied, sorted by ID.
+    /// The state is `None` for instances without state.
+    pub states: Vec<(String, Option<Box<Msg>>)>,
+
+    /// IDs of the instances that didn't reply in time, e.g. because they
+    /// don't implement `EntityState` or are busy
+    pub unanswered: Vec<String>,
+
+    /// Number of instances running when the dump was requested, to
+    /// request the next page
+    pub total: usize,
+}
+
+// Asks each instance for its state and replies with all of them
+// once every instance has replied, or the timeout has passed
+pub(crate) struct StateCollector<Msg: Message> {
+    instances: Vec<(String, ActorRef<Msg>)>,
+    states: HashMap<String, Option<Box<Msg>>>,
+    sender: ActorRef<Msg>,
+    total: usize,
+}
+
+pub(crate) type CollectorArgs<Msg> = (Vec<(String, ActorRef<Msg>)>, ActorRef<Msg>, usize);
+
+impl<Msg: EntityMsg> StateCollector<Msg> {
+    pub(crate) fn actor((instances, sender, total): CollectorArgs<Msg>) -> BoxActor<Msg> {
+        Box::new(StateCollector {
+            instances,
+            states: HashMap::new(),
+            sender,
+            total,
+        })
+    }
+
+    fn finish(&mut self, ctx: &Context<Msg>) {
+        let mut states = Vec::new();
+        let mut unanswered = Vec::new();
+        for (id, _) in self.instances.drain(..) {
+            match self.states.remove(&id) {
+                Some(state) => states.push((id, state)),
+                None => unanswered.push(id)
+            }
+        }
+
+        let dump = StateDump { states, unanswered, total: self.total };
+        self.sender.tell(ControlMsg::DumpStateResult(dump), Some(ctx.myself()));
+        ctx.stop(&ctx.myself());
+    }
+}
+
+impl<Msg: EntityMsg> Actor for StateCollector<Msg> {
+    type Msg = Msg;
+
+    fn pre_start(&mut self, ctx: &Context<Msg>) {
+        if self.instances.is_empty() {
+            self.finish(ctx);
+            return;
+        }
+
+        // asked directly, so that the query isn't held or rejected
+        // by the manager like a command
+        for (id, actor) in self.instances.iter() {
+            actor.tell(Msg::from_control(ControlMsg::GetState(id.clone())), Some(ctx.myself()));
+        }
+        ctx.schedule_once(TIMEOUT, ctx.myself(), None, ActorMsg::Tick);
+    }
+
+    fn other_receive(&mut self,
+                    ctx: &Context<Msg>,
+                    msg: ActorMsg<Msg>,
+                    _: Option<ActorRef<Msg>>) {
+        // instances are drained once the dump is sent
+        if let ActorMsg::Tick = msg {
+            if !self.instances.is_empty() {
+                self.finish(ctx);
+            }
+        }
+    }
+
+    fn receive(&mut self, ctx: &Context<Msg>, msg: Msg, _: Option<ActorRef<Msg>>) {
+        if let Ok(ControlMsg::State(id, state)) = msg.into_control() {
+            self.states.insert(id, state);
+            if !self.instances.is_empty() && self.states.len() == self.instances.len() {
+                self.finish(ctx);
+            }
+        }
+    }
+}
@@ -0,0 +1,60 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::ErrorStats;
+
+// counts of replies in a sliding window, kept in buckets of a tenth of
+// the window so that counts expire gradually
+pub(crate) struct ErrorWindow {
+    window: Duration,
+    buckets: VecDeque<(Instant, u64, u64)>,
+}
+
+impl ErrorWindow {
+    const BUCKETS: u32 = 10;
+
+    pub(crate) fn new(window: Duration) -> Self {
+        ErrorWindow {
+            window,
+            buckets: VecDeque::new(),
+        }
+    }
+
+    pub(crate) fn replied(&mut self) {
+        self.current().1 += 1;
+    }
+
+    pub(crate) fn failed(&mut self) {
+        self.current().2 += 1;
+    }
+
+    pub(crate) fn stats(&mut self) -> ErrorStats {
+        self.expire(Instant::now());
+        ErrorStats {
+            window: self.window,
+            total: self.buckets.iter().map(|b| b.1).sum(),
+            failed: self.buckets.iter().map(|b| b.2).sum(),
+        }
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.buckets.clear();
+    }
+
+    fn current(&mut self) -> &mut (Instant, u64, u64) {
+        let now = Instant::now();
+        self.expire(now);
+
+        let started = self.buckets.back().map(|b| now.duration_since(b.0) < self.window / ErrorWindow::BUCKETS);
+        if started != Some(true) {
+            self.buckets.push_back((now, 0, 0));
+        }
+        self.buckets.back_mut().unwrap()
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while self.buckets.front().map(|b| now.duration_since(b.0) >= self.window).unwrap_or(false) {
+            self.buckets.pop_front();
+        }
+    }
+}
@@ -0,0 +1,188 @@
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{self, Poll, Waker};
+use std::time::Duration;
+
+use riker::actors::*;
+use uuid::Uuid;
+
+use crate::{CommandResult, ControlMsg, Entity, EntityMsg, RejectReason};
+
+/// Why a `CommandFuture` completed without a `CommandResult`
+#[derive(Clone, Debug, PartialEq)]
+pub enum EntityError {
+    /// The entity manager rejected the command
+    Rejected(RejectReason),
+
+    /// No reply was received within the timeout. The instance may
+    /// still handle the command.
+    TimedOut,
+
+    /// The actor system stopped, or the reply couldn't be awaited,
+    /// before a reply was received
+    Stopped,
+}
+
+impl fmt::Display for EntityError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EntityError::Rejected(reason) => write!(f, "command rejected: {}", reason),
+            EntityError::TimedOut => write!(f, "timed out waiting for a reply"),
+            EntityError::Stopped => write!(f, "stopped before a reply"),
+        }
+    }
+}
+
+impl Entity {
+    /// Sends a command to the instance with the given ID and returns a
+    /// future of the instance's reply, for code outside of the actor
+    /// system such as async HTTP handlers:
+    ///
+    /// ```ignore
+    /// let cmd = Protocol::AddAmountCmd(100);
+    /// match Entity::command_future(&sys, &em, "1", cmd, Duration::from_secs(5)).await {
+    ///     Ok(CommandResult::Accepted(events)) => ...,
+    ///     Ok(result) => ...,
+    ///     Err(e) => ...,
+    /// }
+    /// ```
+    ///
+    /// The reply is received by a temporary actor, which sends the command
+    /// when it starts, so the command is sent even if the future is never
+    /// polled. Instances must reply using `Entity::reply` or `Acks`. The
+    /// future completes with `EntityError::TimedOut` if no reply arrives
+    /// within the timeout, and with `EntityError::Stopped` if the system
+    /// stops first.
+    pub fn command_future<Msg: EntityMsg>(sys: &ActorSystem<Msg>,
+                                            em: &ActorRef<Msg>,
+                                            id: &str,
+                                            cmd: Msg,
+                                            timeout: Duration) -> CommandFuture<Msg> {
+        let reply = Arc::new(Mutex::new(Reply::default()));
+        let args = (em.clone(), id.to_string(), cmd, timeout, reply.clone());
+        if sys.tmp_actor_of(Props::new_args(Box::new(Replier::actor), args)).is_err() {
+            reply.lock().unwrap().complete(Err(EntityError::Stopped));
+        }
+
+        CommandFuture { reply }
+    }
+}
+
+/// The reply to a command sent using `Entity::command_future`
+pub struct CommandFuture<Msg: Message> {
+    reply: Arc<Mutex<Reply<Msg>>>,
+}
+
+impl<Msg: Message> Future for CommandFuture<Msg> {
+    type Output = Result<CommandResult<Msg>, EntityError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<Self::Output> {
+        let mut reply = self.reply.lock().unwrap();
+        match reply.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                reply.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+// shared by the future and the actor receiving the reply
+struct Reply<Msg> {
+    result: Option<Result<CommandResult<Msg>, EntityError>>,
+    completed: bool,
+    waker: Option<Waker>,
+}
+
+impl<Msg> Default for Reply<Msg> {
+    fn default() -> Self {
+        Reply {
+            result: None,
+            completed: false,
+            waker: None,
+        }
+    }
+}
+
+impl<Msg> Reply<Msg> {
+    // only the first outcome counts
+    fn complete(&mut self, result: Result<CommandResult<Msg>, EntityError>) {
+        if self.completed {
+            return;
+        }
+
+        self.completed = true;
+        self.result = Some(result);
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+// Sends the command and completes the future with the reply
+struct Replier<Msg: Message> {
+    em: ActorRef<Msg>,
+    id: String,
+    cmd: Option<Msg>,
+    timeout: Duration,
+    timer: Option<Uuid>,
+    reply: Arc<Mutex<Reply<Msg>>>,
+}
+
+impl<Msg: EntityMsg> Replier<Msg> {
+    fn actor((em, id, cmd, timeout, reply): (ActorRef<Msg>, String, Msg, Duration, Arc<Mutex<Reply<Msg>>>)) -> BoxActor<Msg> {
+        Box::new(Replier {
+            em,
+            id,
+            cmd: Some(cmd),
+            timeout,
+            timer: None,
+            reply,
+        })
+    }
+
+    fn complete(&mut self, ctx: &Context<Msg>, result: Result<CommandResult<Msg>, EntityError>) {
+        if let Some(timer) = self.timer.take() {
+            ctx.cancel_schedule(timer);
+        }
+        self.reply.lock().unwrap().complete(result);
+        ctx.stop(&ctx.myself());
+    }
+}
+
+impl<Msg: EntityMsg> Actor for Replier<Msg> {
+    type Msg = Msg;
+
+    fn pre_start(&mut self, ctx: &Context<Msg>) {
+        if let Some(cmd) = self.cmd.take() {
+            self.em.tell(CQMsg::Cmd(self.id.clone(), cmd), Some(ctx.myself()));
+        }
+        self.timer = Some(ctx.schedule_once(self.timeout, ctx.myself(), None, ActorMsg::Tick));
+    }
+
+    // e.g. when the system stops before the reply
+    fn post_stop(&mut self) {
+        self.reply.lock().unwrap().complete(Err(EntityError::Stopped));
+    }
+
+    fn other_receive(&mut self,
+                    ctx: &Context<Msg>,
+                    msg: ActorMsg<Msg>,
+                    _: Option<ActorRef<Msg>>) {
+        if let ActorMsg::Tick = msg {
+            self.timer = None;
+            self.complete(ctx, Err(EntityError::TimedOut));
+        }
+    }
+
+    fn receive(&mut self, ctx: &Context<Msg>, msg: Msg, _: Option<ActorRef<Msg>>) {
+        match msg.into_control() {
+            Ok(ControlMsg::CommandResult(_, result)) => self.complete(ctx, Ok(result)),
+            Ok(ControlMsg::CommandRejected(_, reason)) => self.complete(ctx, Err(EntityError::Rejected(reason))),
+            _ => {}
+        }
+    }
+}
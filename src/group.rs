@@ -0,0 +1,106 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender};
+
+use riker::actors::*;
+
+use crate::{Entity, EntityActorConfig, EntityActorProps, EntityMsg, GROUP_PREFIX};
+
+/// Entity managers supervised by a shared parent actor.
+///
+/// Managers created with `EntityGroup::entity` are children of the group's
+/// actor, so stopping the group's actor stops all of its managers and
+/// their instances. Grouped managers are included in `Entity::registry`
+/// and can be stopped individually with `Entity::stop`.
+///
+/// The group restarts a manager that fails. A restarted manager starts
+/// without instances: running instances are stopped, and commands waiting
+/// for an instance to wake are lost. Commands still in the manager's
+/// mailbox are handled by the restarted manager.
+pub struct EntityGroup<Msg: Message> {
+    sys: ActorSystem<Msg>,
+    actor: ActorRef<Msg>,
+    requests: Requests<Msg>,
+}
+
+type Requests<Msg> = Arc<Mutex<VecDeque<CreateRequest<Msg>>>>;
+
+struct CreateRequest<Msg: Message> {
+    props: BoxActorProd<Msg>,
+    name: String,
+    tx: Sender<Result<ActorRef<Msg>, CreateError>>,
+}
+
+impl<Msg: EntityMsg> EntityGroup<Msg> {
+    /// Creates the group's actor
+    pub fn new(sys: &ActorSystem<Msg>, name: &str) -> Result<Self, CreateError> {
+        let requests: Requests<Msg> = Arc::new(Mutex::new(VecDeque::new()));
+        let props = Props::new_args(Box::new(GroupActor::actor), requests.clone());
+        let actor = sys.actor_of(props, &format!("{}{}", GROUP_PREFIX, name))?;
+
+        Ok(EntityGroup {
+            sys: sys.clone(),
+            actor,
+            requests,
+        })
+    }
+
+    /// Creates an entity manager in the group, as `Entity::new`.
+    ///
+    /// Only the group's actor can create its children, so this blocks the
+    /// calling thread until the manager is created and must not be called
+    /// from within an actor.
+    pub fn entity<Pro>(&self,
+                        instance_fact: Pro,
+                        name: &str,
+                        conf: Option<EntityActorConfig>) -> Result<ActorRef<Msg>, CreateError>
+        where Pro: EntityActorProps<Msg=Msg> + 'static
+    {
+        Entity::create(&self.sys, instance_fact, name, conf, |props, manager| {
+            let (tx, rx) = channel();
+            self.requests.lock().unwrap().push_back(CreateRequest {
+                props,
+                name: manager.to_string(),
+                tx,
+            });
+            self.actor.tell(ActorMsg::Tick, None);
+
+            rx.recv().unwrap_or_else(|_| Err(CreateError::Panicked))
+        })
+    }
+
+    /// The group's actor, the parent of all managers in the group
+    pub fn actor(&self) -> &ActorRef<Msg> {
+        &self.actor
+    }
+}
+
+// Creates managers as its children when requested by `EntityGroup::entity`
+struct GroupActor<Msg: Message> {
+    requests: Requests<Msg>,
+}
+
+impl<Msg: EntityMsg> GroupActor<Msg> {
+    fn actor(requests: Requests<Msg>) -> BoxActor<Msg> {
+        Box::new(GroupActor { requests })
+    }
+}
+
+impl<Msg: EntityMsg> Actor for GroupActor<Msg> {
+    type Msg = Msg;
+
+    fn receive(&mut self, _: &Context<Msg>, _: Msg, _: Option<ActorRef<Msg>>) {}
+
+    // `Tick` signals that there are requests to create managers
+    fn other_receive(&mut self,
+                    ctx: &Context<Msg>,
+                    msg: ActorMsg<Msg>,
+                    _: Option<ActorRef<Msg>>) {
+        if let ActorMsg::Tick = msg {
+            let requests: Vec<CreateRequest<Msg>> = self.requests.lock().unwrap().drain(..).collect();
+            for request in requests.into_iter() {
+                let _ = request.tx.send(ctx.actor_of(request.props, &request.name));
+            }
+        }
+    }
+}
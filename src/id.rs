@@ -0,0 +1,32 @@
+use std::fmt;
+
+use uuid::Uuid;
+
+/// Generates the IDs of instances created with `ControlMsg::CreateNew`.
+/// Set using `EntityActorConfig::id_generator`.
+///
+/// Generated IDs must be unique within the entity, including IDs
+/// generated before a restart or by managers of the same entity on other
+/// systems, and must be valid IDs after `EntityActorProps::normalize`.
+/// The manager rejects a generated ID that already exists rather than
+/// routing the create command to the existing instance, but can only
+/// detect this for instances that are running or have persisted events.
+pub trait IdGenerator: Send + Sync {
+    fn generate(&self, entity: &str) -> String;
+}
+
+/// Generates random (version 4) UUIDs, the default `IdGenerator`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct UuidGenerator;
+
+impl IdGenerator for UuidGenerator {
+    fn generate(&self, _: &str) -> String {
+        Uuid::new_v4().to_string()
+    }
+}
+
+impl fmt::Debug for dyn IdGenerator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("IdGenerator")
+    }
+}
@@ -0,0 +1,135 @@
+use std::thread;
+
+use riker::actors::*;
+
+use crate::{keyspace, CommandResult, ControlMsg, Entity, EntityActorProps, EntityMsg};
+
+// wraps an instance created by `EntityActorProps::props`, so that the
+// manager sees the events it persists
+pub(crate) struct Instance<Pro: EntityActorProps> {
+    id: String,
+    actor: BoxActor<Pro::Msg>,
+    props: Pro,
+    // events applied before `post_start` are replayed, not persisted
+    started: bool,
+    exclusive_keyspace: bool,
+    // the keyspace used by another manager, see `EntityActorConfig::exclusive_keyspace`
+    refused: Option<String>,
+}
+
+impl<Pro> Instance<Pro>
+    where Pro: EntityActorProps + 'static
+{
+    pub(crate) fn props(props: &Pro,
+                        id: String,
+                        persistence: PersistenceConf,
+                        exclusive_keyspace: bool) -> BoxActorProd<Pro::Msg> {
+        let inner = props.props(id.clone(), persistence);
+        let props = props.clone();
+        Props::new(Box::new(move || -> BoxActor<Pro::Msg> {
+            let actor = Instance {
+                id: id.clone(),
+                actor: inner.lock().unwrap().produce(),
+                props: props.clone(),
+                started: false,
+                exclusive_keyspace,
+                refused: None
+            };
+
+            Box::new(actor)
+        }))
+    }
+}
+
+impl<Pro> Actor for Instance<Pro>
+    where Pro: EntityActorProps + 'static
+{
+    type Msg = Pro::Msg;
+
+    // the keyspace is claimed before riker replays the events
+    fn pre_start(&mut self, ctx: &Context<Self::Msg>) {
+        if let Some(conf) = self.actor.persistence_conf() {
+            let manager = ctx.myself().parent();
+            if let Err(other) = keyspace::claim(&ctx.system.id().to_string(), &conf.keyspace, manager.path()) {
+                if self.exclusive_keyspace {
+                    error!("CQRS: Entity: {}, State: rejecting commands, keyspace {} is used by {}", self.id, conf.keyspace, other);
+                    self.refused = Some(conf.keyspace);
+                } else {
+                    warn!("CQRS: Entity: {}, State: keyspace {} is also used by {}, their instances may persist conflicting events", self.id, conf.keyspace, other);
+                }
+            }
+        }
+        self.actor.pre_start(ctx);
+    }
+
+    fn post_start(&mut self, ctx: &Context<Self::Msg>) {
+        self.started = true;
+        let _guard = PanicGuard { ctx, id: &self.id };
+        self.actor.post_start(ctx);
+    }
+
+    fn post_stop(&mut self) {
+        self.actor.post_stop();
+    }
+
+    fn receive(&mut self, ctx: &Context<Self::Msg>, msg: Self::Msg, sender: Option<ActorRef<Self::Msg>>) {
+        let msg = match self.refused {
+            Some(ref keyspace) => match msg.into_control() {
+                Ok(msg) => Self::Msg::from_control(msg),
+                Err(_) => {
+                    let reason = format!("Keyspace {} is used by another entity manager", keyspace);
+                    Entity::reply(ctx, &self.id, sender, CommandResult::Rejected(reason));
+                    return;
+                }
+            },
+            None => msg
+        };
+        let _guard = PanicGuard { ctx, id: &self.id };
+        self.actor.receive(ctx, msg, sender);
+    }
+
+    fn other_receive(&mut self, ctx: &Context<Self::Msg>, msg: ActorMsg<Self::Msg>, sender: Option<ActorRef<Self::Msg>>) {
+        let _guard = PanicGuard { ctx, id: &self.id };
+        self.actor.other_receive(ctx, msg, sender);
+    }
+
+    fn system_receive(&mut self, ctx: &Context<Self::Msg>, msg: SystemMsg<Self::Msg>, sender: Option<ActorRef<Self::Msg>>) {
+        self.actor.system_receive(ctx, msg, sender);
+    }
+
+    fn persistence_conf(&self) -> Option<PersistenceConf> {
+        self.actor.persistence_conf()
+    }
+
+    // the event is published before the instance applies it, so that
+    // subscribers receive it before any reply sent from `apply_event`
+    fn apply_event(&mut self, ctx: &Context<Self::Msg>, evt: Self::Msg) {
+        if self.started {
+            let msg = ControlMsg::Event(self.id.clone(), Box::new(evt.clone()));
+            ctx.myself().parent().tell(msg, Some(ctx.myself()));
+            self.props.on_event(&self.id, &evt);
+        }
+        let _guard = PanicGuard { ctx, id: &self.id };
+        self.actor.apply_event(ctx, evt);
+    }
+
+    fn supervisor_strategy(&self) -> Strategy {
+        self.actor.supervisor_strategy()
+    }
+}
+
+// tells the manager that the instance panicked, as the panic unwinds
+// through the wrapper, before riker applies the supervisor strategy
+struct PanicGuard<'a, Msg: EntityMsg> {
+    ctx: &'a Context<Msg>,
+    id: &'a str,
+}
+
+impl<'a, Msg: EntityMsg> Drop for PanicGuard<'a, Msg> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            let msg = ControlMsg::Panicked(self.id.to_string());
+            self.ctx.myself().parent().tell(msg, Some(self.ctx.myself()));
+        }
+    }
+}
@@ -0,0 +1,27 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+lazy_static! {
+    // the path of the manager using each keyspace, by actor system ID
+    // and keyspace. Instances persist to the keyspace of their own
+    // `PersistenceConf`, so it's claimed as each instance starts
+    static ref KEYSPACES: Mutex<HashMap<(String, String), String>> = Mutex::new(HashMap::new());
+}
+
+// claims a keyspace of the system for the manager with the given path,
+// unless another manager already uses it, whose path is returned
+pub(crate) fn claim(system: &str, keyspace: &str, manager: &str) -> Result<(), String> {
+    let mut keyspaces = KEYSPACES.lock().unwrap();
+    let owner = keyspaces.entry((system.to_string(), keyspace.to_string()))
+                        .or_insert_with(|| manager.to_string());
+    if owner == manager {
+        Ok(())
+    } else {
+        Err(owner.clone())
+    }
+}
+
+// releases the keyspaces claimed for the manager with the given path
+pub(crate) fn release(system: &str, manager: &str) {
+    KEYSPACES.lock().unwrap().retain(|(sys, _), owner| sys != system || owner != manager);
+}
@@ -1,336 +1,6436 @@
-#[macro_use]
-extern crate log;
-
-use std::sync::{Arc, Mutex};
-use std::collections::HashMap;
-use std::time::{SystemTime, Duration};
-
-use config::Config;
-use riker::actors::*;
-
-pub trait EntityActorProps : Clone + Send + Sync {
-    type Msg: Message;
-    
-    fn props(&self, id: String) -> BoxActorProd<Self::Msg>;
-}
-
-impl<Msg, T> EntityActorProps for Arc<Mutex<T>>
-    where Msg: Message, T: EntityActorProps<Msg=Msg>
-{
-    type Msg = Msg;
-
-    fn props(&self, id: String) -> BoxActorProd<Self::Msg> {
-        self.lock().unwrap().props(id)
-    }
-}
-
-pub struct Entity;
-
-impl Entity {
-    pub fn new<Pro, Msg>(sys: &ActorSystem<Msg>,
-                    instance_fact: Pro,
-                    name: &str,
-                    conf: Option<EntityActorConfig>) -> Result<ActorRef<Msg>, CreateError>
-        where Pro: EntityActorProps<Msg=Msg> + 'static, Msg: Message
-    {
-        let conf = conf.unwrap_or(EntityActorConfig::from(&sys.config()));
-        let props = EntityActor::props(name, instance_fact, conf);
-        let actor = sys.actor_of(props, &format!("entity-{}", name))?;
-
-        Ok(actor)
-    }   
-}
-
-struct EntityActor<Pro, Msg: Message> {
-    name: String,
-    props: Pro,
-    instances: HashMap<String, EntityInstance<Msg>>,
-    sleep_after: Duration,
-}
-
-impl<Pro, Msg> EntityActor<Pro, Msg>
-    where Pro: EntityActorProps<Msg=Msg> + 'static, Msg: Message
-{
-    fn props(name: &str,
-            instance_fact: Pro,
-            conf: EntityActorConfig) -> BoxActorProd<Msg> {
-        Props::new_args(
-            Box::new(Self::actor),
-            (name.into(),
-            instance_fact, conf)
-        )
-    }
-
-    fn actor((name, instance_fact, conf): (String, Pro, EntityActorConfig)) -> BoxActor<Msg> {
-        let actor = EntityActor {
-            name,
-            props: instance_fact,
-            instances: HashMap::new(),
-            sleep_after: Duration::from_secs(conf.sleep_after_secs)
-        };
-        Box::new(actor)
-    }
-
-    fn handle_cmd(&mut self,
-                    ctx: &Context<Msg>,
-                    id: String,
-                    cmd: Msg,
-                    sender: Option<ActorRef<Msg>>) {
-
-        if self.instances.contains_key(&id) {
-            trace!("CQRS: Entity: {}, ID: {}, CMD: {:?}, State: running", self.name, id, cmd);
-            let entity = self.instances.get_mut(&id).unwrap();
-            entity.actor.tell(cmd, sender);
-            entity.last_used = SystemTime::now();
-        } else {
-            trace!("CQRS: Entity: {}, ID: {}, CMD: {:?}, State: asleep", self.name, id, cmd);
-            let entity = ctx.actor_of(self.props.props(id.clone()), id.as_ref()).unwrap();
-            entity.tell(cmd, sender);
-
-            let entity = EntityInstance {
-                actor: entity,
-                last_used: SystemTime::now()
-            };
-            self.instances.insert(id, entity);
-        }
-    }
-
-    fn schedule_tick(ctx: &Context<Msg>) {
-        ctx.schedule_once(Duration::from_secs(60),
-                            ctx.myself(),
-                            None,
-                            ActorMsg::Tick);
-    }
-
-    fn sleep_instances(&mut self, ctx: &Context<Msg>) {
-        let count = self.instances.len(); 
-        let threshhold = SystemTime::now() - self.sleep_after;
-
-        let (stop, keep): (Vec<(String, EntityInstance<Msg>)>, Vec<(String, EntityInstance<Msg>)>) =
-            self.instances
-                .drain()
-                .partition(|&(_, ref instance)| threshhold > instance.last_used);
-
-        // stop instances
-        for instance in stop.into_iter() {
-            ctx.stop(&instance.1.actor);
-        }
-
-        // keep instances that are not due to sleep
-        for instance in keep.into_iter() {
-            self.instances.insert(instance.0, instance.1);
-        }
-
-        trace!("CQRS: Number of instances put to sleep: {}", count - self.instances.len());
-    }
-}
-
-impl<Pro, Msg> Actor for EntityActor<Pro, Msg>
-    where Pro: EntityActorProps<Msg=Msg> + 'static, Msg: Message
-{
-    type Msg = Msg;
-
-    fn pre_start(&mut self, ctx: &Context<Msg>) {
-        Self::schedule_tick(ctx);
-    }
-
-    fn receive(&mut self, _: &Context<Msg>, _: Msg, _: Option<ActorRef<Msg>>) {}
-
-    fn other_receive(&mut self,
-                    ctx: &Context<Msg>,
-                    msg: ActorMsg<Msg>,
-                    sender: Option<ActorRef<Msg>>) {
-        match msg {
-            ActorMsg::CQ(cq) => {
-                match cq {
-                    CQMsg::Cmd(id, cmd) => self.handle_cmd(ctx, id, cmd, sender),
-                } 
-            }
-            ActorMsg::Tick => {
-                self.sleep_instances(ctx);
-                Self::schedule_tick(ctx);
-            }
-            _ => {}
-        }
-    }
-}
-
-struct EntityInstance<Msg: Message> {
-    last_used: SystemTime,
-    actor: ActorRef<Msg>,
-}
-
-#[derive(Clone, Debug)]
-pub struct EntityActorConfig {
-    sleep_after_secs: u64,
-}
-
-impl<'a> From<&'a Config> for EntityActorConfig {
-    fn from(config: &Config) -> Self {
-        EntityActorConfig {
-            sleep_after_secs: config.get_int("cqrs.sleep_after_secs").unwrap() as u64
-        }
-    }
-}
-
-
-#[cfg(test)]
-mod tests {
-    use std::{thread, time};
-    use riker::actors::*;
-    use riker_default::DefaultModel;
-
-    use crate::{Entity, EntityActorProps};
-
-    #[derive(Clone, Debug)]
-    pub enum TestMsg {
-        CreateAccountCmd(String),
-        AddAmountCmd(i32),
-
-        AccountCreatedEvt(BankAccount),
-        AmountAddedEvt(i32),
-    }
-
-    impl Into<ActorMsg<TestMsg>> for TestMsg {
-        fn into(self) -> ActorMsg<TestMsg> {
-            ActorMsg::User(self)
-        }
-    }
-
-    #[derive(Clone, Debug)]
-    pub struct BankAccount {
-        id: String,
-        name: String,
-        balance: i32,
-    }
-
-    pub struct BankAccountActor {
-        id: String,
-        state: Option<BankAccount>
-    }
-
-    impl BankAccountActor {
-        pub fn new(id: String) -> BoxActor<TestMsg> {
-            let actor = BankAccountActor {
-                id: id,
-                state: None
-            };
-
-            Box::new(actor)
-        }
-
-        fn create_account(&mut self, ctx: &Context<TestMsg>, cmd: TestMsg) {
-            match cmd {
-                TestMsg::CreateAccountCmd(name) => {
-                    let account = BankAccount {
-                        id: self.id.clone(),
-                        name: name,
-                        balance: 0
-                    };
-
-                    ctx.persist_event(TestMsg::AccountCreatedEvt(account));
-                }
-                _ => {
-                    println!("Can't update a non-existing account");
-                }
-            }
-        }
-
-        fn update_account(&mut self, ctx: &Context<TestMsg>, cmd: TestMsg) {
-            match cmd {
-                TestMsg::AddAmountCmd(amount) => self.add_amount(ctx, amount),
-                _ => {}
-            }
-        }
-
-        fn add_amount(&mut self, ctx: &Context<TestMsg>, amount: i32) {
-            match self.state {
-                Some(ref account) => {
-                    println!("Current balance {}", account.balance);
-                    ctx.persist_event(TestMsg::AmountAddedEvt(amount));
-                }
-                None => {}
-            }
-        }
-    }
-
-    impl Actor for BankAccountActor {
-        type Msg = TestMsg;
-        
-        fn receive(&mut self,
-                    ctx: &Context<TestMsg>,
-                    msg: TestMsg,
-                    _: Option<ActorRef<TestMsg>>) {
-            match self.state {
-                Some(_) => self.update_account(ctx, msg),
-                None => self.create_account(ctx, msg)
-            }
-        }
-
-        fn apply_event(&mut self, _: &Context<Self::Msg>, evt: Self::Msg) {
-            println!("apply event {:?}", evt);
-            match evt {
-                TestMsg::AccountCreatedEvt(account) => self.state = Some(account),
-                TestMsg::AmountAddedEvt(amount) => self.state.as_mut().unwrap().balance += amount,
-                _ => {}
-            }
-        }
-
-        fn persistence_conf(&self) -> Option<PersistenceConf> {
-            Some(PersistenceConf {
-                id: self.id.clone(),
-                keyspace: "persist_test".to_string()
-            })
-        }
-    }
-
-    #[derive(Clone)]
-    pub struct BankAccountActorFact;
-
-    impl EntityActorProps for BankAccountActorFact {
-        type Msg = TestMsg;
-
-        fn props(&self, id: String) -> BoxActorProd<Self::Msg> {
-            Props::new_args(Box::new(BankAccountActor::new), id)
-        }
-    } 
-
-    #[test]
-    fn cqrs() {
-        let model: DefaultModel<TestMsg> = DefaultModel::new();
-        let system = ActorSystem::new(&model).unwrap();
-        
-        let em = Entity::new(&system,
-                            BankAccountActorFact,
-                            "BankAccont",
-                            None).unwrap();
-
-        let number = "12345678".to_string();
-        let name = "Alex Kamal".to_string();
-        em.tell(CQMsg::Cmd(number.clone(), TestMsg::CreateAccountCmd(name)), None);
-
-        let number2 = "87654321".to_string();
-        let name2 = "Jules-Pierre Mao".to_string();
-        em.tell(CQMsg::Cmd(number2.clone(), TestMsg::CreateAccountCmd(name2)), None);
-
-        let number3 = "65555555".to_string();
-        let name3 = "J. Miller".to_string();
-        em.tell(CQMsg::Cmd(number3.clone(), TestMsg::CreateAccountCmd(name3)), None);
-
-
-        em.tell(CQMsg::Cmd(number.clone(), TestMsg::AddAmountCmd(100)), None);
-        em.tell(CQMsg::Cmd(number.clone(), TestMsg::AddAmountCmd(100)), None);
-        em.tell(CQMsg::Cmd(number, TestMsg::AddAmountCmd(100)), None);
-
-        em.tell(CQMsg::Cmd(number2.clone(), TestMsg::AddAmountCmd(50)), None);
-        em.tell(CQMsg::Cmd(number2.clone(), TestMsg::AddAmountCmd(50)), None);
-        em.tell(CQMsg::Cmd(number2.clone(), TestMsg::AddAmountCmd(50)), None);
-
-        em.tell(CQMsg::Cmd(number3.clone(), TestMsg::AddAmountCmd(50)), None);
-        em.tell(CQMsg::Cmd(number3.clone(), TestMsg::AddAmountCmd(50)), None);
-        em.tell(CQMsg::Cmd(number3.clone(), TestMsg::AddAmountCmd(50)), None);
-
-        thread::sleep(time::Duration::from_secs(2));
-        system.print_tree();
-    }
-}
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate lazy_static;
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{SystemTime, Duration, Instant};
+
+use config::Config;
+use riker::actors::*;
+use riker::actor::SysTell;
+use riker::protocol::{ActorCmd, Info};
+use uuid::Uuid;
+
+use crate::cache::CachedStates;
+use crate::errors::ErrorWindow;
+use crate::instance::Instance;
+use crate::load::Loader;
+use crate::rate::TokenBucket;
+use crate::saga::SagaCoordinator;
+
+mod ack;
+mod audit;
+mod budget;
+mod cache;
+mod coordinator;
+mod errors;
+mod future;
+mod group;
+mod id;
+mod instance;
+mod keyspace;
+mod load;
+mod middleware;
+mod migrate;
+mod protocol;
+mod rate;
+mod saga;
+mod serializer;
+mod version;
+
+#[cfg(any(test, feature = "testkit"))]
+pub mod testkit;
+
+#[cfg(feature = "metrics")]
+pub mod metrics;
+
+#[cfg(feature = "diagnostics")]
+mod dump;
+
+pub use crate::ack::{ack_mode, AckMode, Acks};
+pub use crate::audit::{AuditEntry, AuditKind, AuditSink};
+pub use crate::budget::ResidencyBudget;
+pub use crate::cache::StateCache;
+#[cfg(feature = "diagnostics")]
+pub use crate::dump::StateDump;
+pub use crate::coordinator::SweepCoordinator;
+pub use crate::future::{CommandFuture, EntityError};
+pub use crate::group::EntityGroup;
+pub use crate::id::{IdGenerator, UuidGenerator};
+pub use crate::middleware::{CommandMiddleware, DedupMiddleware, LoggingMiddleware, MiddlewareResult};
+pub use crate::protocol::{CommandRecord, CommandResult, ControlMsg, EntityMsg, EntityStats, ErrorStats, Heartbeat, InstanceInfo, LatencyStats, RejectReason, ReplayProgress, SweepStats};
+pub use crate::rate::RateLimit;
+pub use crate::saga::SagaOutcome;
+pub use crate::serializer::{max_event_bytes, serializer_format, EventTooLarge, Serializer};
+pub use crate::version::Version;
+
+pub trait EntityActorProps : Clone + Send + Sync {
+    type Msg: EntityMsg;
+
+    /// Creates the `Props` of the instance with the given ID.
+    ///
+    /// `persistence` is the default persistence configuration of the
+    /// instance, built by the entity manager from the entity name and
+    /// the ID using `Entity::persistence_conf`. Instances inherit it by
+    /// returning it from `Actor::persistence_conf`, so that all instances
+    /// of an entity share the same keyspace.
+    ///
+    /// riker only uses the configuration returned by the instance, so an
+    /// instance that returns its own `PersistenceConf` takes precedence
+    /// over the default. The manager itself still loads events from the
+    /// default keyspace, so for such instances the existence checks of
+    /// `ControlMsg::CmdExisting`, `Create` and `GetOrCreate` find no
+    /// events, and `CatchUp` and `ReplayTo` replay none.
+    fn props(&self, id: String, persistence: PersistenceConf) -> BoxActorProd<Self::Msg>;
+
+    /// Secondary index keys of the instance with the given ID.
+    ///
+    /// Running instances can be found by these keys using
+    /// `ControlMsg::FindByIndex`. Only instances that are currently
+    /// running are indexed, not instances that are asleep.
+    fn index_keys(&self, _id: &str) -> Vec<String> {
+        vec![]
+    }
+
+    /// Derives the ID of the instance that handles a command sent as
+    /// `ControlMsg::CmdAutoKey`, e.g. from the command's fields.
+    ///
+    /// Returns `None` for commands that don't carry their key, which
+    /// are sent to dead letters. Commands sent as `CQMsg::Cmd` are
+    /// routed by their explicit ID and never use this.
+    fn extract_key(&self, _cmd: &Self::Msg) -> Option<String> {
+        None
+    }
+
+    /// Normalizes the ID of commands and queries before they are routed,
+    /// for IDs that have several forms, e.g. `"ACC-123"` and `"acc-123"`.
+    ///
+    /// The normalized ID is the ID of the instance: it's used to find
+    /// the running instance, to name its actor and as its persistence ID.
+    /// Normalization must therefore be stable. Changing it makes events
+    /// persisted under the old form unreachable, unless the instances are
+    /// migrated with `Entity::migrate_keyspace`.
+    fn normalize(&self, id: &str) -> String {
+        id.to_string()
+    }
+
+    /// The command that undoes a command accepted by the instance with
+    /// the given ID, sent when a later step of a `ControlMsg::Saga` fails.
+    ///
+    /// Steps without a compensating command are left as they are.
+    fn compensate(&self, _id: &str, _cmd: &Self::Msg) -> Option<Self::Msg> {
+        None
+    }
+
+    /// Called with each event persisted by the instance with the given ID,
+    /// for lightweight bookkeeping such as counters or auditing.
+    ///
+    /// It's called by the wrapper the manager puts around each instance,
+    /// before the instance applies the event. Events replayed while the
+    /// instance wakes aren't passed again. It runs in the instance's
+    /// context, which handles no other messages while it runs, so it must
+    /// be fast and must not block.
+    fn on_event(&self, _id: &str, _evt: &Self::Msg) {}
+
+    /// How the entity manager handles instances that fail, e.g. panic
+    /// while handling a command.
+    ///
+    /// Defaults to riker's `Strategy::Restart`: the instance is restarted
+    /// in place and replays its events, and commands it had not yet
+    /// processed may be lost. With `Strategy::Stop` the instance is
+    /// stopped and removed from the manager, and the next command for the
+    /// ID wakes a new instance. `Strategy::Escalate` fails the manager
+    /// itself, which is then handled by its own supervisor.
+    fn supervisor_strategy(&self) -> Strategy {
+        Strategy::Restart
+    }
+
+    /// The sender of commands that arrive without one, e.g. an actor that
+    /// logs or audits the outcome of every command.
+    ///
+    /// A command's own sender always takes precedence. The default sender
+    /// also receives the rejections of commands without a sender, which
+    /// are otherwise sent to dead letters.
+    fn default_sender(&self) -> Option<ActorRef<Self::Msg>> {
+        None
+    }
+
+    /// Whether the command only reads the state of the instance. Queries
+    /// are still routed to running instances in read-only mode, see
+    /// `ControlMsg::ReadOnly`.
+    fn is_query(&self, _cmd: &Self::Msg) -> bool {
+        false
+    }
+
+    /// The chain of middlewares that commands pass through before they
+    /// are routed, in order. Called when the manager starts.
+    /// See `CommandMiddleware`.
+    fn middleware(&self) -> Vec<Box<dyn CommandMiddleware<Self::Msg>>> {
+        Vec::new()
+    }
+}
+
+/// Shared props that can be replaced while the entity is running, to
+/// roll out changes to the instances' behavior without a restart:
+///
+/// ```ignore
+/// let props = Arc::new(Mutex::new(BankAccountProps::v1()));
+/// let em = Entity::new(&sys, props.clone(), "BankAccount", None)?;
+///
+/// *props.lock().unwrap() = BankAccountProps::v2();
+/// ```
+///
+/// Only instances woken after the replacement use the new props.
+/// Running instances keep their behavior until they are passivated and
+/// woken again. The other methods, such as `index_keys`, use the new
+/// props immediately.
+impl<Msg, T> EntityActorProps for Arc<Mutex<T>>
+    where Msg: EntityMsg, T: EntityActorProps<Msg=Msg>
+{
+    type Msg = Msg;
+
+    fn props(&self, id: String, persistence: PersistenceConf) -> BoxActorProd<Self::Msg> {
+        self.lock().unwrap().props(id, persistence)
+    }
+
+    fn index_keys(&self, id: &str) -> Vec<String> {
+        self.lock().unwrap().index_keys(id)
+    }
+
+    fn extract_key(&self, cmd: &Self::Msg) -> Option<String> {
+        self.lock().unwrap().extract_key(cmd)
+    }
+
+    fn normalize(&self, id: &str) -> String {
+        self.lock().unwrap().normalize(id)
+    }
+
+    fn compensate(&self, id: &str, cmd: &Self::Msg) -> Option<Self::Msg> {
+        self.lock().unwrap().compensate(id, cmd)
+    }
+
+    fn on_event(&self, id: &str, evt: &Self::Msg) {
+        self.lock().unwrap().on_event(id, evt)
+    }
+
+    fn supervisor_strategy(&self) -> Strategy {
+        self.lock().unwrap().supervisor_strategy()
+    }
+
+    fn default_sender(&self) -> Option<ActorRef<Self::Msg>> {
+        self.lock().unwrap().default_sender()
+    }
+
+    fn is_query(&self, cmd: &Self::Msg) -> bool {
+        self.lock().unwrap().is_query(cmd)
+    }
+
+    fn middleware(&self) -> Vec<Box<dyn CommandMiddleware<Self::Msg>>> {
+        self.lock().unwrap().middleware()
+    }
+}
+
+/// Creates `EntityActorProps` from the factory function of the instances.
+///
+/// The function receives the ID and default persistence configuration
+/// of each instance, as `EntityActorProps::props`:
+///
+/// ```ignore
+/// let em = Entity::new(&sys, entity_props(BankAccountActor::new), "BankAccount", None)?;
+/// ```
+///
+/// Entities that need secondary index keys implement `EntityActorProps`.
+pub fn entity_props<F, Msg>(f: F) -> EntityFn<F>
+    where F: Fn((String, PersistenceConf)) -> BoxActor<Msg> + Send + Sync + 'static, Msg: EntityMsg
+{
+    EntityFn { f: Arc::new(f) }
+}
+
+/// `EntityActorProps` backed by a factory function. See `entity_props`.
+pub struct EntityFn<F> {
+    f: Arc<F>,
+}
+
+impl<F> Clone for EntityFn<F> {
+    fn clone(&self) -> Self {
+        EntityFn { f: self.f.clone() }
+    }
+}
+
+impl<F, Msg> EntityActorProps for EntityFn<F>
+    where F: Fn((String, PersistenceConf)) -> BoxActor<Msg> + Send + Sync + 'static, Msg: EntityMsg
+{
+    type Msg = Msg;
+
+    fn props(&self, id: String, persistence: PersistenceConf) -> BoxActorProd<Self::Msg> {
+        let f = self.f.clone();
+        Props::new_args(Box::new(move |args| f(args)), (id, persistence))
+    }
+}
+
+// `EntityActorProps` backed by a closure, see `Entity::from_fn`
+struct PropsFn<F> {
+    f: Arc<F>,
+}
+
+impl<F> Clone for PropsFn<F> {
+    fn clone(&self) -> Self {
+        PropsFn { f: self.f.clone() }
+    }
+}
+
+impl<F, Msg> EntityActorProps for PropsFn<F>
+    where F: Fn(String) -> BoxActorProd<Msg> + Send + Sync + 'static, Msg: EntityMsg
+{
+    type Msg = Msg;
+
+    fn props(&self, id: String, _: PersistenceConf) -> BoxActorProd<Self::Msg> {
+        (self.f)(id)
+    }
+}
+
+/// Implemented by instances that can be queried with `ControlMsg::GetState`
+pub trait EntityState {
+    type Msg: EntityMsg;
+
+    /// A snapshot of the current state, or `None` if the instance has no state
+    fn state(&self) -> Option<Self::Msg>;
+}
+
+const MANAGER_PREFIX: &str = "entity-";
+const EVENTS_PREFIX: &str = "cqrs-events-";
+const GROUP_PREFIX: &str = "cqrs-group-";
+const HEARTBEAT_TOPIC: &str = "cqrs-heartbeat";
+
+pub struct Entity;
+
+impl Entity {
+    pub fn new<Pro, Msg>(sys: &ActorSystem<Msg>,
+                    instance_fact: Pro,
+                    name: &str,
+                    conf: Option<EntityActorConfig>) -> Result<ActorRef<Msg>, CreateError>
+        where Pro: EntityActorProps<Msg=Msg> + 'static, Msg: EntityMsg
+    {
+        Entity::create(sys, instance_fact, name, conf, |props, manager| sys.actor_of(props, manager))
+    }
+
+    /// Creates an entity manager whose instances are created by a closure,
+    /// given the ID of each instance, for one-off entities defined inline:
+    ///
+    /// ```ignore
+    /// let em = Entity::from_fn(&sys, "BankAccount", None, |id| {
+    ///     let persistence = Entity::persistence_conf("BankAccount", &id);
+    ///     Props::new_args(Box::new(BankAccountActor::new), (id, persistence))
+    /// })?;
+    /// ```
+    ///
+    /// The closure is shared by the manager and its restarts, so it must
+    /// be `Send + Sync`, but not `Clone`. Reusable or stateful factories, and
+    /// entities that need the other methods of `EntityActorProps`,
+    /// implement the trait and use `Entity::new`.
+    pub fn from_fn<F, Msg>(sys: &ActorSystem<Msg>,
+                            name: &str,
+                            conf: Option<EntityActorConfig>,
+                            f: F) -> Result<ActorRef<Msg>, CreateError>
+        where F: Fn(String) -> BoxActorProd<Msg> + Send + Sync + 'static, Msg: EntityMsg
+    {
+        Entity::new(sys, PropsFn { f: Arc::new(f) }, name, conf)
+    }
+
+    // creates the manager's props and events channel, and the manager
+    // itself using `create`, which is given the props and the actor name
+    fn create<Pro, Msg, F>(sys: &ActorSystem<Msg>,
+                            instance_fact: Pro,
+                            name: &str,
+                            conf: Option<EntityActorConfig>,
+                            create: F) -> Result<ActorRef<Msg>, CreateError>
+        where Pro: EntityActorProps<Msg=Msg> + 'static,
+                Msg: EntityMsg,
+                F: FnOnce(BoxActorProd<Msg>, &str) -> Result<ActorRef<Msg>, CreateError>
+    {
+        let conf = conf.unwrap_or(EntityActorConfig::from(&sys.config()));
+        let exclusive_keyspace = conf.exclusive_keyspace;
+
+        if let Some(ref template) = conf.child_name {
+            // the ID is validated when an instance is created
+            if !template.contains("{id}") || !valid_name(&child_name(template, name, "id")) {
+                return Err(CreateError::InvalidName(template.clone()));
+            }
+        }
+
+        let events = if conf.publish_events {
+            Some(sys.actor_of(Channel::props(), &format!("{}{}", EVENTS_PREFIX, name))?)
+        } else {
+            None
+        };
+
+        let props = EntityActor::props(name, instance_fact, conf, events.clone());
+        let manager = create(props, &format!("{}{}", MANAGER_PREFIX, name)).and_then(|manager| {
+            // the default keyspace of the instances, see `Entity::persistence_conf`
+            let keyspace = Entity::persistence_conf(name, "").keyspace;
+            match keyspace::claim(&sys.id().to_string(), &keyspace, manager.path()) {
+                Ok(()) => Ok(manager),
+                Err(ref other) if exclusive_keyspace => {
+                    warn!("CQRS: Entity: {}, State: not created, keyspace {} is used by {}", name, keyspace, other);
+                    sys.stop(&manager);
+                    Err(CreateError::AlreadyExists(keyspace))
+                }
+                Err(other) => {
+                    warn!("CQRS: Entity: {}, State: keyspace {} is also used by {}, their instances may persist conflicting events", name, keyspace, other);
+                    Ok(manager)
+                }
+            }
+        });
+        if manager.is_err() {
+            if let Some(events) = events {
+                sys.stop(&events);
+            }
+        }
+        manager
+    }
+
+    /// Returns the name and `ActorRef` of every entity manager in the system,
+    /// including managers created in an `EntityGroup`.
+    ///
+    /// Managers are discovered by walking the actor tree at the time of
+    /// the call, so the result is always current and nothing is cached.
+    pub fn registry<Msg: EntityMsg>(sys: &ActorSystem<Msg>) -> Vec<(String, ActorRef<Msg>)> {
+        let manager = |actor: ActorRef<Msg>| {
+            if actor.name().starts_with(MANAGER_PREFIX) {
+                Some((actor.name()[MANAGER_PREFIX.len()..].to_string(), actor))
+            } else {
+                None
+            }
+        };
+
+        let mut managers = Vec::new();
+        for actor in sys.user_root().children() {
+            if actor.name().starts_with(GROUP_PREFIX) {
+                managers.extend(actor.children().filter_map(manager));
+            } else {
+                managers.extend(manager(actor));
+            }
+        }
+        managers
+    }
+
+    /// Returns the channel that the named entity publishes its events to,
+    /// if the entity was created with `EntityActorConfig::publish_events`.
+    ///
+    /// Events are published as `ControlMsg::Event` using the instance ID
+    /// as the topic. Subscribe to `All` to receive the events of every
+    /// instance.
+    pub fn events_channel<Msg: EntityMsg>(sys: &ActorSystem<Msg>, name: &str) -> Option<ActorRef<Msg>> {
+        let channel = format!("{}{}", EVENTS_PREFIX, name);
+        sys.user_root()
+            .children()
+            .find(|actor| actor.name() == channel)
+    }
+
+    /// Default persistence configuration of an instance of the named entity.
+    ///
+    /// The entity name is used as the keyspace and the instance ID as
+    /// the persistence ID. This is the configuration passed to
+    /// `EntityActorProps::props`.
+    pub fn persistence_conf(name: &str, id: &str) -> PersistenceConf {
+        PersistenceConf {
+            id: id.to_string(),
+            keyspace: name.to_string()
+        }
+    }
+
+    /// Stops the named entity manager, returning once it has stopped.
+    ///
+    /// The manager passivates all instances and then stops itself.
+    /// Commands waiting for an instance to wake, and commands received
+    /// while stopping, are rejected with `RejectReason::Draining`. Does nothing
+    /// if there is no manager with the name, and can be called again while
+    /// a manager is stopping. Returns early if the manager's
+    /// `EntityActorConfig::drain_timeout` passes first, or if the manager
+    /// doesn't answer within a few seconds, e.g. because it stopped
+    /// before it could be told to.
+    ///
+    /// This blocks the calling thread, so it must not be called from
+    /// within an actor.
+    pub fn stop<Msg: EntityMsg>(sys: &ActorSystem<Msg>, name: &str) {
+        let manager = Entity::registry(sys).into_iter()
+                                            .find(|(n, _)| n == name)
+                                            .map(|(_, manager)| manager);
+
+        if let Some(manager) = manager {
+            let (tx, rx) = channel();
+            let props = Props::new_args(Box::new(StopWatcher::actor), (manager, tx));
+            if sys.tmp_actor_of(props).is_ok() {
+                let _ = rx.recv();
+            }
+        }
+    }
+
+    /// Notifies the entity manager that an instance is ready.
+    ///
+    /// Instances should call this from `post_start`, which runs after
+    /// any persisted events have been replayed. The manager uses it to
+    /// measure how long instances take to wake.
+    pub fn ready<Msg: EntityMsg>(ctx: &Context<Msg>, id: &str) {
+        ctx.myself().parent().tell(ControlMsg::Ready(id.to_string()), Some(ctx.myself()));
+    }
+
+    /// Reports how many of its events the calling instance has replayed,
+    /// and how many there are if it knows, e.g. from a count kept with its
+    /// events. See `ControlMsg::ReplayProgress`.
+    ///
+    /// Instances with long histories can call this from `apply_event` until
+    /// they are ready, e.g. every thousand events, so that a slow wake can
+    /// be told apart from a hung one. Progress is best-effort: the manager
+    /// only keeps the last report, and only until `Entity::ready`.
+    pub fn replay_progress<Msg: EntityMsg>(ctx: &Context<Msg>, id: &str, applied: u64, total: Option<u64>) {
+        ctx.myself().parent().tell(ControlMsg::Replaying(id.to_string(), applied, total), Some(ctx.myself()));
+    }
+
+    /// Requests the entity manager to passivate the calling instance.
+    ///
+    /// For instances that know they are no longer needed, e.g. a
+    /// completed saga, rather than waiting to be put to sleep after
+    /// a period of inactivity. The manager stops the instance and any
+    /// commands it has not yet processed are sent to dead letters.
+    /// The next command for the ID wakes a new instance, which restores
+    /// its state from the persisted events.
+    pub fn passivate<Msg: EntityMsg>(ctx: &Context<Msg>, id: &str) {
+        ctx.myself().parent().tell(ControlMsg::Passivate(id.to_string()), Some(ctx.myself()));
+    }
+
+    /// Replies to `ControlMsg::CanPassivate` from the entity manager.
+    ///
+    /// Instances with work in progress, such as pending timers or external
+    /// calls, can veto their passivation by replying `false`.
+    /// See `EntityActorConfig::passivation_check_timeout`.
+    pub fn reply_can_passivate<Msg: EntityMsg>(ctx: &Context<Msg>, id: &str, ok: bool) {
+        ctx.myself().parent().tell(ControlMsg::CanPassivateResult(id.to_string(), ok), Some(ctx.myself()));
+    }
+
+    /// Replies to the sender of a command with the outcome of the command.
+    ///
+    /// Instances should reply to every command they receive with a sender,
+    /// so that callers can distinguish a command that produced no events
+    /// from a command that was rejected. Events are persisted asynchronously,
+    /// so an `Accepted` reply can arrive before the events are persisted.
+    pub fn reply<Msg: EntityMsg>(ctx: &Context<Msg>,
+                                id: &str,
+                                sender: Option<ActorRef<Msg>>,
+                                result: CommandResult<Msg>) {
+        // the manager is told first, so that it has counted the reply
+        // before the sender can send another command
+        if let CommandResult::Rejected(_) = result {
+            ctx.myself().parent().tell(ControlMsg::Failed(id.to_string()), Some(ctx.myself()));
+        }
+        ctx.myself().parent().tell(ControlMsg::Replied(id.to_string()), Some(ctx.myself()));
+        if let Some(sender) = sender {
+            sender.tell(ControlMsg::CommandResult(id.to_string(), result), Some(ctx.myself()));
+        }
+    }
+
+    /// Handles a command with `decide`, persisting the events it returns
+    /// unless the command is a `ControlMsg::DryRun`.
+    ///
+    /// `decide` computes the outcome of a command from the instance's
+    /// current state, without persisting or applying any events. For a
+    /// dry run the result is replied with `Entity::reply` and `None` is
+    /// returned. Otherwise the events of an `Accepted` result are persisted
+    /// and the result is returned, for the instance to reply with
+    /// `Entity::reply` or `Acks::reply`.
+    pub fn decide<Msg, F>(ctx: &Context<Msg>,
+                            id: &str,
+                            msg: Msg,
+                            sender: &Option<ActorRef<Msg>>,
+                            decide: F) -> Option<CommandResult<Msg>>
+        where Msg: EntityMsg, F: FnOnce(Msg) -> CommandResult<Msg>
+    {
+        match msg.into_control() {
+            Ok(ControlMsg::DryRun(_, cmd)) => {
+                let result = decide(*cmd);
+                Entity::reply(ctx, id, sender.clone(), result);
+                None
+            }
+            Ok(msg) => Some(decide(Msg::from_control(msg))),
+            Err(cmd) => {
+                let result = decide(cmd);
+                if let CommandResult::Accepted(ref events) = result {
+                    for evt in events {
+                        ctx.persist_event(evt.clone());
+                    }
+                }
+                Some(result)
+            }
+        }
+    }
+
+    /// Replies to `ControlMsg::GetState` with the current state of the
+    /// calling instance.
+    ///
+    /// Instances that implement `EntityState` should call this when they
+    /// receive `GetState`, so that the sender receives `ControlMsg::State`.
+    /// The state is also sent to the entity manager, which caches it if
+    /// `EntityActorConfig::state_cache` is set.
+    pub fn reply_state<Msg, S>(ctx: &Context<Msg>,
+                                id: &str,
+                                instance: &S,
+                                sender: Option<ActorRef<Msg>>)
+        where Msg: EntityMsg, S: EntityState<Msg=Msg>
+    {
+        let state = instance.state().map(Box::new);
+        if let Some(sender) = sender {
+            sender.tell(ControlMsg::State(id.to_string(), state.clone()), Some(ctx.myself()));
+        }
+        ctx.myself().parent().tell(ControlMsg::State(id.to_string(), state), Some(ctx.myself()));
+    }
+
+    /// Hands the final state of an instance to the entity manager as the
+    /// instance stops, to be cached if `EntityActorConfig::state_cache`
+    /// is set, so that `GetState` for the instance can be answered
+    /// without waking it again.
+    ///
+    /// Instances should call this from `post_stop` with the manager,
+    /// i.e. their parent, which they keep from `post_start` since
+    /// `post_stop` has no context. The state is cached only if no command
+    /// for the ID was received since the instance was stopped.
+    pub fn hand_off_state<Msg, S>(manager: &ActorRef<Msg>, id: &str, instance: &S)
+        where Msg: EntityMsg, S: EntityState<Msg=Msg>
+    {
+        let state = instance.state().map(Box::new);
+        manager.tell(ControlMsg::FinalState(id.to_string(), state), None);
+    }
+
+    /// Persists an event of the calling instance using the given serializer.
+    ///
+    /// The event store receives `ControlMsg::Serialized` rather than the
+    /// event itself, so `apply_event` must decode it using `Entity::decode`.
+    pub fn persist<Msg: EntityMsg>(ctx: &Context<Msg>, serializer: &dyn Serializer<Msg>, evt: &Msg) {
+        ctx.persist_event(crate::serializer::encode(serializer, evt));
+    }
+
+    /// Persists an event of the calling instance using the given serializer,
+    /// unless its serialized size exceeds `max_bytes`.
+    ///
+    /// The size is checked when the event is serialized, before anything is
+    /// sent to the event store, so an oversized event is never stored or
+    /// applied. Instances should then not persist the command's other
+    /// events and reply with `CommandResult::Rejected`, e.g. with the
+    /// error as the reason. See `max_event_bytes`.
+    pub fn try_persist<Msg: EntityMsg>(ctx: &Context<Msg>,
+                                        serializer: &dyn Serializer<Msg>,
+                                        evt: &Msg,
+                                        max_bytes: Option<usize>) -> Result<(), EventTooLarge> {
+        ctx.persist_event(crate::serializer::encode_bounded(serializer, evt, max_bytes)?);
+        Ok(())
+    }
+
+    /// Decodes an event received by `apply_event` that was persisted
+    /// using `Entity::persist`.
+    ///
+    /// Events that were persisted without a serializer are returned
+    /// unchanged. Fails if the event was serialized in another format.
+    pub fn decode<Msg: EntityMsg>(serializer: &dyn Serializer<Msg>, evt: Msg) -> Result<Msg, String> {
+        crate::serializer::decode(serializer, evt)
+    }
+}
+
+struct EntityActor<Pro, Msg: Message> {
+    name: String,
+    props: Pro,
+    instances: HashMap<String, EntityInstance<Msg>>,
+    index: HashMap<String, HashSet<String>>,
+    subscribers: Vec<(Option<String>, ActorRef<Msg>)>,
+    hot: Arc<Mutex<Vec<String>>>,
+    rewarm_on_restart: Option<usize>,
+    budget: Option<ResidencyBudget>,
+    report_cold_starts: bool,
+    query_existing_only: bool,
+    trace_commands: bool,
+    traced: u64,
+    on_passivation_decision: Option<PassivationCallback>,
+    audit_sink: Option<Arc<dyn AuditSink>>,
+    id_generator: Arc<dyn IdGenerator>,
+    warn_at_resident: Option<usize>,
+    resident_hysteresis: usize,
+    over_resident: bool,
+    states: Option<CachedStates<Msg>>,
+    events: Option<ActorRef<Msg>>,
+    // in the order they were requested, with their events once loaded
+    loads: VecDeque<(Uuid, Load<Msg>, Option<Vec<Msg>>)>,
+    creating: HashSet<String>,
+    catch_ups: HashMap<String, CatchUp<Msg>>,
+    max_concurrent_wakes: Option<usize>,
+    init_timeout: Option<Duration>,
+    pending_wakes: VecDeque<String>,
+    pending_cmds: HashMap<String, Vec<(Msg, Option<ActorRef<Msg>>)>>,
+    pending_count: usize,
+    max_pending_cmds: Option<usize>,
+    max_in_flight: Option<usize>,
+    fail_fast: Overload,
+    command_timeout: Option<Duration>,
+    reject_timed_out_cmds: bool,
+    restart_on_command_timeout: bool,
+    saga_step_timeout: Option<Duration>,
+    draining: bool,
+    drain_timeout: Option<Duration>,
+    // instances passivated by `Stop`, to stop again once the drain times out
+    drained: Vec<(String, ActorRef<Msg>)>,
+    drain_timer: Option<Uuid>,
+    paused: bool,
+    accepting: bool,
+    read_only: bool,
+    middleware: Vec<Box<dyn CommandMiddleware<Msg>>>,
+    child_name: Option<String>,
+    paused_cmds: VecDeque<(String, Msg, Option<ActorRef<Msg>>)>,
+    max_paused_cmds: usize,
+    sleep_after: Duration,
+    max_resident_age: Option<Duration>,
+    max_passivations_per_tick: Option<usize>,
+    min_residency: Option<Duration>,
+    passivation_check_timeout: Option<Duration>,
+    // when the wait for each check ends, and its timer
+    passivation_checks: HashMap<String, (Instant, Uuid)>,
+    passivation_grace: Option<Duration>,
+    // actors of passivated instances that may not have stopped yet,
+    // when their grace period ends, and its timer
+    stopping: HashMap<String, (ActorRef<Msg>, Instant, Uuid)>,
+    grace_cmds: HashMap<String, Vec<(Msg, Option<ActorRef<Msg>>)>>,
+    command_history: Option<usize>,
+    sweep: SweepSchedule,
+    tick: Option<Uuid>,
+    coordinator: Option<SweepCoordinator>,
+    heartbeat: Option<Duration>,
+    heartbeat_timer: Option<Uuid>,
+    batch_window: Option<Duration>,
+    batch: Vec<(String, Msg)>,
+    batch_timer: Option<Uuid>,
+    system: Option<ActorSystem<Msg>>,
+    path: String,
+    exclusive_keyspace: bool,
+    rate_limit: Option<RateLimit>,
+    restart_limit: Option<RestartLimit>,
+    // recent failures of each ID, and the IDs not woken until a time
+    failures: HashMap<String, VecDeque<Instant>>,
+    open_circuits: HashMap<String, Instant>,
+    // IDs told `Failed` for the reply that follows, which doesn't reset failures
+    rejected: HashSet<String>,
+    wake_latency: LatencyStats,
+    persist_latency: LatencyStats,
+    errors: ErrorWindow,
+    wakes: u64,
+    passivations: u64,
+    #[cfg(any(test, feature = "testkit"))]
+    faults: Option<testkit::FaultInjector>,
+}
+
+impl<Pro, Msg> EntityActor<Pro, Msg>
+    where Pro: EntityActorProps<Msg=Msg> + 'static, Msg: EntityMsg
+{
+    // the hot set is shared by the actors created from the props,
+    // so that it survives the manager being restarted
+    fn props(name: &str,
+            instance_fact: Pro,
+            conf: EntityActorConfig,
+            events: Option<ActorRef<Msg>>) -> BoxActorProd<Msg> {
+        Props::new_args(
+            Box::new(Self::actor),
+            (name.into(),
+            instance_fact, conf, events, Arc::new(Mutex::new(Vec::new())))
+        )
+    }
+
+    fn actor((name, instance_fact, conf, events, hot): (String, Pro, EntityActorConfig, Option<ActorRef<Msg>>, Arc<Mutex<Vec<String>>>)) -> BoxActor<Msg> {
+        let middleware = instance_fact.middleware();
+        let sweep_interval = conf.sweep_coordinator
+                                    .as_ref()
+                                    .map(SweepCoordinator::interval)
+                                    .unwrap_or_else(|| Duration::from_secs(60));
+        let actor = EntityActor {
+            name,
+            props: instance_fact,
+            events,
+            hot,
+            rewarm_on_restart: conf.rewarm_on_restart,
+            budget: conf.residency_budget,
+            report_cold_starts: conf.report_cold_starts,
+            query_existing_only: conf.query_existing_only,
+            trace_commands: conf.trace_commands,
+            traced: 0,
+            on_passivation_decision: conf.on_passivation_decision,
+            audit_sink: conf.audit_sink,
+            id_generator: conf.id_generator.unwrap_or_else(|| Arc::new(UuidGenerator)),
+            warn_at_resident: conf.warn_at_resident,
+            resident_hysteresis: conf.resident_hysteresis,
+            over_resident: false,
+            states: conf.state_cache.as_ref().map(CachedStates::new),
+            instances: HashMap::new(),
+            index: HashMap::new(),
+            subscribers: Vec::new(),
+            loads: VecDeque::new(),
+            creating: HashSet::new(),
+            catch_ups: HashMap::new(),
+            max_concurrent_wakes: conf.max_concurrent_wakes,
+            init_timeout: conf.init_timeout,
+            pending_wakes: VecDeque::new(),
+            pending_cmds: HashMap::new(),
+            pending_count: 0,
+            max_pending_cmds: conf.max_pending_cmds,
+            max_in_flight: conf.max_in_flight_per_id,
+            fail_fast: conf.fail_fast,
+            command_timeout: conf.command_timeout,
+            reject_timed_out_cmds: conf.reject_timed_out_cmds,
+            restart_on_command_timeout: conf.restart_on_command_timeout,
+            saga_step_timeout: conf.saga_step_timeout,
+            draining: false,
+            drain_timeout: conf.drain_timeout,
+            drained: Vec::new(),
+            drain_timer: None,
+            paused: false,
+            accepting: true,
+            read_only: false,
+            middleware,
+            child_name: conf.child_name,
+            paused_cmds: VecDeque::new(),
+            max_paused_cmds: conf.max_paused_cmds,
+            sleep_after: Duration::from_secs(conf.sleep_after_secs),
+            max_resident_age: conf.max_resident_age,
+            max_passivations_per_tick: conf.max_passivations_per_tick,
+            min_residency: conf.min_residency,
+            passivation_check_timeout: conf.passivation_check_timeout,
+            passivation_checks: HashMap::new(),
+            passivation_grace: conf.passivation_grace,
+            stopping: HashMap::new(),
+            grace_cmds: HashMap::new(),
+            command_history: conf.command_history,
+            sweep: SweepSchedule::new(sweep_interval, Instant::now()),
+            tick: None,
+            coordinator: conf.sweep_coordinator,
+            heartbeat: conf.emit_heartbeat,
+            heartbeat_timer: None,
+            batch_window: conf.publish_batch_window,
+            batch: Vec::new(),
+            batch_timer: None,
+            system: None,
+            path: String::new(),
+            exclusive_keyspace: conf.exclusive_keyspace,
+            rate_limit: conf.per_id_rate_limit,
+            restart_limit: conf.restart_limit,
+            failures: HashMap::new(),
+            open_circuits: HashMap::new(),
+            rejected: HashSet::new(),
+            wake_latency: LatencyStats::default(),
+            persist_latency: LatencyStats::default(),
+            errors: ErrorWindow::new(conf.error_window),
+            wakes: 0,
+            passivations: 0,
+            #[cfg(any(test, feature = "testkit"))]
+            faults: conf.faults,
+        };
+        Box::new(actor)
+    }
+
+    fn handle_cmd(&mut self,
+                    ctx: &Context<Msg>,
+                    id: String,
+                    cmd: Msg,
+                    sender: Option<ActorRef<Msg>>) {
+
+        let sender = sender.or_else(|| self.props.default_sender());
+        if self.draining {
+            if self.fail_fast.draining {
+                self.overloaded(ctx, id, cmd, sender, "draining");
+            } else {
+                self.reject_draining(ctx, id, cmd, sender);
+            }
+            return;
+        }
+
+        if !self.accepting {
+            debug!("CQRS: Entity: {}, ID: {}, CMD: {:?}, State: rejected, not accepting commands", self.name, id, cmd);
+            self.reject(ctx, id, cmd, sender, RejectReason::KillSwitch);
+            return;
+        }
+
+        // the ID names the actor of the instance, which riker would refuse
+        if !self.instances.contains_key(&id) && !valid_name(&self.actor_name(&id)) {
+            debug!("CQRS: Entity: {}, ID: {}, CMD: {:?}, State: rejected, invalid ID", self.name, id, cmd);
+            self.reject(ctx, id, cmd, sender, RejectReason::InvalidId);
+            return;
+        }
+
+        if self.circuit_open(&id) {
+            debug!("CQRS: Entity: {}, ID: {}, CMD: {:?}, State: rejected, failing repeatedly", self.name, id, cmd);
+            self.reject(ctx, id, cmd, sender, RejectReason::CircuitOpen);
+            return;
+        }
+
+        if self.read_only && !self.allowed_read_only(&id, &cmd) {
+            debug!("CQRS: Entity: {}, ID: {}, CMD: {:?}, State: rejected while read-only", self.name, id, cmd);
+            self.reject(ctx, id, cmd, sender, RejectReason::ReadOnly);
+            return;
+        }
+
+        if self.paused {
+            if self.paused_cmds.len() >= self.max_paused_cmds {
+                debug!("CQRS: Entity: {}, ID: {}, CMD: {:?}, State: rejected, too many commands while paused", self.name, id, cmd);
+                self.reject(ctx, id, cmd, sender, RejectReason::Busy);
+            } else {
+                trace!("CQRS: Entity: {}, ID: {}, CMD: {:?}, State: paused", self.name, id, cmd);
+                self.paused_cmds.push_back((id, cmd, sender));
+            }
+            return;
+        }
+
+        if let Some(catch_up) = self.catch_ups.get_mut(&id) {
+            trace!("CQRS: Entity: {}, ID: {}, CMD: {:?}, State: held while catching up", self.name, id, cmd);
+            catch_up.held.push((cmd, sender));
+            return;
+        }
+
+        if self.instances.contains_key(&id) {
+            let entity = self.instances.get_mut(&id).unwrap();
+            let limited = match entity.bucket {
+                Some(ref mut bucket) => !bucket.try_take(),
+                None => false
+            };
+
+            if limited {
+                debug!("CQRS: Entity: {}, ID: {}, CMD: {:?}, State: rate limited", self.name, id, cmd);
+                self.reject(ctx, id, cmd, sender, RejectReason::RateLimited);
+                return;
+            }
+
+            if self.max_in_flight.map(|max| entity.in_flight.len() >= max).unwrap_or(false) {
+                debug!("CQRS: Entity: {}, ID: {}, CMD: {:?}, State: rejected, too many commands in flight", self.name, id, cmd);
+                self.reject(ctx, id, cmd, sender, RejectReason::Busy);
+                return;
+            }
+
+            if self.fail_fast.in_flight.map(|max| entity.in_flight.len() >= max).unwrap_or(false) {
+                self.overloaded(ctx, id, cmd, sender, "too many commands in flight");
+                return;
+            }
+
+            if self.fail_fast.waking && entity.woken_at.is_some() {
+                self.overloaded(ctx, id, cmd, sender, "instance is waking");
+                return;
+            }
+
+            trace!("CQRS: Entity: {}, ID: {}, CMD: {:?}, State: running", self.name, id, cmd);
+            let correlation = self.correlation();
+            let entity = self.instances.get_mut(&id).unwrap();
+            if let Some(ref correlation) = correlation {
+                info!("CQRS: Entity: {}, ID: {}, Correlation: {}, CMD: {:?}, State: routed to the running instance", self.name, id, correlation, cmd);
+            }
+            let seq = entity.route(cmd, sender, self.command_history, correlation);
+            entity.last_used = Instant::now();
+            self.watch(ctx, &id, seq);
+
+            // the instance is in use again, so an answer to a pending
+            // passivation check no longer applies
+            self.end_passivation_check(ctx, &id);
+        } else if self.stopping.contains_key(&id) {
+            trace!("CQRS: Entity: {}, ID: {}, CMD: {:?}, State: waiting for the passivated instance to stop", self.name, id, cmd);
+            self.grace_cmds.entry(id).or_default().push((cmd, sender));
+        } else if self.pending_cmds.contains_key(&id) || !self.can_wake() || !self.acquire(ctx) {
+            if self.fail_fast.capacity {
+                self.overloaded(ctx, id, cmd, sender, "instance can't be woken");
+                return;
+            }
+
+            if self.max_pending_cmds.map(|max| self.pending_count >= max).unwrap_or(false) {
+                debug!("CQRS: Entity: {}, ID: {}, CMD: {:?}, State: rejected, too many commands waiting to wake", self.name, id, cmd);
+                self.reject(ctx, id, cmd, sender, RejectReason::Busy);
+                return;
+            }
+
+            trace!("CQRS: Entity: {}, ID: {}, CMD: {:?}, State: waiting to wake", self.name, id, cmd);
+            if !self.pending_cmds.contains_key(&id) {
+                self.pending_wakes.push_back(id.clone());
+            }
+            self.pending_cmds.entry(id).or_default().push((cmd, sender));
+            self.pending_count += 1;
+        } else {
+            trace!("CQRS: Entity: {}, ID: {}, CMD: {:?}, State: asleep", self.name, id, cmd);
+            if self.wake(ctx, &id).is_err() {
+                self.release();
+                error!("CQRS: Entity: {}, ID: {}, CMD: {:?}, State: failed to create instance", self.name, id, cmd);
+                self.reject(ctx, id, cmd, sender, RejectReason::CreateFailed);
+                return;
+            }
+
+            if self.report_cold_starts {
+                if let Some(ref sender) = sender {
+                    sender.tell(ControlMsg::ColdStart(id.clone()), Some(ctx.myself()));
+                }
+            }
+
+            let correlation = self.correlation();
+            if let Some(ref correlation) = correlation {
+                info!("CQRS: Entity: {}, ID: {}, Correlation: {}, CMD: {:?}, State: routed to the woken instance", self.name, id, correlation, cmd);
+            }
+
+            let entity = self.instances.get_mut(&id).unwrap();
+            if let Some(ref mut bucket) = entity.bucket {
+                bucket.try_take();
+            }
+            let seq = entity.route(cmd, sender, self.command_history, correlation);
+            self.watch(ctx, &id, seq);
+        }
+    }
+
+    // the next correlation ID, if commands are traced
+    fn correlation(&mut self) -> Option<String> {
+        if !self.trace_commands {
+            return None;
+        }
+
+        self.traced += 1;
+        Some(format!("{}-{}", self.name, self.traced))
+    }
+
+    // checks that the instance has replied to the command once
+    // the command timeout has passed
+    fn watch(&mut self, ctx: &Context<Msg>, id: &str, seq: u64) {
+        if let Some(timeout) = self.command_timeout {
+            let timer = ctx.schedule_once(timeout,
+                                            ctx.myself(),
+                                            None,
+                                            ControlMsg::CommandTimeout(id.to_string(), seq));
+            let cmd = self.instances.get_mut(id)
+                                    .and_then(|entity| entity.in_flight.iter_mut().find(|cmd| cmd.seq == seq));
+            if let Some(cmd) = cmd {
+                cmd.timer = Some(timer);
+            }
+        }
+    }
+
+    fn command_timed_out(&mut self, ctx: &Context<Msg>, id: &str, seq: u64) {
+        let entity = match self.instances.get_mut(id) {
+            Some(entity) => entity,
+            None => return
+        };
+
+        let cmd = match entity.in_flight.iter_mut().find(|cmd| cmd.seq == seq) {
+            Some(cmd) => cmd,
+            None => return
+        };
+        cmd.timer = None;
+
+        match cmd.correlation {
+            Some(ref correlation) => warn!("CQRS: Entity: {}, ID: {}, Correlation: {}, State: no reply to the command after {:?}", self.name, id, correlation, cmd.routed_at.elapsed()),
+            None => warn!("CQRS: Entity: {}, ID: {}, State: no reply to a command after {:?}", self.name, id, cmd.routed_at.elapsed())
+        }
+        if self.reject_timed_out_cmds {
+            if let Some(sender) = cmd.sender.take() {
+                sender.tell(ControlMsg::CommandRejected(id.to_string(), RejectReason::TimedOut), Some(ctx.myself()));
+            }
+        }
+
+        if self.restart_on_command_timeout {
+            // the command and those before it won't be replied to
+            // by the restarted instance
+            warn!("CQRS: Entity: {}, ID: {}, State: restarting after a command timeout", self.name, id);
+            while entity.in_flight.front().map(|cmd| cmd.seq <= seq).unwrap_or(false) {
+                if let Some(timer) = entity.in_flight.pop_front().and_then(|cmd| cmd.timer) {
+                    ctx.cancel_schedule(timer);
+                }
+            }
+            entity.actor.sys_tell(SystemMsg::ActorCmd(ActorCmd::Restart), None);
+        }
+    }
+
+    // the name of the actor of the instance with the given ID
+    fn actor_name(&self, id: &str) -> String {
+        match self.child_name {
+            Some(ref template) => child_name(template, &self.name, id),
+            None => id.to_string()
+        }
+    }
+
+    // creates the instance with the given ID, which replays its events
+    fn wake(&mut self, ctx: &Context<Msg>, id: &str) -> Result<(), CreateError> {
+        let persistence = Entity::persistence_conf(&self.name, id);
+        let actor = if self.injected_create_failure(id) {
+            Err(CreateError::Panicked)
+        } else {
+            let props = Instance::props(&self.props, id.to_string(), persistence, self.exclusive_keyspace);
+            ctx.actor_of(props, &self.actor_name(id))
+        }?;
+
+        let index_keys = self.props.index_keys(id);
+        for key in index_keys.iter() {
+            self.index.entry(key.clone()).or_default().insert(id.to_string());
+        }
+
+        let now = Instant::now();
+        let entity = EntityInstance {
+            actor,
+            created_at: SystemTime::now(),
+            last_used: now,
+            woken_at: Some(now),
+            index_keys,
+            bucket: self.rate_limit.as_ref().map(TokenBucket::new),
+            history: VecDeque::new(),
+            in_flight: VecDeque::new(),
+            routed: 0,
+            replied: None,
+            replayed: (0, None),
+            init_timer: self.init_timeout.map(|timeout| {
+                ctx.schedule_once(timeout, ctx.myself(), None, ControlMsg::InitTimedOut(id.to_string()))
+            }),
+        };
+        self.instances.insert(id.to_string(), entity);
+        self.wakes += 1;
+        self.record_audit(id, AuditKind::Woken);
+        self.check_resident();
+        Ok(())
+    }
+
+    // warns once when the number of running instances exceeds the
+    // threshold, and again once it drops below it by the hysteresis
+    fn check_resident(&mut self) {
+        if let Some(threshold) = self.warn_at_resident {
+            let count = self.instances.len();
+            if !self.over_resident && count > threshold {
+                self.over_resident = true;
+                warn!("CQRS: Entity: {}, {} instances running, over the threshold of {}. Passivation may not be keeping up",
+                        self.name, count, threshold);
+            } else if self.over_resident && count < threshold.saturating_sub(self.resident_hysteresis) {
+                self.over_resident = false;
+                info!("CQRS: Entity: {}, {} instances running, back under the threshold of {}", self.name, count, threshold);
+            }
+        }
+    }
+
+    // wakes the instances that were most recently used before the manager restarted
+    fn rewarm(&mut self, ctx: &Context<Msg>) {
+        let ids: Vec<String> = self.hot.lock().unwrap().drain(..).collect();
+        for id in ids.into_iter() {
+            if !self.can_wake() || !self.acquire(ctx) {
+                break;
+            }
+
+            debug!("CQRS: Entity: {}, ID: {}, State: rewarming", self.name, id);
+            if self.wake(ctx, &id).is_err() {
+                self.release();
+                error!("CQRS: Entity: {}, ID: {}, State: failed to rewarm instance", self.name, id);
+            }
+        }
+    }
+
+    // records the most recently used instances, to be rewarmed if the manager restarts
+    fn record_hot(&self) {
+        if let Some(size) = self.rewarm_on_restart {
+            let mut instances: Vec<(&String, &EntityInstance<Msg>)> = self.instances.iter().collect();
+            instances.sort_by(|a, b| b.1.last_used.cmp(&a.1.last_used));
+
+            let mut hot = self.hot.lock().unwrap();
+            *hot = instances.into_iter()
+                            .take(size)
+                            .map(|(id, _)| id.clone())
+                            .collect();
+        }
+    }
+
+    // commands received while draining are rejected rather than waking
+    // instances, so that the manager can stop once its instances are passivated
+    fn reject_draining(&self,
+                        ctx: &Context<Msg>,
+                        id: String,
+                        cmd: Msg,
+                        sender: Option<ActorRef<Msg>>) {
+        debug!("CQRS: Entity: {}, ID: {}, CMD: {:?}, State: rejected while draining", self.name, id, cmd);
+        self.reject(ctx, id, cmd, sender, RejectReason::Draining);
+    }
+
+    fn overloaded(&self,
+                    ctx: &Context<Msg>,
+                    id: String,
+                    cmd: Msg,
+                    sender: Option<ActorRef<Msg>>,
+                    why: &str) {
+        debug!("CQRS: Entity: {}, ID: {}, CMD: {:?}, State: rejected, overloaded: {}", self.name, id, cmd, why);
+        self.reject(ctx, id, cmd, sender, RejectReason::Overloaded);
+    }
+
+    // replies to the sender with the rejection, or sends
+    // the command to dead letters if there is no sender
+    fn reject(&self,
+                ctx: &Context<Msg>,
+                id: String,
+                cmd: Msg,
+                sender: Option<ActorRef<Msg>>,
+                reason: RejectReason) {
+        match sender {
+            Some(sender) => sender.tell(ControlMsg::CommandRejected(id, reason), Some(ctx.myself())),
+            None => {
+                let msg = ActorMsg::CQ(CQMsg::Cmd(id, cmd));
+                dead_letter(ctx.system.dead_letters(), None, ctx.myself().to_string(), msg);
+            }
+        }
+    }
+
+    #[cfg(any(test, feature = "testkit"))]
+    fn injected_create_failure(&self, id: &str) -> bool {
+        self.faults.as_ref().map(|faults| faults.fail_create(id)).unwrap_or(false)
+    }
+
+    #[cfg(not(any(test, feature = "testkit")))]
+    fn injected_create_failure(&self, _: &str) -> bool {
+        false
+    }
+
+    // compares the instances with the child actors, which include
+    // passivated instances until they have stopped
+    #[cfg(any(test, feature = "testkit"))]
+    fn audit(&self, ctx: &Context<Msg>) -> testkit::ConsistencyReport {
+        let children: HashSet<String> = ctx.myself().children()
+                                                .map(|child| child.name().to_string())
+                                                .collect();
+        let instances: HashMap<String, &String> = self.instances.iter()
+                                                .map(|(id, instance)| (instance.actor.name().to_string(), id))
+                                                .collect();
+
+        let mut orphaned: Vec<String> = children.iter()
+                                                .filter(|name| !instances.contains_key(*name))
+                                                .cloned()
+                                                .collect();
+        let mut missing: Vec<String> = instances.iter()
+                                                .filter(|(name, _)| !children.contains(*name))
+                                                .map(|(_, id)| (*id).clone())
+                                                .collect();
+        orphaned.sort();
+        missing.sort();
+
+        testkit::ConsistencyReport { orphaned, missing }
+    }
+
+    fn can_wake(&self) -> bool {
+        match self.max_concurrent_wakes {
+            Some(max) => self.instances.values().filter(|i| i.woken_at.is_some()).count() < max,
+            None => true
+        }
+    }
+
+    // the manager is notified with `BudgetAvailable` when a token is
+    // returned to the residency budget if none are available
+    fn budget_available(&self, ctx: &Context<Msg>) -> bool {
+        match self.budget {
+            Some(ref budget) => {
+                let myself = ctx.myself();
+                budget.available(&self.path, move || myself.tell(ControlMsg::BudgetAvailable, None))
+            }
+            None => true
+        }
+    }
+
+    // takes a token from the residency budget to wake an instance
+    fn acquire(&self, ctx: &Context<Msg>) -> bool {
+        match self.budget {
+            Some(ref budget) => {
+                let myself = ctx.myself();
+                budget.try_acquire(&self.path, move || myself.tell(ControlMsg::BudgetAvailable, None))
+            }
+            None => true
+        }
+    }
+
+    // returns the token of a passivated instance to the residency budget
+    fn release(&self) {
+        if let Some(ref budget) = self.budget {
+            budget.release(&self.path);
+        }
+    }
+
+    // wakes instances waiting for a free slot, in the order they were requested
+    fn wake_pending(&mut self, ctx: &Context<Msg>) {
+        while !self.pending_wakes.is_empty() && self.can_wake() && self.budget_available(ctx) {
+            let id = self.pending_wakes.pop_front().unwrap();
+            if let Some(cmds) = self.pending_cmds.remove(&id) {
+                if cmds.is_empty() {
+                    self.preload(ctx, id);
+                    continue;
+                }
+
+                self.pending_count -= cmds.len();
+                for (cmd, sender) in cmds.into_iter() {
+                    self.handle_cmd(ctx, id.clone(), cmd, sender);
+                }
+            }
+        }
+    }
+
+    // only queries of running instances are routed in read-only mode,
+    // since waking an instance needs the event store
+    fn allowed_read_only(&self, id: &str, cmd: &Msg) -> bool {
+        if !self.instances.contains_key(id) {
+            return false;
+        }
+
+        self.is_query(cmd)
+    }
+
+    fn is_query(&self, cmd: &Msg) -> bool {
+        if let Ok(ControlMsg::GetState(_)) = cmd.clone().into_control() {
+            return true;
+        }
+        self.props.is_query(cmd)
+    }
+
+    // routes a command received from a client, waking its instance
+    // only if it isn't a query or the instance exists
+    fn handle_client_cmd(&mut self,
+                            ctx: &Context<Msg>,
+                            id: String,
+                            cmd: Msg,
+                            sender: Option<ActorRef<Msg>>) {
+        if self.query_existing_only && self.is_query(&cmd) {
+            self.handle_cmd_existing(ctx, id, cmd, sender);
+        } else {
+            self.handle_cmd(ctx, id, cmd, sender);
+        }
+    }
+
+    // passes a command received by the manager through the middleware,
+    // returning it unless a middleware rejected or dropped it
+    fn accept(&mut self,
+                ctx: &Context<Msg>,
+                id: &str,
+                cmd: Msg,
+                sender: &Option<ActorRef<Msg>>) -> Option<Msg> {
+        if self.middleware.is_empty() {
+            return Some(cmd);
+        }
+
+        // a command rejected without a sender is sent to dead letters as
+        // it was received, not as a middleware may have changed it
+        let received = cmd.clone();
+        let sender = sender.clone().or_else(|| self.props.default_sender());
+        let mut cmd = cmd;
+        for i in 0..self.middleware.len() {
+            match self.middleware[i].handle(ctx, id, cmd) {
+                MiddlewareResult::Continue(next) => cmd = next,
+                MiddlewareResult::Reject(reason) => {
+                    debug!("CQRS: Entity: {}, ID: {}, State: rejected by middleware: {}", self.name, id, reason);
+                    self.reject(ctx, id.to_string(), received, sender, reason);
+                    return None;
+                }
+                MiddlewareResult::Drop => {
+                    trace!("CQRS: Entity: {}, ID: {}, State: dropped by middleware", self.name, id);
+                    return None;
+                }
+            }
+        }
+        Some(cmd)
+    }
+
+    // wakes the instance without a command, or queues the wake as with
+    // commands. Returns whether the instance is woken or waiting to wake
+    fn preload(&mut self, ctx: &Context<Msg>, id: String) -> bool {
+        if self.draining || !self.accepting || self.read_only || self.instances.contains_key(&id) || self.stopping.contains_key(&id) || self.circuit_open(&id) {
+            return false;
+        }
+
+        if self.pending_cmds.contains_key(&id) {
+            return true;
+        }
+
+        if !self.can_wake() || !self.acquire(ctx) {
+            trace!("CQRS: Entity: {}, ID: {}, State: preload waiting to wake", self.name, id);
+            self.pending_wakes.push_back(id.clone());
+            self.pending_cmds.insert(id, Vec::new());
+            return true;
+        }
+
+        match self.wake(ctx, &id) {
+            Ok(()) => true,
+            Err(_) => {
+                self.release();
+                error!("CQRS: Entity: {}, ID: {}, State: failed to create instance to preload", self.name, id);
+                false
+            }
+        }
+    }
+
+    fn handle_cmd_existing(&mut self,
+                            ctx: &Context<Msg>,
+                            id: String,
+                            cmd: Msg,
+                            sender: Option<ActorRef<Msg>>) {
+        if self.instances.contains_key(&id) {
+            self.handle_cmd(ctx, id, cmd, sender);
+            return;
+        }
+
+        match ctx.persistence.event_store {
+            Some(_) => self.load(ctx, Load::Exists(id, cmd, sender)),
+            None => self.not_found(ctx, id, cmd, sender)
+        }
+    }
+
+    fn handle_create(&mut self,
+                        ctx: &Context<Msg>,
+                        id: String,
+                        cmd: Msg,
+                        sender: Option<ActorRef<Msg>>) {
+        if self.instances.contains_key(&id) || self.creating.contains(&id) {
+            self.already_exists(ctx, id, cmd, sender);
+            return;
+        }
+
+        match ctx.persistence.event_store {
+            Some(_) => {
+                self.creating.insert(id.clone());
+                self.load(ctx, Load::Create(id, cmd, sender));
+            }
+            None => self.handle_cmd(ctx, id, cmd, sender)
+        }
+    }
+
+    fn handle_get_or_create(&mut self,
+                            ctx: &Context<Msg>,
+                            id: String,
+                            cmd: Msg,
+                            sender: Option<ActorRef<Msg>>) {
+        if self.instances.contains_key(&id) || self.pending_cmds.contains_key(&id) {
+            self.get_state(ctx, id, sender);
+            return;
+        }
+
+        match ctx.persistence.event_store {
+            Some(_) => self.load(ctx, Load::GetOrCreate(id, cmd, sender)),
+            None => {
+                self.handle_cmd(ctx, id.clone(), cmd, sender.clone());
+                self.get_state(ctx, id, sender);
+            }
+        }
+    }
+
+    // routes `GetState` to the instance like a command
+    fn get_state(&mut self, ctx: &Context<Msg>, id: String, sender: Option<ActorRef<Msg>>) {
+        let query = Msg::from_control(ControlMsg::GetState(id.clone()));
+        self.handle_cmd(ctx, id, query, sender);
+    }
+
+    fn already_exists(&self,
+                        ctx: &Context<Msg>,
+                        id: String,
+                        cmd: Msg,
+                        sender: Option<ActorRef<Msg>>) {
+        debug!("CQRS: Entity: {}, ID: {}, State: already exists", self.name, id);
+        self.reject(ctx, id, cmd, sender, RejectReason::AlreadyExists);
+    }
+
+    // loads the events of an instance with a `Loader` of its own, which
+    // tags the result with the request. Results of requests sent before
+    // the manager restarted are ignored, and results that arrive out of
+    // order wait for the earlier requests
+    fn load(&mut self, ctx: &Context<Msg>, load: Load<Msg>) {
+        let request = Uuid::new_v4();
+        let conf = Entity::persistence_conf(&self.name, load.id());
+        let props = Props::new_args(Box::new(Loader::actor), (ctx.myself(), request, conf));
+        if ctx.system.tmp_actor_of(props).is_err() {
+            error!("CQRS: Entity: {}, ID: {}, State: failed to load events", self.name, load.id());
+            self.load_failed(ctx, load);
+            return;
+        }
+        self.loads.push_back((request, load, None));
+    }
+
+    fn load_failed(&mut self, ctx: &Context<Msg>, load: Load<Msg>) {
+        match load {
+            Load::Exists(id, cmd, sender) |
+            Load::GetOrCreate(id, cmd, sender) => self.reject(ctx, id, cmd, sender, RejectReason::CreateFailed),
+            Load::Create(id, cmd, sender) => {
+                self.creating.remove(&id);
+                self.reject(ctx, id, cmd, sender, RejectReason::CreateFailed);
+            }
+            Load::CatchUp(id) => self.caught_up(ctx, &id, Vec::new()),
+            Load::Replay(id, actor) => actor.tell(ControlMsg::Replayed(id, 0), Some(ctx.myself()))
+        }
+    }
+
+    fn handle_loaded(&mut self, ctx: &Context<Msg>, request: Uuid, events: Vec<Msg>) {
+        match self.loads.iter_mut().find(|(r, _, _)| *r == request) {
+            Some(load) => load.2 = Some(events),
+            None => {
+                trace!("CQRS: Entity: {}, State: ignoring the events of an earlier request", self.name);
+                return;
+            }
+        }
+
+        while self.loads.front().map(|(_, _, events)| events.is_some()).unwrap_or(false) {
+            if let Some((_, load, Some(events))) = self.loads.pop_front() {
+                self.handle_load_result(ctx, load, events);
+            }
+        }
+    }
+
+    fn handle_load_result(&mut self, ctx: &Context<Msg>, load: Load<Msg>, events: Vec<Msg>) {
+        match load {
+            Load::Exists(id, cmd, sender) => {
+                if events.is_empty() && !self.instances.contains_key(&id) {
+                    self.not_found(ctx, id, cmd, sender);
+                } else {
+                    self.handle_cmd(ctx, id, cmd, sender);
+                }
+            }
+            Load::Create(id, cmd, sender) => {
+                self.creating.remove(&id);
+                if !events.is_empty() || self.instances.contains_key(&id) {
+                    self.already_exists(ctx, id, cmd, sender);
+                } else {
+                    self.handle_cmd(ctx, id, cmd, sender);
+                }
+            }
+            Load::GetOrCreate(id, cmd, sender) => {
+                // an earlier request may have created the instance
+                // since this one was loaded
+                let exists = !events.is_empty()
+                                || self.instances.contains_key(&id)
+                                || self.pending_cmds.contains_key(&id);
+                if !exists {
+                    debug!("CQRS: Entity: {}, ID: {}, State: creating", self.name, id);
+                    self.handle_cmd(ctx, id.clone(), cmd, sender.clone());
+                }
+                self.get_state(ctx, id, sender);
+            }
+            Load::CatchUp(id) => self.caught_up(ctx, &id, events),
+            Load::Replay(id, actor) => {
+                trace!("CQRS: Entity: {}, ID: {}, State: replaying {} events to {}", self.name, id, events.len(), actor);
+                let count = events.len();
+                for evt in events.into_iter() {
+                    actor.tell(ControlMsg::Event(id.clone(), Box::new(evt)), Some(ctx.myself()));
+                }
+                actor.tell(ControlMsg::Replayed(id, count), Some(ctx.myself()));
+            }
+        }
+    }
+
+    // loads the events of an instance for the actors catching up on it,
+    // once the instance has replied to the commands routed to it
+    fn start_catch_up(&mut self, ctx: &Context<Msg>, id: &str) {
+        let in_flight = self.instances.get(id).map(|i| i.in_flight.len()).unwrap_or(0);
+        let ready = match self.catch_ups.get_mut(id) {
+            Some(ref mut catch_up) if !catch_up.loading && in_flight == 0 => {
+                catch_up.loading = true;
+                true
+            }
+            _ => false
+        };
+
+        if !ready {
+            return;
+        }
+
+        match ctx.persistence.event_store {
+            Some(_) => self.load(ctx, Load::CatchUp(id.to_string())),
+            None => self.caught_up(ctx, id, Vec::new())
+        }
+    }
+
+    // replays the loaded events to the actors catching up, subscribes
+    // them and routes the commands held in the meantime
+    fn caught_up(&mut self, ctx: &Context<Msg>, id: &str, events: Vec<Msg>) {
+        if let Some(catch_up) = self.catch_ups.remove(id) {
+            // batched events were replayed, so they are sent to the
+            // existing subscribers before the new ones are added
+            self.flush_events(ctx);
+            debug!("CQRS: Entity: {}, ID: {}, State: replayed {} events to {} subscribers", self.name, id, events.len(), catch_up.actors.len());
+            for actor in catch_up.actors.into_iter() {
+                for evt in events.iter() {
+                    actor.tell(ControlMsg::Event(id.to_string(), Box::new(evt.clone())), Some(ctx.myself()));
+                }
+                actor.tell(ControlMsg::CaughtUp(id.to_string()), Some(ctx.myself()));
+                self.subscribers.push((Some(id.to_string()), actor));
+            }
+
+            for (cmd, sender) in catch_up.held.into_iter() {
+                self.handle_cmd(ctx, id.to_string(), cmd, sender);
+            }
+        }
+    }
+
+    fn start_saga(&self, ctx: &Context<Msg>, steps: Vec<(String, Msg)>, sender: Option<ActorRef<Msg>>) {
+        let first = steps.first().map(|(id, _)| id.clone()).unwrap_or_default();
+        let steps: Vec<(String, Msg, Option<Msg>)> = steps.into_iter()
+                                                        .map(|(id, cmd)| {
+                                                            let compensation = self.props.compensate(&id, &cmd);
+                                                            (id, cmd, compensation)
+                                                        })
+                                                        .collect();
+
+        let args = (ctx.myself(), sender.clone(), self.saga_step_timeout, steps);
+        let props = Props::new_args(Box::new(SagaCoordinator::actor), args);
+        if ctx.system.tmp_actor_of(props).is_err() {
+            error!("CQRS: Entity: {}, failed to start saga", self.name);
+            if let Some(sender) = sender {
+                let outcome = SagaOutcome::Compensated(first, "saga could not be started".into());
+                sender.tell(ControlMsg::SagaResult(outcome), Some(ctx.myself()));
+            }
+        }
+    }
+
+    // commands rejected in read-only mode and queries don't change the state
+    fn invalidate_state(&mut self, id: &str, cmd: &Msg) {
+        if self.read_only || self.is_query(cmd) {
+            return;
+        }
+
+        if let Some(ref mut states) = self.states {
+            states.written(id);
+        }
+    }
+
+    fn not_found(&self,
+                    ctx: &Context<Msg>,
+                    id: String,
+                    cmd: Msg,
+                    sender: Option<ActorRef<Msg>>) {
+        debug!("CQRS: Entity: {}, ID: {}, State: not found", self.name, id);
+        let sender = sender.or_else(|| self.props.default_sender());
+        self.reject(ctx, id, cmd, sender, RejectReason::NotFound);
+    }
+
+    fn handle_control(&mut self,
+                        ctx: &Context<Msg>,
+                        msg: ControlMsg<Msg>,
+                        sender: Option<ActorRef<Msg>>) {
+        match msg {
+            // commands
+            ControlMsg::CmdExisting(id, cmd) => {
+                let id = self.props.normalize(&id);
+                if let Some(cmd) = self.accept(ctx, &id, *cmd, &sender) {
+                    self.invalidate_state(&id, &cmd);
+                    self.handle_cmd_existing(ctx, id, cmd, sender);
+                }
+            }
+            ControlMsg::CmdExpecting(id, version, cmd) => {
+                let id = self.props.normalize(&id);
+                if let Some(cmd) = self.accept(ctx, &id, *cmd, &sender) {
+                    self.invalidate_state(&id, &cmd);
+                    let cmd = Msg::from_control(ControlMsg::CmdExpecting(id.clone(), version, Box::new(cmd)));
+                    self.handle_cmd(ctx, id, cmd, sender);
+                }
+            }
+            ControlMsg::DryRun(id, cmd) => {
+                let id = self.props.normalize(&id);
+                if let Some(cmd) = self.accept(ctx, &id, *cmd, &sender) {
+                    // the state isn't changed, so a cached state is kept
+                    let cmd = Msg::from_control(ControlMsg::DryRun(id.clone(), Box::new(cmd)));
+                    self.handle_cmd(ctx, id, cmd, sender);
+                }
+            }
+            ControlMsg::Create(id, cmd) => {
+                let id = self.props.normalize(&id);
+                if let Some(cmd) = self.accept(ctx, &id, *cmd, &sender) {
+                    self.invalidate_state(&id, &cmd);
+                    self.handle_create(ctx, id, cmd, sender);
+                }
+            }
+            ControlMsg::CreateNew(cmd) => self.create_new(ctx, cmd, sender),
+            ControlMsg::GetOrCreate(id, cmd) => {
+                let id = self.props.normalize(&id);
+                if let Some(cmd) = self.accept(ctx, &id, *cmd, &sender) {
+                    self.invalidate_state(&id, &cmd);
+                    self.handle_get_or_create(ctx, id, cmd, sender);
+                }
+            }
+            ControlMsg::CmdAutoKey(cmd) => self.cmd_auto_key(ctx, cmd, sender),
+            ControlMsg::Saga(steps) => self.start_saga(ctx, steps, sender),
+            ControlMsg::Loaded(request, events) => self.handle_loaded(ctx, request, events),
+            ControlMsg::CommandTimeout(id, seq) => self.command_timed_out(ctx, &id, seq),
+            ControlMsg::Replied(id) => self.replied(ctx, id),
+            ControlMsg::Failed(id) => self.failed(id),
+
+            // state queries
+            ControlMsg::GetState(id) => self.query_state(ctx, id, sender),
+            ControlMsg::State(id, state) => {
+                if let Some(ref mut states) = self.states {
+                    states.answered(&id, state);
+                }
+            }
+            ControlMsg::FinalState(id, state) => {
+                if let Some(ref mut states) = self.states {
+                    states.handed_off(&id, state);
+                }
+            }
+            ControlMsg::Identify(id) => self.identify(ctx, id, sender),
+
+            // instance lifecycle
+            ControlMsg::Ready(id) => self.ready(ctx, id),
+            ControlMsg::InitTimedOut(id) => self.init_timed_out(ctx, &id),
+            ControlMsg::Panicked(id) => self.instance_failed(ctx, &id),
+            ControlMsg::Replaying(id, applied, total) => self.replaying(id, applied, total),
+            ControlMsg::ReplayProgress(id) => self.replay_progress(ctx, id, sender),
+            ControlMsg::Rehydrate(id) => self.rehydrate(id),
+            ControlMsg::PreloadBatch(ids) => self.preload_batch(ctx, ids, sender),
+            ControlMsg::BudgetAvailable => self.wake_pending(ctx),
+
+            // passivation
+            ControlMsg::Passivate(id) => {
+                let id = self.props.normalize(&id);
+                self.passivate(ctx, &id);
+            }
+            ControlMsg::CanPassivateResult(id, ok) => self.can_passivate_result(ctx, id, ok),
+            ControlMsg::CanPassivateTimeout(id) => self.can_passivate_timed_out(ctx, id),
+            ControlMsg::GraceEnded(id) => self.grace_ended(ctx, id),
+            ControlMsg::SweepNow => self.sweep_now(ctx, sender),
+            ControlMsg::Clear => self.passivate_all(ctx),
+
+            // manager lifecycle
+            ControlMsg::Stop => self.drain(ctx, sender),
+            ControlMsg::DrainTimedOut => self.drain_timed_out(ctx),
+            ControlMsg::ReadOnly(read_only) => self.set_read_only(read_only),
+            ControlMsg::Pause => self.pause(),
+            ControlMsg::Resume => self.resume(ctx),
+            ControlMsg::SetAccepting(accepting) => self.set_accepting(accepting),
+
+            // metrics and diagnostics
+            ControlMsg::Stats => self.send_stats(ctx, sender),
+            ControlMsg::EventPersisted(id, latency) => self.event_persisted(id, latency),
+            ControlMsg::ErrorStats => self.error_stats(ctx, sender),
+            ControlMsg::ResetErrorStats => self.errors.reset(),
+            ControlMsg::DumpInstances => self.dump_instances(ctx, sender),
+            #[cfg(feature = "diagnostics")]
+            ControlMsg::DumpState(offset, limit) => self.dump_state(ctx, offset, limit, sender),
+            #[cfg(feature = "diagnostics")]
+            ControlMsg::DumpStateResult(_) => {}
+            #[cfg(any(test, feature = "testkit"))]
+            ControlMsg::AuditConsistency => self.audit_consistency(ctx, sender),
+            #[cfg(any(test, feature = "testkit"))]
+            ControlMsg::AuditConsistencyResult(_) => {}
+            ControlMsg::CommandHistory(id) => self.command_history(ctx, id, sender),
+            ControlMsg::HotIds(n) => self.hot_ids(ctx, n, sender),
+            ControlMsg::FindByIndex(key) => self.find_by_index(ctx, key, sender),
+
+            // events
+            ControlMsg::Subscribe(id, actor) => self.subscribe(id, actor),
+            ControlMsg::CatchUp(id, actor) => self.catch_up(ctx, id, actor),
+            ControlMsg::ReplayTo(id, actor) => self.replay_to(ctx, id, actor),
+            ControlMsg::Unsubscribe(actor) => self.unsubscribe(&actor),
+            ControlMsg::Event(id, evt) => self.publish(ctx, id, evt, sender),
+            ControlMsg::EmitHeartbeat => self.emit_heartbeat(ctx),
+            ControlMsg::FlushEvents => self.flush_events(ctx),
+
+            // not sent to the manager
+            ControlMsg::CommandResult(..) |
+            ControlMsg::Serialized(..) |
+            ControlMsg::StatsResult(_) |
+            ControlMsg::ReplayProgressResult(..) |
+            ControlMsg::Swept(_) |
+            ControlMsg::ErrorStatsResult(_) |
+            ControlMsg::DumpInstancesResult(_) |
+            ControlMsg::CommandHistoryResult(..) |
+            ControlMsg::CommandRejected(..) |
+            ControlMsg::ColdStart(_) |
+            ControlMsg::IdAssigned(_) |
+            ControlMsg::CaughtUp(_) |
+            ControlMsg::Replayed(..) |
+            ControlMsg::PreloadResult(..) |
+            ControlMsg::SagaResult(_) |
+            ControlMsg::SagaStepTimeout(_) |
+            ControlMsg::CanPassivate(_) |
+            ControlMsg::Heartbeat(_) |
+            ControlMsg::Stopping(_) |
+            ControlMsg::HotIdsResult(_) |
+            ControlMsg::EventBatch(_) |
+            ControlMsg::FindByIndexResult(..) => {}
+        }
+    }
+
+    fn create_new(&mut self, ctx: &Context<Msg>, cmd: Box<Msg>, sender: Option<ActorRef<Msg>>) {
+        let id = self.props.normalize(&self.id_generator.generate(&self.name));
+        if let Some(cmd) = self.accept(ctx, &id, *cmd, &sender) {
+            debug!("CQRS: Entity: {}, ID: {}, State: assigned", self.name, id);
+            if let Some(ref sender) = sender {
+                sender.tell(ControlMsg::IdAssigned(id.clone()), Some(ctx.myself()));
+            }
+            self.handle_create(ctx, id, cmd, sender);
+        }
+    }
+
+    fn cmd_auto_key(&mut self, ctx: &Context<Msg>, cmd: Box<Msg>, sender: Option<ActorRef<Msg>>) {
+        match self.props.extract_key(&cmd) {
+            Some(id) => {
+                let id = self.props.normalize(&id);
+                if let Some(cmd) = self.accept(ctx, &id, *cmd, &sender) {
+                    self.invalidate_state(&id, &cmd);
+                    self.handle_client_cmd(ctx, id, cmd, sender);
+                }
+            }
+            None => {
+                warn!("CQRS: Entity: {}, CMD: {:?}, State: no key could be derived", self.name, cmd);
+                let msg = ActorMsg::User(Msg::from_control(ControlMsg::CmdAutoKey(cmd)));
+                dead_letter(ctx.system.dead_letters(), None, ctx.myself().to_string(), msg);
+            }
+        }
+    }
+
+    // serves `GetState` from the state cache, or routes it to the instance
+    fn query_state(&mut self, ctx: &Context<Msg>, id: String, sender: Option<ActorRef<Msg>>) {
+        let id = self.props.normalize(&id);
+        if !self.instances.contains_key(&id) {
+            let cached = self.states.as_mut().and_then(|states| states.get(&id));
+            if let Some(state) = cached {
+                trace!("CQRS: Entity: {}, ID: {}, State: served from cache", self.name, id);
+                if let Some(sender) = sender {
+                    sender.tell(ControlMsg::State(id, state), Some(ctx.myself()));
+                }
+                return;
+            }
+        }
+
+        if let Some(ref mut states) = self.states {
+            states.queried(&id);
+        }
+        let query = Msg::from_control(ControlMsg::GetState(id.clone()));
+        self.handle_client_cmd(ctx, id, query, sender);
+    }
+
+    fn identify(&self, ctx: &Context<Msg>, id: String, sender: Option<ActorRef<Msg>>) {
+        let id = self.props.normalize(&id);
+        match self.instances.get(&id) {
+            Some(instance) => instance.actor.tell(Identify, sender),
+            None => {
+                if let Some(sender) = sender {
+                    sender.tell(Info, Some(ctx.myself()));
+                }
+            }
+        }
+    }
+
+    fn can_passivate_result(&mut self, ctx: &Context<Msg>, id: String, ok: bool) {
+        if self.end_passivation_check(ctx, &id) {
+            if ok {
+                self.passivate(ctx, &id);
+            } else if let Some(instance) = self.instances.get_mut(&id) {
+                trace!("CQRS: Entity: {}, ID: {}, State: passivation vetoed", self.name, id);
+                instance.last_used = Instant::now();
+            }
+        }
+    }
+
+    fn can_passivate_timed_out(&mut self, ctx: &Context<Msg>, id: String) {
+        let expired = self.passivation_checks.get(&id)
+                                            .map(|(deadline, _)| *deadline <= Instant::now())
+                                            .unwrap_or(false);
+        if expired {
+            warn!("CQRS: Entity: {}, ID: {}, State: no reply to passivation check, passivating", self.name, id);
+            self.passivate(ctx, &id);
+        }
+    }
+
+    fn grace_ended(&mut self, ctx: &Context<Msg>, id: String) {
+        let expired = self.stopping.get(&id)
+                                    .map(|(_, deadline, _)| *deadline <= Instant::now())
+                                    .unwrap_or(false);
+        if expired {
+            self.end_grace(ctx, &id);
+        }
+    }
+
+    fn rehydrate(&self, id: String) {
+        let id = self.props.normalize(&id);
+        // a restarted actor is created from its props
+        // and replays its events before processing messages
+        if let Some(instance) = self.instances.get(&id) {
+            debug!("CQRS: Entity: {}, ID: {}, State: rehydrating", self.name, id);
+            instance.actor.sys_tell(SystemMsg::ActorCmd(ActorCmd::Restart), None);
+        }
+    }
+
+    // rejects the commands held by the manager and passivates all
+    // instances, after which riker stops the manager
+    fn drain(&mut self, ctx: &Context<Msg>, sender: Option<ActorRef<Msg>>) {
+        if !self.draining {
+            debug!("CQRS: Entity: {}, State: draining", self.name);
+            self.draining = true;
+            self.pending_wakes.clear();
+
+            let pending: Vec<(String, Vec<(Msg, Option<ActorRef<Msg>>)>)> = self.pending_cmds.drain().collect();
+            self.pending_count = 0;
+            for (id, cmds) in pending.into_iter() {
+                for (cmd, sender) in cmds.into_iter() {
+                    self.reject_draining(ctx, id.clone(), cmd, sender);
+                }
+            }
+
+            let held: Vec<(String, Vec<(Msg, Option<ActorRef<Msg>>)>)> = self.grace_cmds.drain().collect();
+            for (id, cmds) in held.into_iter() {
+                for (cmd, sender) in cmds.into_iter() {
+                    self.reject_draining(ctx, id.clone(), cmd, sender);
+                }
+            }
+
+            let paused: Vec<(String, Msg, Option<ActorRef<Msg>>)> = self.paused_cmds.drain(..).collect();
+            for (id, cmd, sender) in paused.into_iter() {
+                self.reject_draining(ctx, id, cmd, sender);
+            }
+
+            let loads: Vec<Load<Msg>> = self.loads.drain(..).map(|(_, load, _)| load).collect();
+            self.creating.clear();
+            for load in loads.into_iter() {
+                match load {
+                    Load::Exists(id, cmd, sender) |
+                    Load::Create(id, cmd, sender) |
+                    Load::GetOrCreate(id, cmd, sender) => self.reject_draining(ctx, id, cmd, sender),
+                    Load::CatchUp(_) | Load::Replay(..) => {}
+                }
+            }
+
+            let catch_ups: Vec<(String, CatchUp<Msg>)> = self.catch_ups.drain().collect();
+            for (id, catch_up) in catch_ups.into_iter() {
+                for (cmd, sender) in catch_up.held.into_iter() {
+                    self.reject_draining(ctx, id.clone(), cmd, sender);
+                }
+            }
+
+            self.drained = self.instances.iter()
+                                        .map(|(id, instance)| (id.clone(), instance.actor.clone()))
+                                        .collect();
+            self.passivate_all(ctx);
+            self.flush_events(ctx);
+            if let Some(ref events) = self.events {
+                ctx.stop(events);
+            }
+            if let Some(timeout) = self.drain_timeout {
+                self.drain_timer = Some(ctx.schedule_once(timeout, ctx.myself(), None, ControlMsg::DrainTimedOut));
+            }
+            ctx.stop(&ctx.myself());
+        }
+
+        if let Some(sender) = sender {
+            sender.tell(ControlMsg::Stopping(self.drain_timeout), Some(ctx.myself()));
+        }
+    }
+
+    fn preload_batch(&mut self, ctx: &Context<Msg>, ids: Vec<String>, sender: Option<ActorRef<Msg>>) {
+        let mut woken = 0;
+        let mut resident = 0;
+        for id in ids.into_iter() {
+            let id = self.props.normalize(&id);
+            if self.instances.contains_key(&id) {
+                resident += 1;
+            } else if self.preload(ctx, id) {
+                woken += 1;
+            }
+        }
+
+        debug!("CQRS: Entity: {}, preloaded {} instances, {} already running", self.name, woken, resident);
+        if let Some(sender) = sender {
+            sender.tell(ControlMsg::PreloadResult(woken, resident), Some(ctx.myself()));
+        }
+    }
+
+    fn set_read_only(&mut self, read_only: bool) {
+        if read_only != self.read_only {
+            warn!("CQRS: Entity: {}, State: {}", self.name, if read_only { "read-only" } else { "read-write" });
+            self.read_only = read_only;
+        }
+    }
+
+    fn pause(&mut self) {
+        if !self.paused {
+            info!("CQRS: Entity: {}, State: paused", self.name);
+            self.paused = true;
+        }
+    }
+
+    fn resume(&mut self, ctx: &Context<Msg>) {
+        if self.paused {
+            info!("CQRS: Entity: {}, State: resumed, routing {} commands", self.name, self.paused_cmds.len());
+            self.paused = false;
+            let cmds: Vec<(String, Msg, Option<ActorRef<Msg>>)> = self.paused_cmds.drain(..).collect();
+            for (id, cmd, sender) in cmds.into_iter() {
+                self.handle_cmd(ctx, id, cmd, sender);
+            }
+        }
+    }
+
+    fn set_accepting(&mut self, accepting: bool) {
+        if accepting != self.accepting {
+            warn!("CQRS: Entity: {}, State: {}", self.name, if accepting { "accepting commands" } else { "not accepting commands" });
+            self.accepting = accepting;
+        }
+    }
+
+    fn passivate_all(&mut self, ctx: &Context<Msg>) {
+        let ids: Vec<String> = self.instances.keys().cloned().collect();
+        for id in ids.iter() {
+            self.passivate(ctx, id);
+        }
+    }
+
+    fn send_stats(&self, ctx: &Context<Msg>, sender: Option<ActorRef<Msg>>) {
+        if let Some(sender) = sender {
+            let stats = EntityStats {
+                name: self.name.clone(),
+                keyspace: Entity::persistence_conf(&self.name, "").keyspace,
+                instances: self.instances.len(),
+                subscribers: self.subscribers.len(),
+                wakes: self.wakes,
+                passivations: self.passivations,
+                wake_latency: self.wake_latency.clone(),
+                persist_latency: self.persist_latency.clone(),
+                last_sweep_at: self.sweep.last_at(Instant::now(), SystemTime::now()),
+                next_sweep_at: self.sweep.next_at(Instant::now(), SystemTime::now()),
+            };
+            sender.tell(ControlMsg::StatsResult(stats), Some(ctx.myself()));
+        }
+    }
+
+    fn ready(&mut self, ctx: &Context<Msg>, id: String) {
+        let init_timer = self.instances.get_mut(&id).and_then(|i| i.init_timer.take());
+        if let Some(timer) = init_timer {
+            ctx.cancel_schedule(timer);
+        }
+        let woken_at = self.instances.get_mut(&id).and_then(|i| i.woken_at.take());
+        if let Some(woken_at) = woken_at {
+            let latency = woken_at.elapsed();
+            trace!("CQRS: Entity: {}, ID: {}, State: ready after {:?}", self.name, id, latency);
+            self.wake_latency.record(latency);
+        }
+        self.wake_pending(ctx);
+    }
+
+    fn replaying(&mut self, id: String, applied: u64, total: Option<u64>) {
+        if let Some(instance) = self.instances.get_mut(&id) {
+            if instance.woken_at.is_some() {
+                instance.replayed = (applied, total);
+            }
+        }
+    }
+
+    fn replay_progress(&self, ctx: &Context<Msg>, id: String, sender: Option<ActorRef<Msg>>) {
+        if let Some(sender) = sender {
+            let id = self.props.normalize(&id);
+            let progress = self.instances.get(&id).and_then(|instance| {
+                instance.woken_at.map(|woken_at| ReplayProgress {
+                    applied: instance.replayed.0,
+                    total: instance.replayed.1,
+                    elapsed: woken_at.elapsed(),
+                })
+            });
+            sender.tell(ControlMsg::ReplayProgressResult(id, progress), Some(ctx.myself()));
+        }
+    }
+
+    fn event_persisted(&mut self, id: String, latency: Duration) {
+        // the command waiting for its events to persist in
+        // AckMode::Persisted, otherwise the last replied to
+        let correlation = self.instances.get(&id).and_then(|entity| {
+            entity.in_flight
+                    .front()
+                    .and_then(|cmd| cmd.correlation.as_ref())
+                    .or_else(|| entity.replied.as_ref())
+        });
+        if let Some(correlation) = correlation {
+            info!("CQRS: Entity: {}, ID: {}, Correlation: {}, State: event persisted after {:?}", self.name, id, correlation, latency);
+        }
+        self.persist_latency.record(latency);
+    }
+
+    fn failed(&mut self, id: String) {
+        self.errors.failed();
+        self.rejected.insert(id);
+    }
+
+    fn replied(&mut self, ctx: &Context<Msg>, id: String) {
+        self.errors.replied();
+        if !self.rejected.remove(&id) {
+            self.failures.remove(&id);
+        }
+        if let Some(entity) = self.instances.get_mut(&id) {
+            if let Some(cmd) = entity.in_flight.pop_front() {
+                if let Some(timer) = cmd.timer {
+                    ctx.cancel_schedule(timer);
+                }
+                if let Some(ref correlation) = cmd.correlation {
+                    info!("CQRS: Entity: {}, ID: {}, Correlation: {}, State: replied after {:?}", self.name, id, correlation, cmd.routed_at.elapsed());
+                }
+                entity.replied = cmd.correlation;
+            }
+        }
+        self.start_catch_up(ctx, &id);
+    }
+
+    fn sweep_now(&mut self, ctx: &Context<Msg>, sender: Option<ActorRef<Msg>>) {
+        // the tick stays scheduled, as after any sweep
+        let stats = self.sleep_instances(ctx);
+        if let Some(sender) = sender {
+            sender.tell(ControlMsg::Swept(stats), Some(ctx.myself()));
+        }
+    }
+
+    fn error_stats(&mut self, ctx: &Context<Msg>, sender: Option<ActorRef<Msg>>) {
+        if let Some(sender) = sender {
+            sender.tell(ControlMsg::ErrorStatsResult(self.errors.stats()), Some(ctx.myself()));
+        }
+    }
+
+    fn dump_instances(&self, ctx: &Context<Msg>, sender: Option<ActorRef<Msg>>) {
+        if let Some(sender) = sender {
+            let now = SystemTime::now();
+            let instances = self.instances
+                                .iter()
+                                .map(|(id, instance)| InstanceInfo {
+                                    id: id.clone(),
+                                    created_at: instance.created_at,
+                                    last_used: instance.last_used_at(now),
+                                    in_flight: instance.in_flight.len(),
+                                    oldest_in_flight: instance.in_flight.front().map(|cmd| cmd.routed_at.elapsed()),
+                                })
+                                .collect();
+            sender.tell(ControlMsg::DumpInstancesResult(instances), Some(ctx.myself()));
+        }
+    }
+
+    #[cfg(feature = "diagnostics")]
+    fn dump_state(&self, ctx: &Context<Msg>, offset: usize, limit: usize, sender: Option<ActorRef<Msg>>) {
+        if let Some(sender) = sender {
+            let mut instances: Vec<(String, ActorRef<Msg>)> = self.instances
+                                                                .iter()
+                                                                .map(|(id, instance)| (id.clone(), instance.actor.clone()))
+                                                                .collect();
+            let total = instances.len();
+            instances.sort_by(|(a, _), (b, _)| a.cmp(b));
+            let instances: Vec<(String, ActorRef<Msg>)> = instances.into_iter().skip(offset).take(limit).collect();
+
+            let args = (instances, sender, total);
+            let props = Props::new_args(Box::new(dump::StateCollector::actor), args);
+            if ctx.system.tmp_actor_of(props).is_err() {
+                error!("CQRS: Entity: {}, failed to start state dump", self.name);
+            }
+        }
+    }
+
+    #[cfg(any(test, feature = "testkit"))]
+    fn audit_consistency(&self, ctx: &Context<Msg>, sender: Option<ActorRef<Msg>>) {
+        if let Some(sender) = sender {
+            sender.tell(ControlMsg::AuditConsistencyResult(self.audit(ctx)), Some(ctx.myself()));
+        }
+    }
+
+    fn command_history(&self, ctx: &Context<Msg>, id: String, sender: Option<ActorRef<Msg>>) {
+        if let Some(sender) = sender {
+            let id = self.props.normalize(&id);
+            let history = self.instances.get(&id)
+                                        .map(|instance| instance.history.iter().cloned().collect())
+                                        .unwrap_or_default();
+            sender.tell(ControlMsg::CommandHistoryResult(id, history), Some(ctx.myself()));
+        }
+    }
+
+    fn hot_ids(&self, ctx: &Context<Msg>, n: usize, sender: Option<ActorRef<Msg>>) {
+        if let Some(sender) = sender {
+            let mut counts: Vec<(String, u64)> = self.instances
+                                                    .iter()
+                                                    .map(|(id, instance)| (id.clone(), instance.routed))
+                                                    .collect();
+            counts.sort_by(|(a_id, a), (b_id, b)| b.cmp(a).then_with(|| a_id.cmp(b_id)));
+            counts.truncate(n);
+            sender.tell(ControlMsg::HotIdsResult(counts), Some(ctx.myself()));
+        }
+    }
+
+    fn find_by_index(&self, ctx: &Context<Msg>, key: String, sender: Option<ActorRef<Msg>>) {
+        if let Some(sender) = sender {
+            let ids = self.index.get(&key)
+                                .map(|ids| ids.iter().cloned().collect())
+                                .unwrap_or_default();
+            sender.tell(ControlMsg::FindByIndexResult(key, ids), Some(ctx.myself()));
+        }
+    }
+
+    fn subscribe(&mut self, id: Option<String>, actor: ActorRef<Msg>) {
+        let id = id.map(|id| self.props.normalize(&id));
+        trace!("CQRS: Entity: {}, subscribed: {} to ID: {:?}", self.name, actor, id);
+        self.subscribers.push((id, actor));
+    }
+
+    fn catch_up(&mut self, ctx: &Context<Msg>, id: String, actor: ActorRef<Msg>) {
+        if !self.draining {
+            let id = self.props.normalize(&id);
+            trace!("CQRS: Entity: {}, catching up: {} on ID: {}", self.name, actor, id);
+            self.catch_ups.entry(id.clone())
+                            .or_insert_with(CatchUp::new)
+                            .actors.push(actor);
+            self.start_catch_up(ctx, &id);
+        }
+    }
+
+    fn replay_to(&mut self, ctx: &Context<Msg>, id: String, actor: ActorRef<Msg>) {
+        let id = self.props.normalize(&id);
+        match ctx.persistence.event_store {
+            Some(_) => self.load(ctx, Load::Replay(id, actor)),
+            None => actor.tell(ControlMsg::Replayed(id, 0), Some(ctx.myself()))
+        }
+    }
+
+    // publishes an event persisted by an instance to its subscribers,
+    // or batches it
+    fn publish(&mut self, ctx: &Context<Msg>, id: String, evt: Box<Msg>, sender: Option<ActorRef<Msg>>) {
+        if let Some(window) = self.batch_window {
+            self.batch.push((id, *evt));
+            if self.batch_timer.is_none() {
+                let timer = ctx.schedule_once(window, ctx.myself(), None, ControlMsg::FlushEvents);
+                self.batch_timer = Some(timer);
+            }
+            return;
+        }
+
+        for (sub_id, actor) in self.subscribers.iter() {
+            if sub_id.is_none() || sub_id.as_ref() == Some(&id) {
+                actor.tell(ControlMsg::Event(id.clone(), evt.clone()), sender.clone());
+            }
+        }
+
+        if let Some(ref events) = self.events {
+            let msg = Msg::from_control(ControlMsg::Event(id.clone(), evt));
+            events.tell(ChannelMsg::Publish(id.into(), msg), sender);
+        }
+    }
+
+    fn passivate(&mut self, ctx: &Context<Msg>, id: &str) {
+        if let Some(instance) = self.remove(ctx, id) {
+            trace!("CQRS: Entity: {}, ID: {}, State: passivated", self.name, id);
+            self.passivations += 1;
+            self.record_audit(id, AuditKind::Passivated);
+            self.stop_instance(ctx, id, instance.actor);
+        }
+    }
+
+    // riker stops the manager once all of its instances have stopped, which
+    // an instance blocked in a handler delays. The instances still running
+    // are stopped again, so that they stop as soon as their handler returns
+    // without handling the commands in their mailbox, and so is the manager
+    fn drain_timed_out(&mut self, ctx: &Context<Msg>) {
+        self.drain_timer = None;
+        let children: Vec<ActorRef<Msg>> = ctx.myself().children().collect();
+        let remaining: Vec<(String, ActorRef<Msg>)> = self.drained
+                                                        .drain(..)
+                                                        .filter(|(_, actor)| children.contains(actor))
+                                                        .collect();
+        if remaining.is_empty() {
+            return;
+        }
+
+        let ids: Vec<&String> = remaining.iter().map(|(id, _)| id).collect();
+        warn!("CQRS: Entity: {}, drain timed out, force stopping instances: {:?}", self.name, ids);
+        for (_, actor) in remaining.iter() {
+            ctx.stop(actor);
+        }
+        ctx.stop(&ctx.myself());
+    }
+
+    // counts a panic of an instance, after which the supervisor strategy
+    // restarts or stops it, passivating the instance and not waking it
+    // again for the cooldown if it fails too often
+    fn instance_failed(&mut self, ctx: &Context<Msg>, id: &str) {
+        let limit = match self.restart_limit {
+            Some(ref limit) => limit.clone(),
+            None => return
+        };
+
+        let now = Instant::now();
+        let failures = self.failures.entry(id.to_string()).or_default();
+        failures.push_back(now);
+        while failures.front().map(|failed| now.duration_since(*failed) > limit.within).unwrap_or(false) {
+            failures.pop_front();
+        }
+
+        if failures.len() >= limit.max_restarts as usize {
+            warn!("CQRS: Entity: {}, ID: {}, State: failed {} times within {:?}, rejecting commands for {:?}",
+                    self.name, id, failures.len(), limit.within, limit.cooldown);
+            self.failures.remove(id);
+            self.open_circuits.insert(id.to_string(), now + limit.cooldown);
+            self.passivate(ctx, id);
+        }
+    }
+
+    fn circuit_open(&mut self, id: &str) -> bool {
+        match self.open_circuits.get(id) {
+            Some(until) if *until > Instant::now() => true,
+            Some(_) => {
+                self.open_circuits.remove(id);
+                false
+            }
+            None => false
+        }
+    }
+
+    fn record_audit(&self, id: &str, kind: AuditKind) {
+        if let Some(ref sink) = self.audit_sink {
+            sink.record(AuditEntry {
+                entity: self.name.clone(),
+                id: id.to_string(),
+                kind,
+                at: SystemTime::now(),
+            });
+        }
+    }
+
+    // stops the actor of a removed instance, holding commands for
+    // the ID during the grace period
+    fn stop_instance(&mut self, ctx: &Context<Msg>, id: &str, actor: ActorRef<Msg>) {
+        ctx.stop(&actor);
+        if let Some(grace) = self.passivation_grace {
+            let timer = ctx.schedule_once(grace, ctx.myself(), None, ControlMsg::GraceEnded(id.to_string()));
+            if let Some((_, _, earlier)) = self.stopping.insert(id.to_string(), (actor, Instant::now() + grace, timer)) {
+                ctx.cancel_schedule(earlier);
+            }
+        }
+    }
+
+    // stops an instance that isn't ready once the init timeout has
+    // passed since it was woken, rejecting the commands routed to it
+    fn init_timed_out(&mut self, ctx: &Context<Msg>, id: &str) {
+        let timeout = match self.init_timeout {
+            Some(timeout) => timeout,
+            None => return
+        };
+
+        // the instance may have been passivated and woken again since
+        let expired = self.instances.get(id)
+                                    .and_then(|instance| instance.woken_at)
+                                    .map(|woken_at| woken_at.elapsed() >= timeout)
+                                    .unwrap_or(false);
+        if !expired {
+            return;
+        }
+
+        if let Some(mut instance) = self.remove(ctx, id) {
+            warn!("CQRS: Entity: {}, ID: {}, State: not ready after {:?}, stopping with {} commands",
+                    self.name, id, timeout, instance.in_flight.len());
+            for cmd in instance.in_flight.drain(..) {
+                if let Some(sender) = cmd.sender {
+                    sender.tell(ControlMsg::CommandRejected(id.to_string(), RejectReason::InitTimeout), Some(ctx.myself()));
+                }
+            }
+            self.record_audit(id, AuditKind::Stopped);
+            self.stop_instance(ctx, id, instance.actor);
+        }
+    }
+
+    // routes the commands held while the passivated instance was stopping
+    fn end_grace(&mut self, ctx: &Context<Msg>, id: &str) {
+        if let Some((_, _, timer)) = self.stopping.remove(id) {
+            ctx.cancel_schedule(timer);
+            let cmds = self.grace_cmds.remove(id).unwrap_or_default();
+            trace!("CQRS: Entity: {}, ID: {}, State: grace period ended, routing {} commands", self.name, id, cmds.len());
+            for (cmd, sender) in cmds.into_iter() {
+                self.handle_cmd(ctx, id.to_string(), cmd, sender);
+            }
+        }
+    }
+
+    // removes a running instance, without stopping it
+    fn remove(&mut self, ctx: &Context<Msg>, id: &str) -> Option<EntityInstance<Msg>> {
+        self.end_passivation_check(ctx, id);
+        let instance = self.instances.remove(id)?;
+        for timer in instance.timers() {
+            ctx.cancel_schedule(timer);
+        }
+        self.unindex(id, &instance);
+        if let Some(ref mut states) = self.states {
+            states.forget(id);
+            states.stopped(id);
+        }
+        self.release();
+        self.check_resident();
+        self.wake_pending(ctx);
+        self.start_catch_up(ctx, id);
+        Some(instance)
+    }
+
+    // returns whether a passivation check was pending
+    fn end_passivation_check(&mut self, ctx: &Context<Msg>, id: &str) -> bool {
+        match self.passivation_checks.remove(id) {
+            Some((_, timer)) => {
+                ctx.cancel_schedule(timer);
+                true
+            }
+            None => false
+        }
+    }
+
+    fn unsubscribe(&mut self, actor: &ActorRef<Msg>) {
+        self.subscribers.retain(|(_, sub)| sub != actor);
+        for catch_up in self.catch_ups.values_mut() {
+            catch_up.actors.retain(|sub| sub != actor);
+        }
+    }
+
+    fn unindex(&mut self, id: &str, instance: &EntityInstance<Msg>) {
+        for key in instance.index_keys.iter() {
+            let empty = match self.index.get_mut(key) {
+                Some(ids) => {
+                    ids.remove(id);
+                    ids.is_empty()
+                }
+                None => false
+            };
+
+            if empty {
+                self.index.remove(key);
+            }
+        }
+    }
+
+    // the previous tick is cancelled in case it is still outstanding, e.g. when
+    // an overdue sweep is rescheduled, so that only one tick is ever scheduled
+    // managers with a coordinator are ticked by it instead
+    fn schedule_tick(&mut self, ctx: &Context<Msg>) {
+        if let Some(tick) = self.tick.take() {
+            ctx.cancel_schedule(tick);
+        }
+
+        if self.coordinator.is_some() {
+            self.sweep.scheduled(Instant::now());
+            return;
+        }
+
+        let tick = ctx.schedule_once(self.sweep.interval,
+                                        ctx.myself(),
+                                        None,
+                                        ActorMsg::Tick);
+        self.tick = Some(tick);
+        self.sweep.scheduled(Instant::now());
+    }
+
+    // sends each subscriber the batched events it is subscribed to, and
+    // publishes the events of each instance to the channel as one batch
+    fn flush_events(&mut self, ctx: &Context<Msg>) {
+        self.batch_timer = None;
+        if self.batch.is_empty() {
+            return;
+        }
+
+        let batch: Vec<(String, Msg)> = self.batch.drain(..).collect();
+        trace!("CQRS: Entity: {}, publishing a batch of {} events", self.name, batch.len());
+
+        for (sub_id, actor) in self.subscribers.iter() {
+            let events: Vec<(String, Msg)> = batch.iter()
+                                                .filter(|(id, _)| sub_id.is_none() || sub_id.as_ref() == Some(id))
+                                                .cloned()
+                                                .collect();
+            if !events.is_empty() {
+                actor.tell(ControlMsg::EventBatch(events), Some(ctx.myself()));
+            }
+        }
+
+        if let Some(ref events) = self.events {
+            let mut by_id: Vec<(String, Vec<(String, Msg)>)> = Vec::new();
+            for (id, evt) in batch.into_iter() {
+                match by_id.iter_mut().find(|(topic, _)| *topic == id) {
+                    Some((_, evts)) => evts.push((id, evt)),
+                    None => by_id.push((id.clone(), vec![(id, evt)]))
+                }
+            }
+
+            for (id, evts) in by_id.into_iter() {
+                let msg = Msg::from_control(ControlMsg::EventBatch(evts));
+                events.tell(ChannelMsg::Publish(id.into(), msg), Some(ctx.myself()));
+            }
+        }
+    }
+
+    fn schedule_heartbeat(&mut self, ctx: &Context<Msg>) {
+        if let Some(interval) = self.heartbeat {
+            let timer = ctx.schedule_once(interval,
+                                            ctx.myself(),
+                                            None,
+                                            ControlMsg::EmitHeartbeat);
+            self.heartbeat_timer = Some(timer);
+        }
+    }
+
+    fn emit_heartbeat(&mut self, ctx: &Context<Msg>) {
+        let heartbeat = Heartbeat {
+            name: self.name.clone(),
+            instances: self.instances.len(),
+            at: SystemTime::now(),
+        };
+        trace!("CQRS: Entity: {}, heartbeat, {} instances", self.name, heartbeat.instances);
+
+        for (id, actor) in self.subscribers.iter() {
+            if id.is_none() {
+                actor.tell(ControlMsg::Heartbeat(heartbeat.clone()), Some(ctx.myself()));
+            }
+        }
+
+        if let Some(ref events) = self.events {
+            let msg = Msg::from_control(ControlMsg::Heartbeat(heartbeat));
+            events.tell(ChannelMsg::Publish(HEARTBEAT_TOPIC.into(), msg), Some(ctx.myself()));
+        }
+
+        self.schedule_heartbeat(ctx);
+    }
+
+    // riker drops ticks that fail to schedule without reporting an error,
+    // which would stop instances from ever being put to sleep.
+    // Any message received while the tick is overdue restarts the sweep loop.
+    fn check_sweep(&mut self, ctx: &Context<Msg>) {
+        if self.sweep.overdue(Instant::now()) {
+            error!("CQRS: Entity: {}, passivation sweep is overdue. Rescheduling", self.name);
+            self.sleep_instances(ctx);
+            self.schedule_tick(ctx);
+        }
+    }
+
+    fn sleep_instances(&mut self, ctx: &Context<Msg>) -> SweepStats {
+        self.sweep.swept(Instant::now());
+        let mut stats = SweepStats::default();
+        let count = self.instances.len(); 
+        let now = SystemTime::now();
+        let sleep_after = self.sleep_after;
+        let max_age = self.max_resident_age;
+        let min_residency = self.min_residency;
+        let on_decision = self.on_passivation_decision.clone();
+
+        let mut stop: Vec<(String, Instant)> =
+            self.instances
+                .iter()
+                .filter(|&(ref id, ref instance)| {
+                    let expired = match max_age {
+                        Some(max_age) => now.duration_since(instance.created_at)
+                                            .map(|age| age > max_age)
+                                            .unwrap_or(false),
+                        None => false
+                    };
+                    let resident = match min_residency {
+                        Some(min_residency) => now.duration_since(instance.created_at)
+                                                    .map(|age| age < min_residency)
+                                                    .unwrap_or(true),
+                        None => false
+                    };
+                    let idle = instance.last_used.elapsed();
+                    let passivate = (idle > sleep_after && !resident) || expired;
+                    if let Some(PassivationCallback(ref f)) = on_decision {
+                        f(id, idle, passivate);
+                    }
+                    passivate
+                })
+                .map(|(id, instance)| (id.clone(), instance.last_used))
+                .collect();
+
+        // coldest first, so that a cap passivates the longest idle instances
+        stop.sort_by_key(|&(_, last_used)| last_used);
+        if let Some(max) = self.max_passivations_per_tick {
+            stop.truncate(max);
+        }
+
+        // stop instances, or ask them first. Stopped instances go through
+        // `passivate`, so that commands for them wait for the grace period
+        for (id, _) in stop.into_iter() {
+            match self.passivation_check_timeout {
+                Some(timeout) => {
+                    if !self.passivation_checks.contains_key(&id) {
+                        if let Some(instance) = self.instances.get(&id) {
+                            instance.actor.tell(ControlMsg::CanPassivate(id.clone()), Some(ctx.myself()));
+                        }
+                        let timer = ctx.schedule_once(timeout,
+                                                        ctx.myself(),
+                                                        None,
+                                                        ControlMsg::CanPassivateTimeout(id.clone()));
+                        self.passivation_checks.insert(id.clone(), (Instant::now() + timeout, timer));
+                        stats.checking += 1;
+                    }
+                }
+                None => {
+                    self.passivate(ctx, &id);
+                    stats.passivated += 1;
+                }
+            }
+        }
+
+        trace!("CQRS: Number of instances put to sleep: {}", count.saturating_sub(self.instances.len()));
+        self.check_resident();
+        self.wake_pending(ctx);
+        self.record_hot();
+
+        stats.instances = self.instances.len();
+        stats
+    }
+}
+
+impl<Pro, Msg> Actor for EntityActor<Pro, Msg>
+    where Pro: EntityActorProps<Msg=Msg> + 'static, Msg: EntityMsg
+{
+    type Msg = Msg;
+
+    fn pre_start(&mut self, ctx: &Context<Msg>) {
+        self.system = Some(ctx.system.clone());
+        self.path = ctx.myself().path().to_string();
+        if let Some(ref coordinator) = self.coordinator {
+            let myself = ctx.myself();
+            coordinator.register(&self.name, move || myself.tell(ActorMsg::Tick, None));
+        }
+        self.schedule_tick(ctx);
+        self.schedule_heartbeat(ctx);
+
+        // a restarted manager no longer has the instances it woke before
+        if let Some(ref budget) = self.budget {
+            budget.reset(&self.path);
+        }
+        self.rewarm(ctx);
+
+        // unsubscribe subscribers when they terminate
+        let msg = ChannelMsg::Subscribe(SysTopic::ActorTerminated.into(), ctx.myself());
+        ctx.system.event_stream().tell(msg, None);
+    }
+
+    fn post_stop(&mut self) {
+        if let Some(ref budget) = self.budget {
+            budget.reset(&self.path);
+        }
+        if let Some(ref coordinator) = self.coordinator {
+            coordinator.unregister(&self.name);
+        }
+        if let Some(ref sys) = self.system {
+            keyspace::release(&sys.id().to_string(), &self.path);
+        }
+
+        // riker doesn't cancel the timers of a stopped actor
+        if let (Some(sys), Some(tick)) = (self.system.as_ref(), self.tick.take()) {
+            sys.cancel_schedule(tick);
+        }
+        if let (Some(sys), Some(timer)) = (self.system.as_ref(), self.heartbeat_timer.take()) {
+            sys.cancel_schedule(timer);
+        }
+        if let (Some(sys), Some(timer)) = (self.system.as_ref(), self.batch_timer.take()) {
+            sys.cancel_schedule(timer);
+        }
+        if let (Some(sys), Some(timer)) = (self.system.as_ref(), self.drain_timer.take()) {
+            sys.cancel_schedule(timer);
+        }
+        if let Some(ref sys) = self.system {
+            for instance in self.instances.values() {
+                for timer in instance.timers() {
+                    sys.cancel_schedule(timer);
+                }
+            }
+            for (_, (_, timer)) in self.passivation_checks.drain() {
+                sys.cancel_schedule(timer);
+            }
+            for (_, (_, _, timer)) in self.stopping.drain() {
+                sys.cancel_schedule(timer);
+            }
+        }
+    }
+
+    fn receive(&mut self,
+                ctx: &Context<Msg>,
+                msg: Msg,
+                sender: Option<ActorRef<Msg>>) {
+        self.check_sweep(ctx);
+
+        match msg.into_control() {
+            Ok(msg) => self.handle_control(ctx, msg, sender),
+            Err(msg) => {
+                warn!("CQRS: Entity: {}, MSG: {:?} ignored. Commands must be sent as CQMsg::Cmd", self.name, msg);
+            }
+        }
+    }
+
+    fn other_receive(&mut self,
+                    ctx: &Context<Msg>,
+                    msg: ActorMsg<Msg>,
+                    sender: Option<ActorRef<Msg>>) {
+        match msg {
+            ActorMsg::CQ(cq) => {
+                self.check_sweep(ctx);
+
+                match cq {
+                    CQMsg::Cmd(id, cmd) => {
+                        let id = self.props.normalize(&id);
+                        if let Some(cmd) = self.accept(ctx, &id, cmd, &sender) {
+                            self.invalidate_state(&id, &cmd);
+                            self.handle_client_cmd(ctx, id, cmd, sender);
+                        }
+                    }
+                } 
+            }
+            ActorMsg::Tick => {
+                self.sweep.ticked();
+                self.sleep_instances(ctx);
+                self.schedule_tick(ctx);
+            }
+            _ => {}
+        }
+    }
+
+    fn system_receive(&mut self,
+                        ctx: &Context<Msg>,
+                        msg: SystemMsg<Msg>,
+                        _: Option<ActorRef<Msg>>) {
+        if let SystemMsg::Event(SystemEvent::ActorTerminated(actor)) = msg {
+            self.unsubscribe(&actor);
+
+            // an instance stopped by the supervisor strategy,
+            // rather than passivated, is still in the map
+            let stopped = self.instances.iter()
+                                        .find(|(_, instance)| instance.actor == actor)
+                                        .map(|(id, _)| id.clone());
+            if let Some(id) = stopped {
+                debug!("CQRS: Entity: {}, ID: {}, State: stopped after failure", self.name, id);
+                self.remove(ctx, &id);
+                self.record_audit(&id, AuditKind::Stopped);
+            }
+
+            // a passivated instance has stopped, so its name is free
+            let passivated = self.stopping.iter()
+                                            .find(|(_, (stopping, _, _))| *stopping == actor)
+                                            .map(|(id, _)| id.clone());
+            if let Some(id) = passivated {
+                self.end_grace(ctx, &id);
+            }
+        }
+    }
+
+    fn supervisor_strategy(&self) -> Strategy {
+        self.props.supervisor_strategy()
+    }
+}
+
+// longest wait for the manager to answer `Stop`, after which it's
+// assumed to have stopped before receiving it
+const STOP_ANSWER_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Stops an entity manager and reports when it has terminated
+struct StopWatcher<Msg: Message> {
+    manager: ActorRef<Msg>,
+    tx: Sender<()>,
+    answered: bool,
+    // the wait for the answer, then for the drain timeout
+    timer: Option<Uuid>,
+}
+
+impl<Msg: EntityMsg> StopWatcher<Msg> {
+    fn actor((manager, tx): (ActorRef<Msg>, Sender<()>)) -> BoxActor<Msg> {
+        Box::new(StopWatcher { manager, tx, answered: false, timer: None })
+    }
+
+    fn stopped(&self, ctx: &Context<Msg>) {
+        let _ = self.tx.send(());
+        ctx.stop(&ctx.myself());
+    }
+}
+
+impl<Msg: EntityMsg> Actor for StopWatcher<Msg> {
+    type Msg = Msg;
+
+    fn pre_start(&mut self, ctx: &Context<Msg>) {
+        let msg = ChannelMsg::Subscribe(SysTopic::ActorTerminated.into(), ctx.myself());
+        ctx.system.event_stream().tell(msg, None);
+        self.manager.tell(ControlMsg::Stop, Some(ctx.myself()));
+        self.timer = Some(ctx.schedule_once(STOP_ANSWER_TIMEOUT, ctx.myself(), None, ActorMsg::Tick));
+    }
+
+    // stops waiting for a manager that didn't answer, e.g. one that
+    // terminated before `Stop` was sent and so sent it to dead letters,
+    // or once its drain timeout has passed, by which time the manager
+    // has force stopped its instances
+    fn other_receive(&mut self,
+                    ctx: &Context<Msg>,
+                    msg: ActorMsg<Msg>,
+                    _: Option<ActorRef<Msg>>) {
+        if let ActorMsg::Tick = msg {
+            self.timer = None;
+            if self.answered {
+                warn!("CQRS: Entity: {}, drain timed out, not waiting for the manager to stop", self.manager.name());
+            } else {
+                warn!("CQRS: Entity: {}, no answer to stop after {:?}, not waiting for the manager to stop", self.manager.name(), STOP_ANSWER_TIMEOUT);
+            }
+            self.stopped(ctx);
+        }
+    }
+
+    fn system_receive(&mut self,
+                        ctx: &Context<Msg>,
+                        msg: SystemMsg<Msg>,
+                        _: Option<ActorRef<Msg>>) {
+        if let SystemMsg::Event(SystemEvent::ActorTerminated(actor)) = msg {
+            if actor == self.manager {
+                if let Some(timer) = self.timer.take() {
+                    ctx.cancel_schedule(timer);
+                }
+                self.stopped(ctx);
+            }
+        }
+    }
+
+    fn receive(&mut self, ctx: &Context<Msg>, msg: Msg, _: Option<ActorRef<Msg>>) {
+        if let Ok(ControlMsg::Stopping(timeout)) = msg.into_control() {
+            self.answered = true;
+            if let Some(timer) = self.timer.take() {
+                ctx.cancel_schedule(timer);
+            }
+            self.timer = timeout.map(|timeout| ctx.schedule_once(timeout, ctx.myself(), None, ActorMsg::Tick));
+        }
+    }
+}
+
+struct SweepSchedule {
+    interval: Duration,
+    next: Instant,
+    last: Option<Instant>,
+    missed: u32,
+}
+
+impl SweepSchedule {
+    fn new(interval: Duration, now: Instant) -> Self {
+        SweepSchedule {
+            interval,
+            next: now + interval,
+            last: None,
+            missed: 0
+        }
+    }
+
+    fn swept(&mut self, now: Instant) {
+        self.last = Some(now);
+    }
+
+    // wall clock times of the last and next sweep, for reporting only
+    fn last_at(&self, now: Instant, wall: SystemTime) -> Option<SystemTime> {
+        self.last.and_then(|last| wall.checked_sub(since(now, last)))
+    }
+
+    fn next_at(&self, now: Instant, wall: SystemTime) -> SystemTime {
+        wall + since(self.next, now)
+    }
+
+    fn scheduled(&mut self, now: Instant) {
+        self.next = now + self.interval;
+    }
+
+    fn ticked(&mut self) {
+        self.missed = 0;
+    }
+
+    // A tick is overdue once it is more than an interval late.
+    // The allowance doubles with each consecutive missed tick so that
+    // a scheduler that keeps failing isn't retried on every message.
+    fn overdue(&mut self, now: Instant) -> bool {
+        let allowance = self.interval * 2u32.pow(self.missed.min(6));
+        if now > self.next + allowance {
+            self.missed += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+// keeps the last `size` commands, if command history is enabled
+fn record<Msg: Message>(history: &mut VecDeque<CommandRecord>, cmd: &Msg, size: Option<usize>) {
+    if let Some(size) = size {
+        if size > 0 {
+            if history.len() >= size {
+                history.pop_front();
+            }
+            history.push_back(CommandRecord {
+                received_at: SystemTime::now(),
+                cmd: format!("{:?}", cmd)
+            });
+        }
+    }
+}
+
+// a request to the event store, see `EntityActor::load`
+enum Load<Msg: Message> {
+    // whether the instance of a `CmdExisting` exists. (ID, Command, Sender)
+    Exists(String, Msg, Option<ActorRef<Msg>>),
+
+    // whether the instance of a `Create` doesn't exist. (ID, Command, Sender)
+    Create(String, Msg, Option<ActorRef<Msg>>),
+
+    // whether the instance of a `GetOrCreate` exists. (ID, Create command, Sender)
+    GetOrCreate(String, Msg, Option<ActorRef<Msg>>),
+
+    // the events to replay to the actors catching up on an instance. (ID)
+    CatchUp(String),
+
+    // the events to replay to an actor. (ID, Actor)
+    Replay(String, ActorRef<Msg>),
+}
+
+impl<Msg: Message> Load<Msg> {
+    fn id(&self) -> &str {
+        match self {
+            Load::Exists(id, _, _) |
+            Load::Create(id, _, _) |
+            Load::GetOrCreate(id, _, _) |
+            Load::CatchUp(id) |
+            Load::Replay(id, _) => id
+        }
+    }
+}
+
+// actors catching up on the events of an instance, and the commands
+// for the instance held until they have caught up
+struct CatchUp<Msg: Message> {
+    actors: Vec<ActorRef<Msg>>,
+    held: Vec<(Msg, Option<ActorRef<Msg>>)>,
+    loading: bool,
+}
+
+impl<Msg: Message> CatchUp<Msg> {
+    fn new() -> Self {
+        CatchUp {
+            actors: Vec::new(),
+            held: Vec::new(),
+            loading: false,
+        }
+    }
+}
+
+// a command routed to an instance that it hasn't replied to
+struct InFlight<Msg: Message> {
+    seq: u64,
+    routed_at: Instant,
+    sender: Option<ActorRef<Msg>>,
+    correlation: Option<String>,
+    // the command timeout, until it has passed
+    timer: Option<Uuid>,
+}
+
+struct EntityInstance<Msg: Message> {
+    created_at: SystemTime,
+
+    // monotonic, so that idle instances are passivated
+    // correctly when the system clock changes
+    last_used: Instant,
+    woken_at: Option<Instant>,
+    index_keys: Vec<String>,
+    actor: ActorRef<Msg>,
+    bucket: Option<TokenBucket>,
+    history: VecDeque<CommandRecord>,
+
+    // commands routed to the instance that it hasn't replied to,
+    // oldest first, and the number of commands routed to it
+    in_flight: VecDeque<InFlight<Msg>>,
+    routed: u64,
+
+    // correlation ID of the last command replied to, if traced
+    replied: Option<String>,
+
+    // events applied and total, last reported while waking
+    replayed: (u64, Option<u64>),
+
+    // the init timeout, until the instance is ready
+    init_timer: Option<Uuid>,
+}
+
+impl<Msg: Message> EntityInstance<Msg> {
+    fn record(&mut self, cmd: &Msg, size: Option<usize>) {
+        record(&mut self.history, cmd, size);
+    }
+
+    // sends a command to the instance, returning its sequence number
+    fn route(&mut self,
+            cmd: Msg,
+            sender: Option<ActorRef<Msg>>,
+            size: Option<usize>,
+            correlation: Option<String>) -> u64 {
+        self.record(&cmd, size);
+        self.routed += 1;
+        self.in_flight.push_back(InFlight {
+            seq: self.routed,
+            routed_at: Instant::now(),
+            sender: sender.clone(),
+            correlation,
+            timer: None,
+        });
+        self.actor.tell(cmd, sender);
+        self.routed
+    }
+
+    // the timers to cancel once the instance is removed
+    fn timers(&self) -> Vec<Uuid> {
+        self.in_flight.iter()
+                        .filter_map(|cmd| cmd.timer)
+                        .chain(self.init_timer)
+                        .collect()
+    }
+
+    // wall clock time of the last use, for reporting only
+    fn last_used_at(&self, now: SystemTime) -> SystemTime {
+        now.checked_sub(self.last_used.elapsed())
+            .map(|last_used| last_used.max(self.created_at))
+            .unwrap_or(self.created_at)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct EntityActorConfig {
+    sleep_after_secs: u64,
+
+    /// Passivates instances that have been running longer than this,
+    /// even if they were recently used.
+    ///
+    /// Instances that are used continuously are otherwise never put to
+    /// sleep. The next command for the ID wakes a new instance, which
+    /// reloads its state from the persisted events.
+    pub max_resident_age: Option<Duration>,
+
+    /// Limits the number of instances passivated by each sweep, to spread
+    /// the stops of a large idle population over several sweeps. The
+    /// instances that have been idle longest are passivated first, and
+    /// the rest are left for the next sweep.
+    pub max_passivations_per_tick: Option<usize>,
+
+    /// Keeps woken instances running for at least this long, even if
+    /// they are idle for longer than `sleep_after_secs`.
+    ///
+    /// Absorbs short gaps in bursty traffic that would otherwise put an
+    /// instance to sleep only for the next command to replay its events
+    /// again. Only has an effect if it is longer than `sleep_after_secs`.
+    /// Instances are still passivated at `max_resident_age`.
+    pub min_residency: Option<Duration>,
+
+    /// Asks instances that are due to be passivated whether they can be,
+    /// waiting at most this long for a reply.
+    ///
+    /// Instances receive `ControlMsg::CanPassivate` and reply using
+    /// `Entity::reply_can_passivate`. An instance that vetoes is kept as
+    /// if it had just been used, and an instance that doesn't reply in time
+    /// is passivated. Without a timeout instances are passivated directly.
+    pub passivation_check_timeout: Option<Duration>,
+
+    /// Holds commands for an instance that was just passivated until its
+    /// actor has stopped, or at most this long, before waking it again.
+    ///
+    /// riker stops actors asynchronously and only frees the name of a
+    /// child once it has stopped, so waking an instance right after it was
+    /// passivated can fail to create its actor. Without a grace period
+    /// such commands are rejected with `RejectReason::CreateFailed`, as
+    /// creation isn't retried. Commands are routed as soon as the actor
+    /// has stopped, so the grace period only bounds the wait.
+    pub passivation_grace: Option<Duration>,
+
+    /// Limits the number of instances that can be waking at once, i.e.
+    /// replaying their events and not yet ready.
+    ///
+    /// Commands that would wake another instance are queued until a
+    /// waking instance becomes ready. This protects the event store from
+    /// a flood of replays, e.g. after a restart, at the cost of added
+    /// latency for queued wakes. Requires instances to call `Entity::ready`.
+    pub max_concurrent_wakes: Option<usize>,
+
+    /// Gives up on an instance that isn't ready this long after it was
+    /// woken, e.g. because replaying its events hangs on a slow event store.
+    ///
+    /// The commands routed to the instance are rejected with
+    /// `RejectReason::InitTimeout`, though the instance may still handle
+    /// them before it stops, and the instance is stopped, which frees its
+    /// slot for `max_concurrent_wakes`. The next command for the ID wakes
+    /// it again. An instance blocked in `post_start` only stops once it
+    /// unblocks, and its actor holds its name until then, so without
+    /// `passivation_grace` that command may be rejected with
+    /// `RejectReason::CreateFailed`, as creation isn't retried. Requires
+    /// instances to call `Entity::ready`.
+    pub init_timeout: Option<Duration>,
+
+    /// Limits the number of commands waiting for an instance to wake
+    /// when `max_concurrent_wakes` is reached.
+    ///
+    /// Commands over the limit are not queued and the sender receives
+    /// `RejectReason::Busy`. riker's mailboxes are unbounded and their length
+    /// isn't exposed, so commands can still back up in the manager's
+    /// mailbox. This only bounds the commands held by the manager itself.
+    pub max_pending_cmds: Option<usize>,
+
+    /// Limits the number of commands routed to each instance that it
+    /// hasn't yet replied to.
+    ///
+    /// Commands over the limit are rejected with `RejectReason::Busy`,
+    /// so that a client can't flood the mailbox of a single instance, e.g.
+    /// one waiting for its events to persist in `AckMode::Persisted`.
+    /// A command is counted until the instance replies to it using
+    /// `Entity::reply` or `Acks`, so instances that don't reply to every
+    /// command must not use this. The count is dropped when the instance
+    /// is passivated.
+    pub max_in_flight_per_id: Option<usize>,
+
+    /// Rejects commands with `RejectReason::Overloaded` rather than
+    /// holding them when the manager is overloaded, so that callers see
+    /// predictable latency. What counts as overloaded is configured by
+    /// each field of `Overload`, and nothing does by default.
+    pub fail_fast: Overload,
+
+    /// Limits how long `Entity::stop` waits for the instances to stop.
+    ///
+    /// riker can't stop an actor that is blocked in a handler, so an
+    /// instance stuck on a command keeps the manager from stopping. Once
+    /// the timeout has passed, the manager logs the IDs of the instances
+    /// still running and force stops them and itself, and `Entity::stop`
+    /// returns. A force stopped instance stops as soon as its handler
+    /// returns, without handling the commands still in its mailbox, and
+    /// the manager stops once they have. Force stopped instances may not
+    /// flush their state: commands they haven't handled are lost, and so
+    /// are events they haven't persisted if the process exits first.
+    /// Without a timeout `Entity::stop` waits indefinitely.
+    pub drain_timeout: Option<Duration>,
+
+    /// Maximum number of commands held while the manager is paused.
+    /// Further commands are rejected with `RejectReason::Busy`, or sent to
+    /// dead letters if they have no sender. See `ControlMsg::Pause`.
+    pub max_paused_cmds: usize,
+
+    /// Names the actors of instances using this template, in which
+    /// `{entity}` is replaced by the entity name and `{id}` by the instance
+    /// ID, e.g. "{entity}-{id}". Instances are named by their ID by default.
+    ///
+    /// Only changes the actor name, e.g. as shown by `print_tree`. Commands
+    /// and persistence still use the ID. The template must contain `{id}`
+    /// and the name must only contain letters, digits, '-' and '_', as
+    /// required by riker, otherwise `Entity::new` fails. Commands for IDs
+    /// with other characters are rejected with `RejectReason::InvalidId`,
+    /// whether or not a template is set.
+    pub child_name: Option<String>,
+
+    /// Fails a step of a `ControlMsg::Saga` if its instance doesn't reply
+    /// within this long. Without a timeout a saga waits indefinitely for
+    /// instances that don't reply using `Entity::reply`.
+    pub saga_step_timeout: Option<Duration>,
+
+    /// Logs a warning when an instance hasn't replied to a command within
+    /// this long, e.g. because it is blocked on I/O.
+    ///
+    /// Only instances that reply to every command using `Entity::reply`
+    /// or `Acks` should use this. Each routed command schedules a check,
+    /// so very short timeouts add overhead under load.
+    pub command_timeout: Option<Duration>,
+
+    /// Rejects a command with `RejectReason::TimedOut` once it passes
+    /// `command_timeout`. The instance may still handle the command
+    /// afterwards, in which case the sender receives both replies.
+    pub reject_timed_out_cmds: bool,
+
+    /// Restarts an instance once a command passes `command_timeout`.
+    ///
+    /// The restart takes effect once the instance returns from the command
+    /// it is processing, so it can't free an instance blocked forever. It
+    /// recovers an instance left in a bad state, but also restarts one
+    /// that was merely slow, replaying all of its events and dropping any
+    /// state it doesn't persist. Use a timeout well above the slowest
+    /// expected command.
+    pub restart_on_command_timeout: bool,
+
+    /// Limits the number of instances running at once across all managers
+    /// configured with the same budget. See `ResidencyBudget`.
+    pub residency_budget: Option<ResidencyBudget>,
+
+    /// Runs the passivation sweep when told by a timer shared by all
+    /// managers configured with the same coordinator, rather than a timer
+    /// of the manager's own. See `SweepCoordinator`. Without a coordinator
+    /// the manager sweeps every 60 seconds.
+    pub sweep_coordinator: Option<SweepCoordinator>,
+
+    /// Wakes up to this many of the most recently used instances again
+    /// when the manager is restarted by its supervisor.
+    ///
+    /// Restarting the manager stops all of its instances, so hot instances
+    /// would otherwise replay their events on their next command. The
+    /// instances to rewarm are recorded in memory at each passivation sweep,
+    /// so they survive a restart of the manager but not of the system.
+    /// Rewarming replays the events of all of them at once, adding load
+    /// to the event store while the manager restarts, and is limited by
+    /// `max_concurrent_wakes`.
+    pub rewarm_on_restart: Option<usize>,
+
+    /// Keeps the given number of most recent commands received by each
+    /// running instance, for debugging. See `ControlMsg::CommandHistory`.
+    ///
+    /// Commands are kept as their `Debug` representation and the history
+    /// is cleared when the instance passivates.
+    pub command_history: Option<usize>,
+
+    /// Limits the rate of commands accepted for each ID.
+    ///
+    /// Commands over the limit are not routed and the sender receives
+    /// `RejectReason::RateLimited`, or they are sent to dead letters if
+    /// there is no sender. Limits are tracked only while an
+    /// instance is running and reset when the instance passivates.
+    pub per_id_rate_limit: Option<RateLimit>,
+
+    /// Stops waking an instance that keeps failing, e.g. because of a bug
+    /// it hits on every command rather than a transient fault.
+    ///
+    /// Failures are counted each time an instance panics, whether the
+    /// supervisor strategy then restarts it in place, as riker's default
+    /// `Strategy::Restart` does, or stops it. See
+    /// `EntityActorProps::supervisor_strategy`. Instances that stop
+    /// themselves aren't counted. Once an ID fails `max_restarts` times
+    /// within `within`, its instance is passivated, its commands are
+    /// rejected with `RejectReason::CircuitOpen` and it isn't woken for
+    /// the `cooldown`.
+    /// The count for an ID is reset whenever its instance replies to a
+    /// command without rejecting it, so only failures without a successful
+    /// command in between count towards the limit.
+    pub restart_limit: Option<RestartLimit>,
+
+    /// Publishes the events of all instances to a channel created for
+    /// the entity, see `Entity::events_channel`.
+    ///
+    /// Every event an instance persists is published once it has been
+    /// persisted, in the order the instance persisted them. Events
+    /// replayed while an instance wakes aren't published again. Unlike
+    /// `ControlMsg::Subscribe`, consumers subscribe to the channel
+    /// directly and don't need the manager's `ActorRef`.
+    pub publish_events: bool,
+
+    /// Emits a `ControlMsg::Heartbeat` at this interval, for monitors
+    /// that alert when an entity stops emitting them.
+    ///
+    /// Heartbeats are sent to the subscribers of all instances, i.e.
+    /// `ControlMsg::Subscribe` with `None`, and published to the events
+    /// channel under the topic "cqrs-heartbeat" if `publish_events` is set.
+    /// They are independent of the passivation sweep.
+    pub emit_heartbeat: Option<Duration>,
+
+    /// Coalesces the events published within this long into a single
+    /// `ControlMsg::EventBatch` for each subscriber, rather than an
+    /// `Event` for each event, to reduce the number of messages sent to
+    /// busy projections.
+    ///
+    /// The window starts with the first event published after the last
+    /// batch, so events are delayed by up to the window. Events keep the
+    /// order they were published in within and across batches. The events
+    /// channel receives a batch for each instance, under its ID.
+    pub publish_batch_window: Option<Duration>,
+
+    /// Logs a warning when more than this many instances are running,
+    /// e.g. because passivation isn't keeping up with wakes.
+    pub warn_at_resident: Option<usize>,
+
+    /// How far below `warn_at_resident` the number of running instances
+    /// must drop before the manager logs that it has recovered, and would
+    /// warn again. Avoids repeated warnings around the threshold.
+    pub resident_hysteresis: usize,
+
+    /// Caches the last known state of instances, so that `GetState` for
+    /// an instance that is asleep can be answered without waking it.
+    ///
+    /// States are cached when instances answer `GetState` using
+    /// `Entity::reply_state` or stop using `Entity::hand_off_state`, and
+    /// are served only while the instance is asleep. Any command for the
+    /// ID other than a query removes its cached state, so the cache never
+    /// serves a state older than the last write, but a
+    /// state can be served up to `ttl` after it was read, e.g. while
+    /// events persisted by other means are not reflected.
+    pub state_cache: Option<StateCache>,
+
+    /// Notifies the sender of a command that wakes its instance with
+    /// `ControlMsg::ColdStart`, before the command is routed.
+    pub report_cold_starts: bool,
+
+    /// Length of the sliding window over which `ControlMsg::ErrorStats`
+    /// counts the commands replied to, 60 seconds by default. Counts
+    /// expire in steps of a tenth of the window, so the window slides in
+    /// steps rather than continuously.
+    pub error_window: Duration,
+
+    /// Routes queries, i.e. `GetState` and commands for which
+    /// `EntityActorProps::is_query` returns true, only to instances that
+    /// exist, as if they were sent as `ControlMsg::CmdExisting`. Queries
+    /// for an instance that isn't running and has no persisted events are
+    /// rejected with `RejectReason::NotFound` rather than waking an empty
+    /// instance. Other commands still wake the instance. Checking an
+    /// instance that is asleep loads its events, as for `CmdExisting`.
+    pub query_existing_only: bool,
+
+    /// Logs each command routed to an instance at info level with a
+    /// correlation ID, `{entity}-{n}`, as it is routed, as the events of
+    /// the instance are persisted and as it is replied to or times out,
+    /// so that one command's path can be followed in the log. Rejected
+    /// and held commands are logged as before, by ID. Events are
+    /// attributed to the oldest command not yet replied to, or else the
+    /// last command replied to, which is exact in `AckMode::Persisted`
+    /// but can be off by one command in `AckMode::Accepted` when commands
+    /// for an ID are sent without waiting for replies.
+    pub trace_commands: bool,
+
+    /// Refuses to share a keyspace with another entity manager of the
+    /// actor system. Without it a warning is logged.
+    ///
+    /// Each manager claims the default keyspace of its instances when it's
+    /// created, and each instance claims the keyspace of its own
+    /// `PersistenceConf` as it starts, before it replays its events. A
+    /// manager whose default keyspace is used by another manager, e.g. one
+    /// of the same name in an `EntityGroup`, isn't created and
+    /// `CreateError::AlreadyExists` is returned. An instance whose keyspace
+    /// is used by another manager rejects every command until it's
+    /// passivated, without persisting any events. Keyspaces are released when their manager stops. Only
+    /// managers of the same actor system are checked, and only keyspaces
+    /// claimed so far, i.e. those of instances that have been woken.
+    pub exclusive_keyspace: bool,
+
+    /// Called by each sweep with the decision for every running instance:
+    /// its ID, how long it has been idle and whether it is due to be
+    /// passivated, e.g. to derive a suitable `sleep_after_secs` from real
+    /// traffic. Instances due to be passivated can still refuse when
+    /// `passivation_check_timeout` is set. The callback runs on the manager
+    /// while it sweeps, so it must be fast and must not block. Can only be
+    /// set in code.
+    pub on_passivation_decision: Option<PassivationCallback>,
+
+    /// Records each wake, passivation and stop of an instance, e.g. for an
+    /// audit trail of when aggregates were active. See `AuditSink` for
+    /// when entries are recorded. Can only be set in code.
+    pub audit_sink: Option<Arc<dyn AuditSink>>,
+
+    /// Generates the IDs of instances created with `ControlMsg::CreateNew`,
+    /// `UuidGenerator` by default. Can only be set in code.
+    pub id_generator: Option<Arc<dyn IdGenerator>>,
+
+    /// Faults to inject into the entity. Requires the `testkit` feature.
+    #[cfg(any(test, feature = "testkit"))]
+    pub faults: Option<testkit::FaultInjector>,
+}
+
+impl<'a> From<&'a Config> for EntityActorConfig {
+    fn from(config: &Config) -> Self {
+        let capacity = config.get_int("cqrs.per_id_rate_limit.capacity");
+        let refill_per_sec = config.get_int("cqrs.per_id_rate_limit.refill_per_sec");
+        let max_restarts = config.get_int("cqrs.restart_limit.max_restarts");
+        let within_secs = config.get_int("cqrs.restart_limit.within_secs");
+        let cooldown_secs = config.get_int("cqrs.restart_limit.cooldown_secs");
+        let cache_capacity = config.get_int("cqrs.state_cache.capacity");
+        let cache_ttl_secs = config.get_int("cqrs.state_cache.ttl_secs");
+
+        EntityActorConfig {
+            sleep_after_secs: config.get_int("cqrs.sleep_after_secs").unwrap() as u64,
+            max_resident_age: config.get_int("cqrs.max_resident_age_secs")
+                                    .ok()
+                                    .map(|secs| Duration::from_secs(secs as u64)),
+            max_passivations_per_tick: config.get_int("cqrs.max_passivations_per_tick")
+                                    .ok()
+                                    .map(|max| max as usize),
+            min_residency: config.get_int("cqrs.min_residency_secs")
+                                    .ok()
+                                    .map(|secs| Duration::from_secs(secs as u64)),
+            passivation_check_timeout: config.get_int("cqrs.passivation_check_timeout_millis")
+                                    .ok()
+                                    .map(|millis| Duration::from_millis(millis as u64)),
+            passivation_grace: config.get_int("cqrs.passivation_grace_millis")
+                                    .ok()
+                                    .map(|millis| Duration::from_millis(millis as u64)),
+            command_history: config.get_int("cqrs.command_history_size")
+                                    .ok()
+                                    .map(|size| size as usize),
+            init_timeout: config.get_int("cqrs.init_timeout_millis")
+                                    .ok()
+                                    .map(|millis| Duration::from_millis(millis as u64)),
+            max_concurrent_wakes: config.get_int("cqrs.max_concurrent_wakes")
+                                    .ok()
+                                    .map(|max| max as usize),
+            max_pending_cmds: config.get_int("cqrs.max_pending_cmds")
+                                    .ok()
+                                    .map(|max| max as usize),
+            max_in_flight_per_id: config.get_int("cqrs.max_in_flight_per_id")
+                                    .ok()
+                                    .map(|max| max as usize),
+            fail_fast: Overload {
+                capacity: config.get_bool("cqrs.fail_fast.capacity").unwrap_or(false),
+                waking: config.get_bool("cqrs.fail_fast.waking").unwrap_or(false),
+                in_flight: config.get_int("cqrs.fail_fast.in_flight")
+                                    .ok()
+                                    .map(|max| max as usize),
+                draining: config.get_bool("cqrs.fail_fast.draining").unwrap_or(false),
+            },
+            child_name: config.get_str("cqrs.child_name").ok(),
+            drain_timeout: config.get_int("cqrs.drain_timeout_millis")
+                                    .ok()
+                                    .map(|millis| Duration::from_millis(millis as u64)),
+            max_paused_cmds: config.get_int("cqrs.max_paused_cmds")
+                                    .map(|max| max as usize)
+                                    .unwrap_or(10_000),
+            residency_budget: None,
+            sweep_coordinator: None,
+            rewarm_on_restart: config.get_int("cqrs.rewarm_on_restart")
+                                    .ok()
+                                    .map(|size| size as usize),
+            saga_step_timeout: config.get_int("cqrs.saga_step_timeout_millis")
+                                    .ok()
+                                    .map(|millis| Duration::from_millis(millis as u64)),
+            command_timeout: config.get_int("cqrs.command_timeout_millis")
+                                    .ok()
+                                    .map(|millis| Duration::from_millis(millis as u64)),
+            reject_timed_out_cmds: config.get_bool("cqrs.reject_timed_out_cmds").unwrap_or(false),
+            restart_on_command_timeout: config.get_bool("cqrs.restart_on_command_timeout").unwrap_or(false),
+            per_id_rate_limit: match (capacity, refill_per_sec) {
+                (Ok(capacity), Ok(refill_per_sec)) => Some(RateLimit {
+                    capacity: capacity as u32,
+                    refill_per_sec: refill_per_sec as u32
+                }),
+                _ => None
+            },
+            restart_limit: match (max_restarts, within_secs, cooldown_secs) {
+                (Ok(max_restarts), Ok(within_secs), Ok(cooldown_secs)) => Some(RestartLimit {
+                    max_restarts: max_restarts as u32,
+                    within: Duration::from_secs(within_secs as u64),
+                    cooldown: Duration::from_secs(cooldown_secs as u64)
+                }),
+                _ => None
+            },
+            publish_events: config.get_bool("cqrs.publish_events").unwrap_or(false),
+            publish_batch_window: config.get_int("cqrs.publish_batch_window_millis")
+                                    .ok()
+                                    .map(|millis| Duration::from_millis(millis as u64)),
+            emit_heartbeat: config.get_int("cqrs.heartbeat_interval_secs")
+                                    .ok()
+                                    .map(|secs| Duration::from_secs(secs as u64)),
+            report_cold_starts: config.get_bool("cqrs.report_cold_starts").unwrap_or(false),
+            error_window: config.get_int("cqrs.error_window_secs")
+                                    .map(|secs| Duration::from_secs(secs as u64))
+                                    .unwrap_or_else(|_| Duration::from_secs(60)),
+            query_existing_only: config.get_bool("cqrs.query_existing_only").unwrap_or(false),
+            trace_commands: config.get_bool("cqrs.trace_commands").unwrap_or(false),
+            exclusive_keyspace: config.get_bool("cqrs.exclusive_keyspace").unwrap_or(false),
+            on_passivation_decision: None,
+            audit_sink: None,
+            id_generator: None,
+            warn_at_resident: config.get_int("cqrs.warn_at_resident")
+                                    .ok()
+                                    .map(|threshold| threshold as usize),
+            resident_hysteresis: config.get_int("cqrs.resident_hysteresis")
+                                    .map(|hysteresis| hysteresis as usize)
+                                    .unwrap_or(0),
+            state_cache: match (cache_capacity, cache_ttl_secs) {
+                (Ok(capacity), Ok(ttl_secs)) => Some(StateCache {
+                    capacity: capacity as usize,
+                    ttl: Duration::from_secs(ttl_secs as u64)
+                }),
+                _ => None
+            },
+            #[cfg(any(test, feature = "testkit"))]
+            faults: None,
+        }
+    }
+}
+
+// name of the actor of an instance, see `EntityActorConfig::child_name`
+fn child_name(template: &str, entity: &str, id: &str) -> String {
+    template.replace("{entity}", entity).replace("{id}", id)
+}
+
+// a duration in seconds, with its fraction
+fn secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1_000_000_000.0
+}
+
+// the time from `earlier` to `later`, or zero if `earlier` is later
+fn since(later: Instant, earlier: Instant) -> Duration {
+    if later > earlier {
+        later.duration_since(earlier)
+    } else {
+        Duration::from_secs(0)
+    }
+}
+
+// riker's rule for actor names
+fn valid_name(name: &str) -> bool {
+    name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Limit on the failures of an instance, see
+/// `EntityActorConfig::restart_limit`
+#[derive(Clone, Debug)]
+pub struct RestartLimit {
+    /// Number of failures within `within` after which commands
+    /// for the ID are rejected
+    pub max_restarts: u32,
+
+    pub within: Duration,
+
+    /// How long commands for the ID are rejected
+    pub cooldown: Duration,
+}
+
+/// When the manager is overloaded and rejects commands rather than
+/// holding them, see `EntityActorConfig::fail_fast`
+#[derive(Clone, Debug, Default)]
+pub struct Overload {
+    /// The instance isn't running and can't be woken right away, because
+    /// `max_concurrent_wakes` or the residency budget is reached, or
+    /// commands are already waiting for it to wake
+    pub capacity: bool,
+
+    /// The instance is running but still waking, i.e. replaying its
+    /// events. Requires instances to call `Entity::ready`, otherwise
+    /// every command is rejected.
+    pub waking: bool,
+
+    /// The instance hasn't replied to this many commands. Counted as for
+    /// `EntityActorConfig::max_in_flight_per_id`.
+    pub in_flight: Option<usize>,
+
+    /// The manager is stopping. Commands are rejected with `Overloaded`
+    /// rather than `RejectReason::Draining`.
+    pub draining: bool,
+}
+
+/// Called by the sweep with the decision for each running instance,
+/// see `EntityActorConfig::on_passivation_decision`.
+/// (ID, Idle duration, Passivate)
+#[derive(Clone)]
+pub struct PassivationCallback(pub Arc<dyn Fn(&str, Duration, bool) + Send + Sync>);
+
+impl PassivationCallback {
+    pub fn new<F>(f: F) -> Self
+        where F: Fn(&str, Duration, bool) + Send + Sync + 'static
+    {
+        PassivationCallback(Arc::new(f))
+    }
+}
+
+impl fmt::Debug for PassivationCallback {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("PassivationCallback")
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time};
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use riker::actors::*;
+    use riker::actor::SysTell;
+    use riker::protocol::ActorCmd;
+    use riker_default::DefaultModel;
+    use riker_testkit::probe::{Probe, ProbeReceive};
+    use riker_testkit::probe::channel::{probe, ChannelProbe};
+
+    use crate::testkit::{Fault, FaultInjector, TestEntity};
+    use crate::{entity_props, AckMode, Acks, AuditEntry, AuditKind, AuditSink, CommandMiddleware, CommandResult, DedupMiddleware, Entity, EntityActorConfig, EntityError, EntityGroup, EntityActorProps, EntityMsg, EntityState, EntityStats, ErrorStats, EventTooLarge, ControlMsg, IdGenerator, MiddlewareResult, PassivationCallback, RateLimit, RejectReason, RestartLimit, ResidencyBudget, SagaOutcome, Serializer, StateCache, SweepCoordinator, SweepSchedule, SweepStats, Version};
+
+    #[derive(Clone, Debug)]
+    pub enum TestMsg {
+        Control(ControlMsg<TestMsg>),
+        Probe(ChannelProbe<(), ControlMsg<TestMsg>>),
+        CreateAccountCmd(String),
+        AddAmountCmd(i32),
+        BalanceQry,
+        BalanceResult(i32),
+
+        AccountCreatedEvt(BankAccount),
+        AmountAddedEvt(i32),
+
+        AccountState(BankAccount),
+    }
+
+    impl Into<ActorMsg<TestMsg>> for TestMsg {
+        fn into(self) -> ActorMsg<TestMsg> {
+            ActorMsg::User(self)
+        }
+    }
+
+    impl EntityMsg for TestMsg {
+        fn from_control(msg: ControlMsg<TestMsg>) -> Self {
+            TestMsg::Control(msg)
+        }
+
+        fn into_control(self) -> Result<ControlMsg<TestMsg>, Self> {
+            match self {
+                TestMsg::Control(msg) => Ok(msg),
+                msg => Err(msg),
+            }
+        }
+    }
+
+    #[derive(Clone, Debug)]
+    pub struct BankAccount {
+        id: String,
+        name: String,
+        balance: i32,
+    }
+
+    pub struct BankAccountActor {
+        id: String,
+        persistence: PersistenceConf,
+        acks: Acks<TestMsg>,
+        version: Version,
+        manager: Option<ActorRef<TestMsg>>,
+        state: Option<BankAccount>
+    }
+
+    impl BankAccountActor {
+        pub fn new((id, persistence): (String, PersistenceConf)) -> BoxActor<TestMsg> {
+            let actor = BankAccountActor {
+                id: id,
+                persistence,
+                acks: Acks::new(AckMode::Persisted),
+                version: Version::new(),
+                manager: None,
+                state: None
+            };
+
+            Box::new(actor)
+        }
+
+        fn create_account(&self, cmd: TestMsg) -> CommandResult<TestMsg> {
+            match cmd {
+                TestMsg::CreateAccountCmd(name) => {
+                    let account = BankAccount {
+                        id: self.id.clone(),
+                        name: name,
+                        balance: 0
+                    };
+
+                    CommandResult::Accepted(vec![TestMsg::AccountCreatedEvt(account)])
+                }
+                _ => CommandResult::Rejected("Can't update a non-existing account".into())
+            }
+        }
+
+        fn update_account(&self,
+                            ctx: &Context<TestMsg>,
+                            cmd: TestMsg,
+                            sender: &Option<ActorRef<TestMsg>>) -> CommandResult<TestMsg> {
+            let balance = self.state.as_ref().unwrap().balance;
+            match cmd {
+                TestMsg::AddAmountCmd(amount) => {
+                    println!("Current balance {}", balance);
+                    CommandResult::Accepted(vec![TestMsg::AmountAddedEvt(amount)])
+                }
+                TestMsg::BalanceQry => {
+                    if let Some(sender) = sender {
+                        sender.tell(TestMsg::BalanceResult(balance), Some(ctx.myself()));
+                    }
+                    CommandResult::NoOp
+                }
+                _ => CommandResult::Rejected("Account already exists".into())
+            }
+        }
+    }
+
+    impl Actor for BankAccountActor {
+        type Msg = TestMsg;
+
+        fn post_start(&mut self, ctx: &Context<TestMsg>) {
+            self.manager = Some(ctx.myself().parent());
+            Entity::ready(ctx, &self.id);
+        }
+
+        fn post_stop(&mut self) {
+            if let Some(ref manager) = self.manager {
+                Entity::hand_off_state(manager, &self.id, self);
+            }
+        }
+
+        fn receive(&mut self,
+                    ctx: &Context<TestMsg>,
+                    msg: TestMsg,
+                    sender: Option<ActorRef<TestMsg>>) {
+            let msg = match msg {
+                TestMsg::Control(ControlMsg::GetState(_)) => {
+                    Entity::reply_state(ctx, &self.id, self, sender);
+                    return;
+                }
+                TestMsg::Control(ControlMsg::CanPassivate(_)) => {
+                    // accounts with a balance veto their passivation
+                    let empty = self.state.as_ref().map(|s| s.balance == 0).unwrap_or(true);
+                    Entity::reply_can_passivate(ctx, &self.id, empty);
+                    return;
+                }
+                TestMsg::Control(ControlMsg::CmdExpecting(_, version, cmd)) => {
+                    if !self.version.check(ctx, &self.id, version, &sender) {
+                        return;
+                    }
+                    *cmd
+                }
+                msg => msg
+            };
+
+            let result = Entity::decide(ctx, &self.id, msg, &sender, |cmd| {
+                match self.state {
+                    Some(_) => self.update_account(ctx, cmd, &sender),
+                    None => self.create_account(cmd)
+                }
+            });
+            if let Some(result) = result {
+                self.acks.reply(ctx, &self.id, sender, result);
+            }
+        }
+
+        fn apply_event(&mut self, ctx: &Context<Self::Msg>, evt: Self::Msg) {
+            println!("apply event {:?}", evt);
+            self.acks.persisted(ctx, &self.id);
+            self.version.applied();
+
+            match evt {
+                TestMsg::AccountCreatedEvt(account) => self.state = Some(account),
+                TestMsg::AmountAddedEvt(amount) => self.state.as_mut().unwrap().balance += amount,
+                _ => {}
+            }
+        }
+
+        fn persistence_conf(&self) -> Option<PersistenceConf> {
+            Some(self.persistence.clone())
+        }
+    }
+
+    impl EntityState for BankAccountActor {
+        type Msg = TestMsg;
+
+        fn state(&self) -> Option<TestMsg> {
+            self.state.clone().map(TestMsg::AccountState)
+        }
+    }
+
+    #[derive(Clone)]
+    pub struct BankAccountActorFact;
+
+    impl EntityActorProps for BankAccountActorFact {
+        type Msg = TestMsg;
+
+        fn props(&self, id: String, persistence: PersistenceConf) -> BoxActorProd<Self::Msg> {
+            Props::new_args(Box::new(BankAccountActor::new), (id, persistence))
+        }
+
+        // accounts are opened under the holder's name
+        fn extract_key(&self, cmd: &TestMsg) -> Option<String> {
+            match cmd {
+                TestMsg::CreateAccountCmd(name) => Some(name.clone()),
+                _ => None
+            }
+        }
+
+        fn compensate(&self, _id: &str, cmd: &TestMsg) -> Option<TestMsg> {
+            match cmd {
+                TestMsg::AddAmountCmd(amount) => Some(TestMsg::AddAmountCmd(-amount)),
+                _ => None
+            }
+        }
+
+        fn is_query(&self, cmd: &TestMsg) -> bool {
+            match cmd {
+                TestMsg::BalanceQry => true,
+                _ => false
+            }
+        }
+    } 
+
+    #[test]
+    fn cqrs() {
+        let model: DefaultModel<TestMsg> = DefaultModel::new();
+        let system = ActorSystem::new(&model).unwrap();
+        
+        let mut em = TestEntity::new(&system,
+                                    BankAccountActorFact,
+                                    "BankAccont",
+                                    None).unwrap();
+
+        let accounts = [("12345678", "Alex Kamal", 100),
+                        ("87654321", "Jules-Pierre Mao", 50),
+                        ("65555555", "J. Miller", 50)];
+
+        for &(number, name, _) in accounts.iter() {
+            let outcome = em.cmd(number, TestMsg::CreateAccountCmd(name.into()));
+            match outcome.result {
+                Some(CommandResult::Accepted(ref events)) => assert_eq!(events.len(), 1),
+                result => panic!("Unexpected result {:?}", result)
+            }
+            match outcome.events.as_slice() {
+                [TestMsg::AccountCreatedEvt(account)] => {
+                    assert_eq!(account.id, number);
+                    assert_eq!(account.name, name);
+                    assert_eq!(account.balance, 0);
+                }
+                events => panic!("Unexpected events {:?}", events)
+            }
+        }
+
+        for &(number, _, amount) in accounts.iter() {
+            for _ in 0..3 {
+                let outcome = em.cmd(number, TestMsg::AddAmountCmd(amount));
+                match outcome.events.as_slice() {
+                    [TestMsg::AmountAddedEvt(added)] => assert_eq!(*added, amount),
+                    events => panic!("Unexpected events {:?}", events)
+                }
+            }
+        }
+
+        for &(number, _, amount) in accounts.iter() {
+            assert_eq!(em.events(number).len(), 4);
+
+            let outcome = em.cmd(number, TestMsg::BalanceQry);
+            assert!(outcome.events.is_empty());
+            match outcome.result {
+                Some(CommandResult::NoOp) => {}
+                result => panic!("Unexpected result {:?}", result)
+            }
+            match outcome.replies.as_slice() {
+                [TestMsg::BalanceResult(balance)] => assert_eq!(*balance, amount * 3),
+                replies => panic!("Unexpected replies {:?}", replies)
+            }
+
+            let outcome = em.cmd(number, TestMsg::CreateAccountCmd("Fred Johnson".into()));
+            assert!(outcome.events.is_empty());
+            match outcome.result {
+                Some(CommandResult::Rejected(_)) => {}
+                result => panic!("Unexpected result {:?}", result)
+            }
+        }
+    }
+
+    #[test]
+    fn entity_fn() {
+        let model: DefaultModel<TestMsg> = DefaultModel::new();
+        let system = ActorSystem::new(&model).unwrap();
+
+        let mut em = TestEntity::new(&system,
+                                    entity_props(BankAccountActor::new),
+                                    "BankAccount",
+                                    None).unwrap();
+
+        let outcome = em.cmd("1", TestMsg::CreateAccountCmd("Holden".into()));
+        match outcome.result {
+            Some(CommandResult::Accepted(_)) => {}
+            result => panic!("Unexpected result {:?}", result)
+        }
+        assert_eq!(em.events("1").len(), 1);
+    }
+
+    #[test]
+    fn rehydrate() {
+        let (_, em, probe, listen) = manager(BankAccountActorFact, "BankAccount", |_| {});
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Naomi Nagata".into())), Some(probe.clone()));
+        replied(&listen);
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(100)), Some(probe.clone()));
+        replied(&listen);
+
+        // the restarted instance replays its events before the query
+        em.tell(ControlMsg::Rehydrate("1".into()), None);
+        em.tell(ControlMsg::GetState("1".into()), Some(probe.clone()));
+        match listen.recv() {
+            ControlMsg::State(_, Some(state)) => match *state {
+                TestMsg::AccountState(account) => assert_eq!(account.balance, 100),
+                state => panic!("Unexpected state {:?}", state)
+            },
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+
+        // still the same instance
+        em.tell(ControlMsg::Stats, Some(probe));
+        assert_eq!(stats(&listen).instances, 1);
+    }
+
+    // Forwards control messages it receives to a probe
+    struct ProbeActor {
+        probe: Option<ChannelProbe<(), ControlMsg<TestMsg>>>,
+    }
+
+    impl ProbeActor {
+        fn actor() -> BoxActor<TestMsg> {
+            Box::new(ProbeActor { probe: None })
+        }
+    }
+
+    impl Actor for ProbeActor {
+        type Msg = TestMsg;
+
+        fn receive(&mut self,
+                    _: &Context<TestMsg>,
+                    msg: TestMsg,
+                    _: Option<ActorRef<TestMsg>>) {
+            match msg {
+                TestMsg::Probe(probe) => self.probe = Some(probe),
+                TestMsg::Control(msg) => self.probe.event(msg),
+                _ => {}
+            }
+        }
+    }
+
+    fn probe_actor(system: &ActorSystem<TestMsg>,
+                    name: &str) -> (ActorRef<TestMsg>, impl ProbeReceive<Msg=ControlMsg<TestMsg>>) {
+        let actor = system.actor_of(Props::new(Box::new(ProbeActor::actor)), name).unwrap();
+        let (probe, listen) = probe();
+        actor.tell(TestMsg::Probe(probe), None);
+        (actor, listen)
+    }
+
+    // starts a system with a manager, configured by `configure`, and a probe
+    fn manager<Pro, F>(props: Pro,
+                        name: &str,
+                        configure: F) -> (ActorSystem<TestMsg>,
+                                            ActorRef<TestMsg>,
+                                            ActorRef<TestMsg>,
+                                            impl ProbeReceive<Msg=ControlMsg<TestMsg>>)
+        where Pro: EntityActorProps<Msg=TestMsg> + 'static, F: FnOnce(&mut EntityActorConfig)
+    {
+        let model: DefaultModel<TestMsg> = DefaultModel::new();
+        let system = ActorSystem::new(&model).unwrap();
+
+        let mut conf = EntityActorConfig::from(&system.config());
+        configure(&mut conf);
+        let em = Entity::new(&system, props, name, Some(conf)).unwrap();
+        let (probe, listen) = probe_actor(&system, "probe");
+        (system, em, probe, listen)
+    }
+
+    fn stats(listen: &impl ProbeReceive<Msg=ControlMsg<TestMsg>>) -> EntityStats {
+        match listen.recv() {
+            ControlMsg::StatsResult(stats) => stats,
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+    }
+
+    fn replied(listen: &impl ProbeReceive<Msg=ControlMsg<TestMsg>>) -> CommandResult<TestMsg> {
+        match listen.recv() {
+            ControlMsg::CommandResult(_, result) => result,
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+    }
+
+    // polls `done` until it holds, for changes made asynchronously,
+    // e.g. a parent removing a stopped child, or a timer firing
+    fn eventually<F: FnMut() -> bool>(mut done: F) -> bool {
+        let started = time::Instant::now();
+        while !done() {
+            if started.elapsed() > time::Duration::from_secs(5) {
+                return false;
+            }
+            thread::yield_now();
+        }
+        true
+    }
+
+    #[test]
+    fn control() {
+        let (_, em, probe, listen) = manager(BankAccountActorFact, "BankAccount", |_| {});
+
+        // the instances report they are ready before replying
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Amos Burton".into())), Some(probe.clone()));
+        em.tell(CQMsg::Cmd("2".into(), TestMsg::CreateAccountCmd("Naomi Nagata".into())), Some(probe.clone()));
+        em.tell(CQMsg::Cmd("3".into(), TestMsg::CreateAccountCmd("James Holden".into())), Some(probe.clone()));
+        for _ in 0..3 {
+            replied(&listen);
+        }
+        em.tell(ControlMsg::Stats, Some(probe.clone()));
+        let stats_result = stats(&listen);
+        assert_eq!(stats_result.instances, 3);
+        assert_eq!(stats_result.keyspace, "BankAccount");
+
+        em.tell(ControlMsg::DumpInstances, Some(probe.clone()));
+        match listen.recv() {
+            ControlMsg::DumpInstancesResult(instances) => {
+                assert_eq!(instances.len(), 3);
+                assert!(instances.iter().all(|i| i.last_used >= i.created_at));
+            }
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+
+        em.tell(ControlMsg::Passivate("1".into()), None);
+        em.tell(ControlMsg::Stats, Some(probe.clone()));
+        assert_eq!(stats(&listen).instances, 2);
+
+        em.tell(ControlMsg::Clear, None);
+        em.tell(ControlMsg::Stats, Some(probe));
+        let stats = stats(&listen);
+        assert_eq!(stats.instances, 0);
+        assert_eq!(stats.wake_latency.count, 3);
+        assert_eq!(stats.wakes, 3);
+        assert_eq!(stats.passivations, 3);
+    }
+
+    #[test]
+    fn registry() {
+        let model: DefaultModel<TestMsg> = DefaultModel::new();
+        let system = ActorSystem::new(&model).unwrap();
+
+        Entity::new(&system, BankAccountActorFact, "Checking", None).unwrap();
+        Entity::new(&system, BankAccountActorFact, "Savings", None).unwrap();
+        probe_actor(&system, "probe");
+
+        let mut names: Vec<String> = Entity::registry(&system)
+                                        .into_iter()
+                                        .map(|(name, _)| name)
+                                        .collect();
+        names.sort();
+        assert_eq!(names, vec!["Checking", "Savings"]);
+    }
+
+    #[test]
+    fn group() {
+        let model: DefaultModel<TestMsg> = DefaultModel::new();
+        let system = ActorSystem::new(&model).unwrap();
+
+        let group = EntityGroup::new(&system, "Bank").unwrap();
+        let em = group.entity(BankAccountActorFact, "Checking", None).unwrap();
+        group.entity(BankAccountActorFact, "Savings", None).unwrap();
+        Entity::new(&system, BankAccountActorFact, "Loans", None).unwrap();
+        assert!(group.entity(BankAccountActorFact, "Savings", None).is_err());
+
+        let (probe, listen) = probe_actor(&system, "probe");
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Roberta Draper".into())), None);
+        em.tell(ControlMsg::Stats, Some(probe));
+        assert_eq!(stats(&listen).instances, 1);
+
+        let names = || {
+            let mut names: Vec<String> = Entity::registry(&system)
+                                            .into_iter()
+                                            .map(|(name, _)| name)
+                                            .collect();
+            names.sort();
+            names
+        };
+        assert_eq!(names(), vec!["Checking", "Loans", "Savings"]);
+
+        // stopping the group stops all of its managers
+        system.stop(group.actor());
+        assert!(eventually(|| names() == vec!["Loans"]));
+    }
+
+    #[test]
+    fn stop() {
+        let model: DefaultModel<TestMsg> = DefaultModel::new();
+        let system = ActorSystem::new(&model).unwrap();
+
+        let em = Entity::new(&system, BankAccountActorFact, "Checking", None).unwrap();
+        Entity::new(&system, BankAccountActorFact, "Savings", None).unwrap();
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Camina Drummer".into())), None);
+        em.tell(CQMsg::Cmd("2".into(), TestMsg::CreateAccountCmd("Klaes Ashford".into())), None);
+
+        Entity::stop(&system, "Checking");
+        let names: Vec<String> = Entity::registry(&system)
+                                        .into_iter()
+                                        .map(|(name, _)| name)
+                                        .collect();
+        assert_eq!(names, vec!["Savings"]);
+
+        // already stopped
+        Entity::stop(&system, "Checking");
+    }
+
+    #[test]
+    fn missed_sweep() {
+        let interval = time::Duration::from_secs(60);
+        let start = time::Instant::now();
+        let mut sweep = SweepSchedule::new(interval, start);
+
+        // late, but not yet overdue
+        assert!(!sweep.overdue(start + interval * 2));
+
+        // the tick was never received
+        let now = start + interval * 2 + time::Duration::from_secs(1);
+        assert!(sweep.overdue(now));
+        sweep.scheduled(now);
+
+        // rescheduling failed again, the allowance doubles
+        assert!(!sweep.overdue(now + interval * 2));
+        let now = now + interval * 3 + time::Duration::from_secs(1);
+        assert!(sweep.overdue(now));
+        sweep.scheduled(now);
+
+        // ticks are received again
+        sweep.ticked();
+        sweep.scheduled(now + interval);
+        assert!(!sweep.overdue(now + interval * 3));
+        assert!(sweep.overdue(now + interval * 3 + time::Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn sweep_times() {
+        let interval = time::Duration::from_secs(60);
+        let start = time::Instant::now();
+        let wall = time::SystemTime::now();
+        let mut sweep = SweepSchedule::new(interval, start);
+
+        assert_eq!(sweep.last_at(start, wall), None);
+        assert_eq!(sweep.next_at(start, wall), wall + interval);
+
+        let now = start + interval;
+        let wall = wall + interval;
+        sweep.swept(now);
+        sweep.scheduled(now);
+        assert_eq!(sweep.last_at(now + interval / 2, wall + interval / 2), Some(wall));
+        assert_eq!(sweep.next_at(now + interval / 2, wall + interval / 2), wall + interval);
+
+        // an overdue sweep is reported as due now
+        assert_eq!(sweep.next_at(now + interval * 2, wall + interval * 2), wall + interval * 2);
+    }
+
+    // Serializes `AmountAddedEvt` as the amount's bytes
+    struct AmountSerializer;
+
+    impl Serializer<TestMsg> for AmountSerializer {
+        fn format(&self) -> &str {
+            "amount"
+        }
+
+        fn serialize(&self, evt: &TestMsg) -> Vec<u8> {
+            match *evt {
+                TestMsg::AmountAddedEvt(amount) => amount.to_be_bytes().to_vec(),
+                ref evt => panic!("Unexpected event {:?}", evt)
+            }
+        }
+
+        fn deserialize(&self, bytes: &[u8]) -> Result<TestMsg, String> {
+            let mut amount = [0; 4];
+            if bytes.len() != amount.len() {
+                return Err(format!("Expected 4 bytes, got {}", bytes.len()));
+            }
+            amount.copy_from_slice(bytes);
+            Ok(TestMsg::AmountAddedEvt(i32::from_be_bytes(amount)))
+        }
+    }
+
+    #[test]
+    fn serializer() {
+        let evt = crate::serializer::encode(&AmountSerializer, &TestMsg::AmountAddedEvt(-250));
+        match evt {
+            TestMsg::Control(ControlMsg::Serialized(ref format, ref bytes)) => {
+                assert_eq!(format, "amount");
+                assert_eq!(bytes.len(), 4);
+            }
+            ref evt => panic!("Unexpected event {:?}", evt)
+        }
+
+        match Entity::decode(&AmountSerializer, evt) {
+            Ok(TestMsg::AmountAddedEvt(amount)) => assert_eq!(amount, -250),
+            evt => panic!("Unexpected event {:?}", evt)
+        }
+
+        // events persisted without a serializer are unchanged
+        match Entity::decode(&AmountSerializer, TestMsg::AmountAddedEvt(100)) {
+            Ok(TestMsg::AmountAddedEvt(amount)) => assert_eq!(amount, 100),
+            evt => panic!("Unexpected event {:?}", evt)
+        }
+
+        let other = TestMsg::Control(ControlMsg::Serialized("json".into(), b"100".to_vec()));
+        assert!(Entity::decode(&AmountSerializer, other).is_err());
+    }
+
+    #[test]
+    fn max_event_bytes() {
+        let evt = TestMsg::AmountAddedEvt(-250);
+        match crate::serializer::encode_bounded(&AmountSerializer, &evt, Some(2)) {
+            Err(e) => assert_eq!(e, EventTooLarge { size: 4, max: 2 }),
+            evt => panic!("Unexpected event {:?}", evt)
+        }
+
+        assert!(crate::serializer::encode_bounded(&AmountSerializer, &evt, Some(4)).is_ok());
+        assert!(crate::serializer::encode_bounded(&AmountSerializer, &evt, None).is_ok());
+
+        let mut config = config::Config::new();
+        config.set("cqrs.max_event_bytes", 1024).unwrap();
+        config.set("cqrs.entities.BankAccount.max_event_bytes", 64).unwrap();
+        assert_eq!(crate::max_event_bytes(&config, "BankAccount"), Some(64));
+        assert_eq!(crate::max_event_bytes(&config, "Loan"), Some(1024));
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn metrics() {
+        let model: DefaultModel<TestMsg> = DefaultModel::new();
+        let system = ActorSystem::new(&model).unwrap();
+
+        let em = Entity::new(&system, BankAccountActorFact, "Checking", None).unwrap();
+        Entity::new(&system, BankAccountActorFact, "Savings", None).unwrap();
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Cotyar Ghazi".into())), None);
+        em.tell(ControlMsg::Passivate("1".into()), None);
+
+        let text = Entity::metrics_text(&system);
+        assert!(text.contains("# TYPE cqrs_instances gauge"));
+        assert!(text.contains("cqrs_instances{entity=\"Checking\"} 0"));
+        assert!(text.contains("cqrs_wakes_total{entity=\"Checking\"} 1"));
+        assert!(text.contains("cqrs_passivations_total{entity=\"Checking\"} 1"));
+        assert!(text.contains("cqrs_wakes_total{entity=\"Savings\"} 0"));
+    }
+
+    #[test]
+    fn rate_limit() {
+        let (_, em, probe, listen) = manager(BankAccountActorFact, "BankAccount", |conf| {
+            conf.per_id_rate_limit = Some(RateLimit { capacity: 2, refill_per_sec: 0 });
+        });
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Bobbie Draper".into())), None);
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(100)), None);
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(100)), Some(probe.clone()));
+
+        match listen.recv() {
+            ControlMsg::CommandRejected(id, reason) => {
+                assert_eq!(id, "1");
+                assert_eq!(reason, RejectReason::RateLimited);
+            }
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+    }
+
+    #[test]
+    fn subscribe() {
+        let model: DefaultModel<TestMsg> = DefaultModel::new();
+        let system = ActorSystem::new(&model).unwrap();
+
+        let em = Entity::new(&system,
+                            BankAccountActorFact,
+                            "BankAccount",
+                            None).unwrap();
+        let (one, one_listen) = probe_actor(&system, "one");
+        let (all, all_listen) = probe_actor(&system, "all");
+        let (stats_probe, stats_listen) = probe_actor(&system, "stats");
+
+        em.tell(ControlMsg::Subscribe(Some("1".into()), one.clone()), None);
+        em.tell(ControlMsg::Subscribe(None, all.clone()), None);
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Chrisjen Avasarala".into())), None);
+        em.tell(CQMsg::Cmd("2".into(), TestMsg::CreateAccountCmd("Fred Johnson".into())), None);
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(100)), None);
+
+        let events = |listen: &dyn ProbeReceive<Msg=ControlMsg<TestMsg>>, n| {
+            (0..n).map(|_| match listen.recv() {
+                        ControlMsg::Event(id, evt) => (id, *evt),
+                        msg => panic!("Unexpected message {:?}", msg)
+                    })
+                    .collect::<Vec<_>>()
+        };
+
+        let one_events = events(&one_listen, 2);
+        assert!(one_events.iter().all(|(id, _)| id == "1"));
+        match one_events[1].1 {
+            TestMsg::AmountAddedEvt(amount) => assert_eq!(amount, 100),
+            ref evt => panic!("Unexpected event {:?}", evt)
+        }
+
+        let mut ids: Vec<String> = events(&all_listen, 3).into_iter().map(|(id, _)| id).collect();
+        ids.sort();
+        assert_eq!(ids, vec!["1", "1", "2"]);
+
+        em.tell(ControlMsg::Stats, Some(stats_probe.clone()));
+        assert_eq!(stats(&stats_listen).subscribers, 2);
+
+        // terminated subscribers are unsubscribed
+        system.stop(&all);
+        assert!(eventually(|| {
+            em.tell(ControlMsg::Stats, Some(stats_probe.clone()));
+            stats(&stats_listen).subscribers == 1
+        }));
+    }
+
+    #[test]
+    fn publish_after_wake() {
+        let (_, em, probe, listen) = manager(BankAccountActorFact, "BankAccount", |_| {});
+        em.tell(ControlMsg::Subscribe(Some("1".into()), probe), None);
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Klaes Ashford".into())), None);
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(5)), None);
+        em.tell(ControlMsg::Passivate("1".into()), None);
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(10)), None);
+
+        // the events replayed by the woken instance aren't published again
+        let amounts: Vec<i32> = (0..3).map(|_| match listen.recv() {
+                                    ControlMsg::Event(_, evt) => match *evt {
+                                        TestMsg::AccountCreatedEvt(_) => 0,
+                                        TestMsg::AmountAddedEvt(amount) => amount,
+                                        evt => panic!("Unexpected event {:?}", evt)
+                                    },
+                                    msg => panic!("Unexpected message {:?}", msg)
+                                })
+                                .collect();
+        assert_eq!(amounts, vec![0, 5, 10]);
+    }
+
+    #[test]
+    fn publish_events() {
+        let (system, em, probe, listen) = manager(BankAccountActorFact, "BankAccount", |conf| {
+            conf.publish_events = true;
+        });
+
+        let events = Entity::events_channel(&system, "BankAccount").unwrap();
+        events.tell(ChannelMsg::Subscribe(All.into(), probe), None);
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Anderson Dawes".into())), None);
+        match listen.recv() {
+            ControlMsg::Event(id, evt) => {
+                assert_eq!(id, "1");
+                match *evt {
+                    TestMsg::AccountCreatedEvt(_) => {}
+                    ref evt => panic!("Unexpected event {:?}", evt)
+                }
+            }
+            msg => panic!("Unexpected message {:?}", msg)
+        }
+
+        // the channel is stopped with the manager
+        Entity::stop(&system, "BankAccount");
+        assert!(eventually(|| Entity::events_channel(&system, "BankAccount").is_none()));
+        assert!(Entity::events_channel(&system, "Other").is_none());
+    }
+
+    #[test]
+    fn sleep_after() {
+        let (_, em, probe, listen) = manager(BankAccountActorFact, "BankAccount", |conf| {
+            conf.sleep_after_secs = 1;
+        });
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Elvi Okoye".into())), None);
+        em.tell(CQMsg::Cmd("2".into(), TestMsg::CreateAccountCmd("Clarissa Mao".into())), None);
+        // both idle for longer than `sleep_after_secs`
+        thread::sleep(time::Duration::from_millis(1100));
+
+        // "1" was used recently, "2" is idle
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(100)), None);
+        em.tell(ActorMsg::Tick, None);
+        em.tell(ControlMsg::DumpInstances, Some(probe));
+        match listen.recv() {
+            ControlMsg::DumpInstancesResult(instances) => {
+                let ids: Vec<String> = instances.into_iter().map(|i| i.id).collect();
+                assert_eq!(ids, vec!["1"]);
+            }
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+    }
+
+    #[test]
+    fn passivation_check() {
+        let model: DefaultModel<TestMsg> = DefaultModel::new();
+        let system = ActorSystem::new(&model).unwrap();
+
+        let mut conf = EntityActorConfig::from(&system.config());
+        conf.sleep_after_secs = 0;
+        conf.passivation_check_timeout = Some(time::Duration::from_millis(200));
+
+        let em = Entity::new(&system,
+                            BankAccountActorFact,
+                            "BankAccount",
+                            Some(conf.clone())).unwrap();
+        let waking = Entity::new(&system,
+                                WakingActorFact,
+                                "Waking",
+                                Some(conf)).unwrap();
+        let (probe, listen) = probe_actor(&system, "probe");
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Marco Inaros".into())), Some(probe.clone()));
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(100)), Some(probe.clone()));
+        em.tell(CQMsg::Cmd("2".into(), TestMsg::CreateAccountCmd("Filip Inaros".into())), Some(probe.clone()));
+        for _ in 0..3 {
+            replied(&listen);
+        }
+        waking.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(1)), None);
+
+        em.tell(ActorMsg::Tick, None);
+        waking.tell(ActorMsg::Tick, None);
+
+        // "1" vetoed, "2" agreed
+        let ids = || {
+            em.tell(ControlMsg::DumpInstances, Some(probe.clone()));
+            match listen.recv() {
+                ControlMsg::DumpInstancesResult(instances) => instances.into_iter().map(|i| i.id).collect::<Vec<String>>(),
+                msg => panic!("Unexpected reply {:?}", msg)
+            }
+        };
+        assert!(eventually(|| ids().len() == 1));
+        assert_eq!(ids(), vec!["1"]);
+
+        // no reply, passivated once the check times out
+        waking.tell(ControlMsg::Stats, Some(probe.clone()));
+        assert_eq!(stats(&listen).instances, 1);
+        assert!(eventually(|| {
+            waking.tell(ControlMsg::Stats, Some(probe.clone()));
+            stats(&listen).instances == 0
+        }));
+    }
+
+    #[test]
+    fn max_resident_age() {
+        let (_, em, probe, listen) = manager(BankAccountActorFact, "BankAccount", |conf| {
+            conf.max_resident_age = Some(time::Duration::from_millis(50));
+        });
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Julie Mao".into())), Some(probe.clone()));
+        replied(&listen);
+        // let the instance outlive the maximum age
+        thread::sleep(time::Duration::from_millis(100));
+
+        // recently used, but resident for longer than the maximum age
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(100)), None);
+        em.tell(ActorMsg::Tick, None);
+        em.tell(ControlMsg::Stats, Some(probe));
+        assert_eq!(stats(&listen).instances, 0);
+    }
+
+    #[test]
+    fn create_failure() {
+        let faults = FaultInjector::new();
+        faults.inject(Some("1"), Fault::Create);
+
+        let (_, em, probe, listen) = manager(BankAccountActorFact, "BankAccount", |conf| {
+            conf.faults = Some(faults.clone());
+        });
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Amos Burton".into())), Some(probe.clone()));
+        match listen.recv() {
+            ControlMsg::CommandRejected(id, reason) => {
+                assert_eq!(id, "1");
+                assert_eq!(reason, RejectReason::CreateFailed);
+            }
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+
+        em.tell(CQMsg::Cmd("2".into(), TestMsg::CreateAccountCmd("Peaches Mao".into())), None);
+        em.tell(ControlMsg::Stats, Some(probe.clone()));
+        assert_eq!(stats(&listen).instances, 1);
+
+        // the fault can be cleared while the entity is running
+        faults.clear();
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Amos Burton".into())), None);
+        em.tell(ControlMsg::Stats, Some(probe));
+        assert_eq!(stats(&listen).instances, 2);
+    }
+
+    #[test]
+    fn command_history() {
+        let (_, em, probe, listen) = manager(BankAccountActorFact, "BankAccount", |conf| {
+            conf.command_history = Some(2);
+        });
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Cortazar".into())), None);
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(100)), None);
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(200)), None);
+        em.tell(ControlMsg::CommandHistory("1".into()), Some(probe.clone()));
+        match listen.recv() {
+            ControlMsg::CommandHistoryResult(id, history) => {
+                assert_eq!(id, "1");
+                let cmds: Vec<String> = history.into_iter().map(|r| r.cmd).collect();
+                assert_eq!(cmds, vec!["AddAmountCmd(100)", "AddAmountCmd(200)"]);
+            }
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+
+        // cleared on passivation
+        em.tell(ControlMsg::Passivate("1".into()), None);
+        em.tell(ControlMsg::CommandHistory("1".into()), Some(probe));
+        match listen.recv() {
+            ControlMsg::CommandHistoryResult(_, history) => assert!(history.is_empty()),
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+    }
+
+    #[test]
+    fn cmd_existing() {
+        let (_, em, probe, listen) = manager(BankAccountActorFact, "BankAccount", |_| {});
+
+        let cmd = |id: &str, cmd| ControlMsg::CmdExisting(id.into(), Box::new(cmd));
+
+        em.tell(cmd("1", TestMsg::AddAmountCmd(100)), Some(probe.clone()));
+        match listen.recv() {
+            ControlMsg::CommandRejected(id, RejectReason::NotFound) => assert_eq!(id, "1"),
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+
+        // persisted, but asleep
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Prax Meng".into())), Some(probe.clone()));
+        replied(&listen);
+        em.tell(ControlMsg::Clear, None);
+
+        em.tell(cmd("1", TestMsg::AddAmountCmd(100)), Some(probe.clone()));
+        replied(&listen);
+        em.tell(cmd("2", TestMsg::AddAmountCmd(100)), Some(probe.clone()));
+        match listen.recv() {
+            ControlMsg::CommandRejected(id, RejectReason::NotFound) => assert_eq!(id, "2"),
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+
+        em.tell(ControlMsg::Stats, Some(probe));
+        assert_eq!(stats(&listen).instances, 1);
+    }
+
+    #[test]
+    fn get_state() {
+        let (_, em, probe, listen) = manager(BankAccountActorFact, "BankAccount", |_| {});
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Alex Kamal".into())), None);
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(100)), None);
+        em.tell(ControlMsg::GetState("1".into()), Some(probe.clone()));
+        match listen.recv() {
+            ControlMsg::State(id, Some(state)) => {
+                assert_eq!(id, "1");
+                match *state {
+                    TestMsg::AccountState(account) => assert_eq!(account.balance, 100),
+                    state => panic!("Unexpected state {:?}", state)
+                }
+            }
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+
+        // woken to reply, but never created
+        em.tell(ControlMsg::GetState("2".into()), Some(probe));
+        match listen.recv() {
+            ControlMsg::State(id, None) => assert_eq!(id, "2"),
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+    }
+
+    // Never reports that it is ready, so it stays waking until passivated
+    struct WakingActor;
+
+    impl Actor for WakingActor {
+        type Msg = TestMsg;
+
+        fn receive(&mut self, _: &Context<TestMsg>, _: TestMsg, _: Option<ActorRef<TestMsg>>) {}
+    }
+
+    #[derive(Clone)]
+    struct WakingActorFact;
+
+    impl EntityActorProps for WakingActorFact {
+        type Msg = TestMsg;
+
+        fn props(&self, _: String, _: PersistenceConf) -> BoxActorProd<Self::Msg> {
+            Props::new(Box::new(|| -> BoxActor<TestMsg> { Box::new(WakingActor) }))
+        }
+    }
+
+    #[test]
+    fn max_concurrent_wakes() {
+        let (_, em, probe, listen) = manager(WakingActorFact, "Waking", |conf| {
+            conf.max_concurrent_wakes = Some(2);
+        });
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(1)), None);
+        em.tell(CQMsg::Cmd("2".into(), TestMsg::AddAmountCmd(1)), None);
+        em.tell(CQMsg::Cmd("3".into(), TestMsg::AddAmountCmd(1)), None);
+        em.tell(CQMsg::Cmd("3".into(), TestMsg::AddAmountCmd(1)), None);
+        em.tell(ControlMsg::Stats, Some(probe.clone()));
+        assert_eq!(stats(&listen).instances, 2);
+
+        // passivating a waking instance frees a slot
+        em.tell(ControlMsg::Passivate("1".into()), None);
+        em.tell(ControlMsg::DumpInstances, Some(probe));
+        match listen.recv() {
+            ControlMsg::DumpInstancesResult(instances) => {
+                let mut ids: Vec<String> = instances.into_iter().map(|i| i.id).collect();
+                ids.sort();
+                assert_eq!(ids, vec!["2", "3"]);
+            }
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+    }
+
+    #[test]
+    fn draining() {
+        let (_, em, probe, listen) = manager(WakingActorFact, "Waking", |conf| {
+            conf.max_concurrent_wakes = Some(1);
+        });
+
+        // "2" waits for "1" to wake, and is rejected once the manager drains
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(1)), None);
+        em.tell(CQMsg::Cmd("2".into(), TestMsg::AddAmountCmd(1)), Some(probe.clone()));
+        em.tell(ControlMsg::Stats, Some(probe.clone()));
+        assert_eq!(stats(&listen).instances, 1);
+
+        em.tell(ControlMsg::Stop, None);
+        match listen.recv() {
+            ControlMsg::CommandRejected(id, reason) => {
+                assert_eq!(id, "2");
+                assert_eq!(reason, RejectReason::Draining);
+            }
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+    }
+
+    #[test]
+    fn max_pending_cmds() {
+        let (_, em, probe, listen) = manager(WakingActorFact, "Waking", |conf| {
+            conf.max_concurrent_wakes = Some(1);
+            conf.max_pending_cmds = Some(1);
+        });
+
+        // "1" is waking, "2" waits for it and "3" is over the limit
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(1)), None);
+        em.tell(CQMsg::Cmd("2".into(), TestMsg::AddAmountCmd(1)), None);
+        em.tell(CQMsg::Cmd("3".into(), TestMsg::AddAmountCmd(1)), Some(probe));
+        match listen.recv() {
+            ControlMsg::CommandRejected(id, reason) => {
+                assert_eq!(id, "3");
+                assert_eq!(reason, RejectReason::Busy);
+            }
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+    }
+
+    #[test]
+    fn cmd_auto_key() {
+        let (_, em, probe, listen) = manager(BankAccountActorFact, "BankAccount", |_| {});
+
+        let cmd = TestMsg::CreateAccountCmd("Julie Mao".into());
+        em.tell(ControlMsg::CmdAutoKey(Box::new(cmd)), None);
+
+        // no key can be derived, so nothing is woken
+        em.tell(ControlMsg::CmdAutoKey(Box::new(TestMsg::AddAmountCmd(100))), None);
+
+        em.tell(ControlMsg::GetState("Julie Mao".into()), Some(probe.clone()));
+        match listen.recv() {
+            ControlMsg::State(_, Some(state)) => match *state {
+                TestMsg::AccountState(account) => assert_eq!(account.name, "Julie Mao"),
+                state => panic!("Unexpected state {:?}", state)
+            },
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+
+        em.tell(ControlMsg::Stats, Some(probe));
+        assert_eq!(stats(&listen).instances, 1);
+    }
+
+    #[test]
+    fn saga() {
+        let (_, em, probe, listen) = manager(BankAccountActorFact, "BankAccount", |_| {});
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Praxidike Meng".into())), None);
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(100)), None);
+        em.tell(CQMsg::Cmd("2".into(), TestMsg::CreateAccountCmd("Mei Meng".into())), None);
+
+        let transfer = vec![("1".to_string(), TestMsg::AddAmountCmd(-30)),
+                            ("2".to_string(), TestMsg::AddAmountCmd(30))];
+        em.tell(ControlMsg::Saga(transfer), Some(probe.clone()));
+        match listen.recv() {
+            ControlMsg::SagaResult(SagaOutcome::Completed) => {}
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+
+        // the second step is rejected, so the first is compensated
+        let failing = vec![("1".to_string(), TestMsg::AddAmountCmd(-30)),
+                            ("2".to_string(), TestMsg::CreateAccountCmd("Mei Meng".into()))];
+        em.tell(ControlMsg::Saga(failing), Some(probe.clone()));
+        match listen.recv() {
+            ControlMsg::SagaResult(SagaOutcome::Compensated(id, _)) => assert_eq!(id, "2"),
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+
+        // and a dry run is rejected like the command would be
+        em.tell(ControlMsg::DryRun("1".into(), Box::new(TestMsg::CreateAccountCmd("Bobbie Draper".into()))), Some(probe.clone()));
+        match replied(&listen) {
+            CommandResult::Rejected(_) => {}
+            result => panic!("Unexpected result {:?}", result)
+        }
+
+        em.tell(ControlMsg::GetState("1".into()), Some(probe));
+        match listen.recv() {
+            ControlMsg::State(_, Some(state)) => match *state {
+                TestMsg::AccountState(account) => assert_eq!(account.balance, 70),
+                state => panic!("Unexpected state {:?}", state)
+            },
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+    }
+
+    #[test]
+    fn min_residency() {
+        let (_, em, probe, listen) = manager(BankAccountActorFact, "BankAccount", |conf| {
+            conf.sleep_after_secs = 0;
+            conf.min_residency = Some(time::Duration::from_millis(500));
+        });
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Sadavir Errinwright".into())), Some(probe.clone()));
+        replied(&listen);
+
+        // idle, but woken too recently to sleep
+        em.tell(ActorMsg::Tick, None);
+        em.tell(ControlMsg::Stats, Some(probe.clone()));
+        assert_eq!(stats(&listen).instances, 1);
+
+        assert!(eventually(|| {
+            em.tell(ActorMsg::Tick, None);
+            em.tell(ControlMsg::Stats, Some(probe.clone()));
+            stats(&listen).instances == 0
+        }));
+    }
+
+    // counts the events of plain bank accounts
+    #[derive(Clone)]
+    struct CountingActorFact {
+        events: Arc<AtomicUsize>,
+    }
+
+    impl EntityActorProps for CountingActorFact {
+        type Msg = TestMsg;
+
+        fn props(&self, id: String, persistence: PersistenceConf) -> BoxActorProd<Self::Msg> {
+            BankAccountActorFact.props(id, persistence)
+        }
+
+        fn on_event(&self, _id: &str, _evt: &TestMsg) {
+            self.events.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn on_event() {
+        let events = Arc::new(AtomicUsize::new(0));
+        let (_, em, probe, listen) = manager(CountingActorFact { events: events.clone() }, "Counting", |conf| {
+            conf.passivation_grace = Some(time::Duration::from_secs(1));
+        });
+
+        // accounts reply once the event is applied
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Elvi Okoye".into())), Some(probe.clone()));
+        replied(&listen);
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(100)), Some(probe.clone()));
+        replied(&listen);
+        assert_eq!(events.load(Ordering::SeqCst), 2);
+
+        // replayed events aren't counted again
+        em.tell(ControlMsg::Passivate("1".into()), None);
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(300)), Some(probe));
+        replied(&listen);
+        assert_eq!(events.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn rewarm_on_restart() {
+        let (_, em, probe, listen) = manager(BankAccountActorFact, "BankAccount", |conf| {
+            conf.rewarm_on_restart = Some(2);
+        });
+
+        for (id, name) in [("1", "Joe Miller"), ("2", "Octavia Muss"), ("3", "Havelock")].iter() {
+            em.tell(CQMsg::Cmd(id.to_string(), TestMsg::CreateAccountCmd(name.to_string())), Some(probe.clone()));
+            replied(&listen);
+        }
+
+        // the hot set is recorded by the sweep, and the instances are
+        // rewarmed before the manager handles the next message
+        em.tell(ActorMsg::Tick, None);
+        em.tell(ControlMsg::Stats, Some(probe.clone()));
+        stats(&listen);
+        em.sys_tell(SystemMsg::ActorCmd(ActorCmd::Restart), None);
+
+        em.tell(ControlMsg::DumpInstances, Some(probe));
+        match listen.recv() {
+            ControlMsg::DumpInstancesResult(instances) => {
+                let mut ids: Vec<String> = instances.into_iter().map(|i| i.id).collect();
+                ids.sort();
+                assert_eq!(ids, vec!["2", "3"]);
+            }
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+    }
+
+    #[test]
+    fn residency_budget() {
+        let model: DefaultModel<TestMsg> = DefaultModel::new();
+        let system = ActorSystem::new(&model).unwrap();
+
+        let budget = ResidencyBudget::new(2);
+        let mut conf = EntityActorConfig::from(&system.config());
+        conf.residency_budget = Some(budget.clone());
+
+        let checking = Entity::new(&system,
+                                    BankAccountActorFact,
+                                    "Checking",
+                                    Some(conf.clone())).unwrap();
+        let savings = Entity::new(&system,
+                                    BankAccountActorFact,
+                                    "Savings",
+                                    Some(conf)).unwrap();
+        let (probe, listen) = probe_actor(&system, "probe");
+
+        checking.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Anna Volovodov".into())), Some(probe.clone()));
+        savings.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Anna Volovodov".into())), Some(probe.clone()));
+        replied(&listen);
+        replied(&listen);
+
+        // waits for a token
+        checking.tell(CQMsg::Cmd("2".into(), TestMsg::CreateAccountCmd("Nono Volovodov".into())), None);
+        checking.tell(ControlMsg::Stats, Some(probe.clone()));
+        assert_eq!(stats(&listen).instances, 1);
+        assert_eq!(budget.used(), 2);
+
+        // passivating in one manager wakes the waiting instance in the other
+        savings.tell(ControlMsg::Passivate("1".into()), None);
+        assert!(eventually(|| {
+            checking.tell(ControlMsg::Stats, Some(probe.clone()));
+            stats(&listen).instances == 2
+        }));
+        assert_eq!(budget.used(), 2);
+    }
+
+    #[test]
+    fn residency_budget_groups() {
+        let model: DefaultModel<TestMsg> = DefaultModel::new();
+        let system = ActorSystem::new(&model).unwrap();
+
+        let budget = ResidencyBudget::new(2);
+        let mut conf = EntityActorConfig::from(&system.config());
+        conf.residency_budget = Some(budget.clone());
+
+        let tycho = EntityGroup::new(&system, "Tycho").unwrap();
+        let checking = tycho.entity(BankAccountActorFact, "Checking", Some(conf.clone())).unwrap();
+        let ceres = EntityGroup::new(&system, "Ceres").unwrap();
+        let other = ceres.entity(BankAccountActorFact, "Checking", Some(conf)).unwrap();
+        let (probe, listen) = probe_actor(&system, "probe");
+
+        checking.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Fred Johnson".into())), Some(probe.clone()));
+        replied(&listen);
+        other.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Fred Johnson".into())), Some(probe.clone()));
+        replied(&listen);
+        assert_eq!(budget.used(), 2);
+
+        // a restarted manager returns only its own tokens, although
+        // the other manager has the same name
+        other.sys_tell(SystemMsg::ActorCmd(ActorCmd::Restart), None);
+        other.tell(ControlMsg::Stats, Some(probe));
+        assert_eq!(stats(&listen).instances, 0);
+        assert_eq!(budget.used(), 1);
+    }
+
+    #[derive(Clone)]
+    struct VersionedActorFact(u32);
+
+    impl EntityActorProps for VersionedActorFact {
+        type Msg = TestMsg;
+
+        fn props(&self, id: String, persistence: PersistenceConf) -> BoxActorProd<Self::Msg> {
+            Props::new_args(Box::new(BankAccountActor::new), (id, persistence))
+        }
+
+        fn index_keys(&self, _id: &str) -> Vec<String> {
+            vec![format!("v{}", self.0)]
+        }
+    }
+
+    #[test]
+    fn replace_props() {
+        let props = Arc::new(Mutex::new(VersionedActorFact(1)));
+        let (_, em, probe, listen) = manager(props.clone(), "BankAccount", |_| {});
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Elio Casimir".into())), None);
+        em.tell(ControlMsg::Stats, Some(probe.clone()));
+        stats(&listen);
+
+        // only instances woken from now on use the new props
+        *props.lock().unwrap() = VersionedActorFact(2);
+        em.tell(CQMsg::Cmd("2".into(), TestMsg::CreateAccountCmd("Carol Chiwewe".into())), None);
+
+        for (version, id) in [("v1", "1"), ("v2", "2")].iter() {
+            em.tell(ControlMsg::FindByIndex(version.to_string()), Some(probe.clone()));
+            match listen.recv() {
+                ControlMsg::FindByIndexResult(_, ids) => assert_eq!(ids, vec![id.to_string()]),
+                msg => panic!("Unexpected reply {:?}", msg)
+            }
+        }
+    }
+
+    #[test]
+    fn report_cold_starts() {
+        let (_, em, probe, listen) = manager(BankAccountActorFact, "BankAccount", |conf| {
+            conf.report_cold_starts = true;
+        });
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Filip Inaros".into())), Some(probe.clone()));
+        match listen.recv() {
+            ControlMsg::ColdStart(id) => assert_eq!(id, "1"),
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+        replied(&listen);
+
+        // the instance is resident
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(100)), Some(probe));
+        replied(&listen);
+    }
+
+    #[test]
+    fn state_cache() {
+        let (_, em, probe, listen) = manager(BankAccountActorFact, "BankAccount", |conf| {
+            conf.state_cache = Some(StateCache {
+                capacity: 10,
+                ttl: time::Duration::from_secs(60)
+            });
+        });
+
+        let balance = |listen: &dyn ProbeReceive<Msg=ControlMsg<TestMsg>>| {
+            match listen.recv() {
+                ControlMsg::State(_, Some(state)) => match *state {
+                    TestMsg::AccountState(account) => account.balance,
+                    state => panic!("Unexpected state {:?}", state)
+                },
+                msg => panic!("Unexpected reply {:?}", msg)
+            }
+        };
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Nancy Gao".into())), None);
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(100)), None);
+        em.tell(ControlMsg::GetState("1".into()), Some(probe.clone()));
+        assert_eq!(balance(&listen), 100);
+
+        // served from the cache without waking the instance
+        em.tell(ControlMsg::Passivate("1".into()), None);
+        em.tell(ControlMsg::GetState("1".into()), Some(probe.clone()));
+        assert_eq!(balance(&listen), 100);
+        em.tell(ControlMsg::Stats, Some(probe.clone()));
+        assert_eq!(stats(&listen).instances, 0);
+
+        // a command invalidates the cached state
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(5)), Some(probe.clone()));
+        replied(&listen);
+        em.tell(ControlMsg::Passivate("1".into()), None);
+        em.tell(ControlMsg::GetState("1".into()), Some(probe));
+        assert_eq!(balance(&listen), 105);
+    }
+
+    // Fails on every command
+    struct FailingActor;
+
+    impl Actor for FailingActor {
+        type Msg = TestMsg;
+
+        fn receive(&mut self, _: &Context<TestMsg>, msg: TestMsg, _: Option<ActorRef<TestMsg>>) {
+            panic!("Failed to handle {:?}", msg);
+        }
+    }
+
+    #[derive(Clone)]
+    struct FailingActorFact;
+
+    impl EntityActorProps for FailingActorFact {
+        type Msg = TestMsg;
+
+        fn props(&self, _: String, _: PersistenceConf) -> BoxActorProd<Self::Msg> {
+            Props::new(Box::new(|| -> BoxActor<TestMsg> { Box::new(FailingActor) }))
+        }
+
+        fn supervisor_strategy(&self) -> Strategy {
+            Strategy::Stop
+        }
+    }
+
+    #[test]
+    fn supervisor_strategy() {
+        let (_, em, probe, listen) = manager(FailingActorFact, "Failing", |_| {});
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(1)), None);
+
+        // the stopped instance is removed, and woken again by the next command
+        assert!(eventually(|| {
+            em.tell(ControlMsg::Stats, Some(probe.clone()));
+            stats(&listen).instances == 0
+        }));
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(1)), None);
+        em.tell(ControlMsg::Stats, Some(probe));
+        let stats = stats(&listen);
+        assert_eq!(stats.wakes, 2);
+        assert_eq!(stats.passivations, 0);
+    }
+
+    #[test]
+    fn migrate_keyspace() {
+        let (system, em, probe, listen) = manager(BankAccountActorFact, "BankAccount", |_| {});
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Fred Johnson".into())), None);
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(20)), Some(probe.clone()));
+        replied(&listen);
+
+        // the manager must be stopped first
+        let ids = vec!["1".to_string(), "2".to_string()];
+        assert!(Entity::migrate_keyspace(&system, "BankAccount", "Ledger", &ids).is_err());
+
+        Entity::stop(&system, "BankAccount");
+        assert_eq!(Entity::migrate_keyspace(&system, "BankAccount", "Ledger", &ids), Ok(2));
+
+        // already copied
+        assert_eq!(Entity::migrate_keyspace(&system, "BankAccount", "Ledger", &ids), Ok(2));
+
+        let em = Entity::new(&system, BankAccountActorFact, "Ledger", None).unwrap();
+        em.tell(ControlMsg::GetState("1".into()), Some(probe));
+        match listen.recv() {
+            ControlMsg::State(_, Some(state)) => match *state {
+                TestMsg::AccountState(account) => assert_eq!(account.balance, 20),
+                state => panic!("Unexpected state {:?}", state)
+            },
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+    }
+
+    // Never replies to commands
+    struct SilentActor;
+
+    impl Actor for SilentActor {
+        type Msg = TestMsg;
+
+        fn receive(&mut self, _: &Context<TestMsg>, _: TestMsg, _: Option<ActorRef<TestMsg>>) {}
+    }
+
+    #[derive(Clone)]
+    struct SilentActorFact;
+
+    impl EntityActorProps for SilentActorFact {
+        type Msg = TestMsg;
+
+        fn props(&self, _: String, _: PersistenceConf) -> BoxActorProd<Self::Msg> {
+            Props::new(Box::new(|| -> BoxActor<TestMsg> { Box::new(SilentActor) }))
+        }
+    }
+
+    #[test]
+    fn max_in_flight_per_id() {
+        let model: DefaultModel<TestMsg> = DefaultModel::new();
+        let system = ActorSystem::new(&model).unwrap();
+
+        let mut conf = EntityActorConfig::from(&system.config());
+        conf.max_in_flight_per_id = Some(2);
+
+        let em = Entity::new(&system,
+                            SilentActorFact,
+                            "Silent",
+                            Some(conf.clone())).unwrap();
+        let (probe, listen) = probe_actor(&system, "probe");
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(1)), Some(probe.clone()));
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(2)), Some(probe.clone()));
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(3)), Some(probe.clone()));
+        match listen.recv() {
+            ControlMsg::CommandRejected(id, reason) => {
+                assert_eq!(id, "1");
+                assert_eq!(reason, RejectReason::Busy);
+            }
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+
+        // other IDs have their own window
+        em.tell(CQMsg::Cmd("2".into(), TestMsg::AddAmountCmd(1)), Some(probe.clone()));
+
+        // the window is dropped on passivation
+        em.tell(ControlMsg::Passivate("1".into()), None);
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(4)), Some(probe.clone()));
+        em.tell(ControlMsg::Stats, Some(probe.clone()));
+        stats(&listen);
+
+        // replies free the window
+        conf.max_in_flight_per_id = Some(1);
+        let em = Entity::new(&system,
+                            BankAccountActorFact,
+                            "BankAccount",
+                            Some(conf)).unwrap();
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Marco Inaros".into())), Some(probe.clone()));
+        replied(&listen);
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(10)), Some(probe));
+        replied(&listen);
+    }
+
+    #[test]
+    fn catch_up() {
+        let (system, em, probe, listen) = manager(BankAccountActorFact, "BankAccount", |_| {});
+        let (projection, projection_listen) = probe_actor(&system, "projection");
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Naomi Nagata".into())), None);
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(10)), Some(probe.clone()));
+        replied(&listen);
+
+        // the command is held until the projection has caught up
+        em.tell(ControlMsg::CatchUp("1".into(), projection), None);
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(5)), Some(probe));
+
+        let mut events = Vec::new();
+        loop {
+            match projection_listen.recv() {
+                ControlMsg::Event(id, evt) => {
+                    assert_eq!(id, "1");
+                    events.push(*evt);
+                }
+                ControlMsg::CaughtUp(id) => {
+                    assert_eq!(id, "1");
+                    break;
+                }
+                msg => panic!("Unexpected message {:?}", msg)
+            }
+        }
+        assert_eq!(events.len(), 2);
+        match events[0] {
+            TestMsg::AccountCreatedEvt(_) => {}
+            ref evt => panic!("Unexpected event {:?}", evt)
+        }
+        match events[1] {
+            TestMsg::AmountAddedEvt(10) => {}
+            ref evt => panic!("Unexpected event {:?}", evt)
+        }
+
+        match projection_listen.recv() {
+            ControlMsg::Event(_, evt) => match *evt {
+                TestMsg::AmountAddedEvt(5) => {}
+                evt => panic!("Unexpected event {:?}", evt)
+            },
+            msg => panic!("Unexpected message {:?}", msg)
+        }
+    }
+
+    #[test]
+    fn command_timeout() {
+        let (_, em, probe, listen) = manager(SilentActorFact, "Silent", |conf| {
+            conf.command_timeout = Some(time::Duration::from_millis(100));
+            conf.reject_timed_out_cmds = true;
+            conf.restart_on_command_timeout = true;
+            conf.max_in_flight_per_id = Some(1);
+        });
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(1)), Some(probe.clone()));
+        match listen.recv() {
+            ControlMsg::CommandRejected(id, reason) => {
+                assert_eq!(id, "1");
+                assert_eq!(reason, RejectReason::TimedOut);
+            }
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+
+        // the restarted instance no longer counts the command as in flight
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(2)), Some(probe));
+        match listen.recv() {
+            ControlMsg::CommandRejected(_, reason) => assert_eq!(reason, RejectReason::TimedOut),
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+    }
+
+    #[test]
+    fn on_passivation_decision() {
+        let decisions = Arc::new(Mutex::new(Vec::new()));
+        let recorded = decisions.clone();
+
+        let (_, em, probe, listen) = manager(BankAccountActorFact, "BankAccount", |conf| {
+            conf.sleep_after_secs = 1;
+            conf.on_passivation_decision = Some(PassivationCallback::new(move |id, idle, passivate| {
+                recorded.lock().unwrap().push((id.to_string(), idle, passivate));
+            }));
+        });
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Julie Mao".into())), None);
+        em.tell(CQMsg::Cmd("2".into(), TestMsg::CreateAccountCmd("Jules-Pierre Mao".into())), None);
+        // both idle for longer than `sleep_after_secs`
+        thread::sleep(time::Duration::from_millis(1100));
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(100)), None);
+        em.tell(ActorMsg::Tick, None);
+        em.tell(ControlMsg::Stats, Some(probe));
+        stats(&listen);
+
+        let mut decisions = decisions.lock().unwrap().clone();
+        decisions.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(decisions.len(), 2);
+        assert_eq!((decisions[0].0.as_str(), decisions[0].2), ("1", false));
+        assert_eq!((decisions[1].0.as_str(), decisions[1].2), ("2", true));
+        assert!(decisions[1].1 > time::Duration::from_secs(1));
+    }
+
+    #[test]
+    fn pause() {
+        let (_, em, probe, listen) = manager(BankAccountActorFact, "BankAccount", |conf| {
+            conf.max_paused_cmds = 2;
+        });
+
+        em.tell(ControlMsg::Pause, None);
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Alex Kamal".into())), Some(probe.clone()));
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(10)), Some(probe.clone()));
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(20)), Some(probe.clone()));
+
+        // over the bound
+        match listen.recv() {
+            ControlMsg::CommandRejected(_, reason) => assert_eq!(reason, RejectReason::Busy),
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+
+        // nothing is routed while paused
+        em.tell(ControlMsg::Stats, Some(probe.clone()));
+        assert_eq!(stats(&listen).instances, 0);
+
+        em.tell(ControlMsg::Resume, None);
+        replied(&listen);
+        replied(&listen);
+
+        em.tell(ControlMsg::GetState("1".into()), Some(probe));
+        match listen.recv() {
+            ControlMsg::State(_, Some(state)) => match *state {
+                TestMsg::AccountState(account) => assert_eq!(account.balance, 10),
+                state => panic!("Unexpected state {:?}", state)
+            },
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+    }
+
+    #[test]
+    fn child_name() {
+        let model: DefaultModel<TestMsg> = DefaultModel::new();
+        let system = ActorSystem::new(&model).unwrap();
+
+        let mut conf = EntityActorConfig::from(&system.config());
+        conf.child_name = Some("{entity}.{id}".into());
+        assert!(Entity::new(&system, BankAccountActorFact, "Invalid", Some(conf.clone())).is_err());
+
+        conf.child_name = Some("{entity}-{id}".into());
+        let em = Entity::new(&system,
+                            BankAccountActorFact,
+                            "BankAccount",
+                            Some(conf)).unwrap();
+        let (probe, listen) = probe_actor(&system, "probe");
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Monica Stuart".into())), Some(probe.clone()));
+        replied(&listen);
+
+        let names: Vec<String> = em.children().map(|child| child.name().to_string()).collect();
+        assert_eq!(names, vec!["BankAccount-1"]);
+
+        // IDs that can't name an actor aren't woken
+        em.tell(CQMsg::Cmd("1/2".into(), TestMsg::CreateAccountCmd("Monica Stuart".into())), Some(probe.clone()));
+        match listen.recv() {
+            ControlMsg::CommandRejected(id, RejectReason::InvalidId) => assert_eq!(id, "1/2"),
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+
+        // instances are still addressed by their ID
+        em.tell(ControlMsg::DumpInstances, Some(probe));
+        match listen.recv() {
+            ControlMsg::DumpInstancesResult(instances) => assert_eq!(instances[0].id, "1"),
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+    }
+
+    #[test]
+    fn preload_batch() {
+        let (_, em, probe, listen) = manager(BankAccountActorFact, "BankAccount", |_| {});
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Sadavir Errinwright".into())), None);
+        em.tell(ControlMsg::PreloadBatch(vec!["1".into(), "2".into(), "3".into()]), Some(probe.clone()));
+        match listen.recv() {
+            ControlMsg::PreloadResult(woken, resident) => {
+                assert_eq!(woken, 2);
+                assert_eq!(resident, 1);
+            }
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+
+        em.tell(ControlMsg::Stats, Some(probe));
+        let stats = stats(&listen);
+        assert_eq!(stats.instances, 3);
+        assert_eq!(stats.wakes, 3);
+    }
+
+    // Replies to commands without a sender go to the auditor
+    #[derive(Clone)]
+    struct AuditedActorFact {
+        auditor: ActorRef<TestMsg>,
+    }
+
+    impl EntityActorProps for AuditedActorFact {
+        type Msg = TestMsg;
+
+        fn props(&self, id: String, persistence: PersistenceConf) -> BoxActorProd<Self::Msg> {
+            BankAccountActorFact.props(id, persistence)
+        }
+
+        fn default_sender(&self) -> Option<ActorRef<TestMsg>> {
+            Some(self.auditor.clone())
+        }
+    }
+
+    #[test]
+    fn default_sender() {
+        let model: DefaultModel<TestMsg> = DefaultModel::new();
+        let system = ActorSystem::new(&model).unwrap();
+
+        let (auditor, audit) = probe_actor(&system, "auditor");
+        let (probe, listen) = probe_actor(&system, "probe");
+        let em = Entity::new(&system,
+                            AuditedActorFact { auditor },
+                            "BankAccount",
+                            None).unwrap();
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Cortazar".into())), None);
+        replied(&audit);
+
+        // an explicit sender takes precedence
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(10)), Some(probe));
+        replied(&listen);
+
+        // and so do rejections
+        em.tell(ControlMsg::CmdExisting("2".into(), Box::new(TestMsg::AddAmountCmd(10))), None);
+        match audit.recv() {
+            ControlMsg::CommandRejected(id, RejectReason::NotFound) => assert_eq!(id, "2"),
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+    }
+
+    #[test]
+    fn persist_latency() {
+        let (_, em, probe, listen) = manager(BankAccountActorFact, "BankAccount", |_| {});
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Praxidike Meng".into())), Some(probe.clone()));
+        replied(&listen);
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(10)), Some(probe.clone()));
+        replied(&listen);
+
+        em.tell(ControlMsg::Stats, Some(probe));
+        let stats = stats(&listen);
+        assert_eq!(stats.persist_latency.count, 2);
+        assert!(stats.persist_latency.max >= stats.persist_latency.min);
+    }
+
+    #[test]
+    fn from_fn() {
+        let model: DefaultModel<TestMsg> = DefaultModel::new();
+        let system = ActorSystem::new(&model).unwrap();
+
+        let em = Entity::from_fn(&system, "BankAccount", None, |id| {
+            let persistence = Entity::persistence_conf("BankAccount", &id);
+            Props::new_args(Box::new(BankAccountActor::new), (id, persistence))
+        }).unwrap();
+        let (probe, listen) = probe_actor(&system, "probe");
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Katoa Merton".into())), Some(probe));
+        replied(&listen);
+    }
+
+    #[test]
+    fn read_only() {
+        let (_, em, probe, listen) = manager(BankAccountActorFact, "BankAccount", |_| {});
+        let rejected = |listen: &dyn ProbeReceive<Msg=ControlMsg<TestMsg>>| {
+            match listen.recv() {
+                ControlMsg::CommandRejected(_, reason) => assert_eq!(reason, RejectReason::ReadOnly),
+                msg => panic!("Unexpected reply {:?}", msg)
+            }
+        };
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Carlos de Baca".into())), Some(probe.clone()));
+        replied(&listen);
+
+        em.tell(ControlMsg::ReadOnly(true), None);
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(5)), Some(probe.clone()));
+        rejected(&listen);
+
+        // running instances still answer queries, others aren't woken
+        em.tell(ControlMsg::GetState("1".into()), Some(probe.clone()));
+        match listen.recv() {
+            ControlMsg::State(_, Some(_)) => {}
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+        em.tell(ControlMsg::GetState("2".into()), Some(probe.clone()));
+        rejected(&listen);
+
+        em.tell(ControlMsg::ReadOnly(false), None);
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(5)), Some(probe));
+        replied(&listen);
+    }
+
+    #[test]
+    fn max_passivations_per_tick() {
+        let (_, em, probe, listen) = manager(BankAccountActorFact, "BankAccount", |conf| {
+            conf.sleep_after_secs = 0;
+            conf.max_passivations_per_tick = Some(2);
+        });
+
+        for (id, name) in vec![("1", "Filip Inaros"), ("2", "Cyn"), ("3", "Karal")] {
+            em.tell(CQMsg::Cmd(id.into(), TestMsg::CreateAccountCmd(name.into())), Some(probe.clone()));
+            replied(&listen);
+        }
+
+        // the two longest idle are passivated first
+        em.tell(ActorMsg::Tick, None);
+        em.tell(ControlMsg::DumpInstances, Some(probe.clone()));
+        match listen.recv() {
+            ControlMsg::DumpInstancesResult(instances) => {
+                let ids: Vec<String> = instances.into_iter().map(|i| i.id).collect();
+                assert_eq!(ids, vec!["3"]);
+            }
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+
+        em.tell(ActorMsg::Tick, None);
+        em.tell(ControlMsg::Stats, Some(probe));
+        assert_eq!(stats(&listen).instances, 0);
+    }
+
+    #[test]
+    fn audit_consistency() {
+        let model: DefaultModel<TestMsg> = DefaultModel::new();
+        let system = ActorSystem::new(&model).unwrap();
+
+        let mut em = TestEntity::new(&system,
+                                    BankAccountActorFact,
+                                    "BankAccount",
+                                    None).unwrap();
+
+        em.cmd("1", TestMsg::CreateAccountCmd("Amos Burton".into()));
+        em.cmd("2", TestMsg::CreateAccountCmd("Clarissa Mao".into()));
+        assert!(em.audit().is_consistent());
+
+        em.entity().tell(ControlMsg::Passivate("1".into()), None);
+        em.entity().tell(ControlMsg::Clear, None);
+        assert!(eventually(|| em.audit().is_consistent()));
+    }
+
+    // Refuses to take money out of accounts
+    struct NoWithdrawals;
+
+    impl CommandMiddleware<TestMsg> for NoWithdrawals {
+        fn handle(&mut self, _: &Context<TestMsg>, _: &str, cmd: TestMsg) -> MiddlewareResult<TestMsg> {
+            match cmd {
+                TestMsg::AddAmountCmd(amount) if amount < 0 => MiddlewareResult::Reject(RejectReason::Refused),
+                cmd => MiddlewareResult::Continue(cmd)
+            }
+        }
+    }
+
+    #[derive(Clone)]
+    struct CheckedActorFact;
+
+    impl EntityActorProps for CheckedActorFact {
+        type Msg = TestMsg;
+
+        fn props(&self, id: String, persistence: PersistenceConf) -> BoxActorProd<Self::Msg> {
+            BankAccountActorFact.props(id, persistence)
+        }
+
+        fn middleware(&self) -> Vec<Box<dyn CommandMiddleware<TestMsg>>> {
+            let accounts = DedupMiddleware::new(100, |id: &str, cmd: &TestMsg| match cmd {
+                TestMsg::CreateAccountCmd(_) => Some(id.to_string()),
+                _ => None
+            });
+            vec![Box::new(accounts), Box::new(NoWithdrawals)]
+        }
+    }
+
+    #[test]
+    fn middleware() {
+        let (_, em, probe, listen) = manager(CheckedActorFact, "BankAccount", |_| {});
+        let rejected = |listen: &dyn ProbeReceive<Msg=ControlMsg<TestMsg>>| {
+            match listen.recv() {
+                ControlMsg::CommandRejected(_, reason) => reason,
+                msg => panic!("Unexpected reply {:?}", msg)
+            }
+        };
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Anna Volovodov".into())), Some(probe.clone()));
+        replied(&listen);
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Anna Volovodov".into())), Some(probe.clone()));
+        assert_eq!(rejected(&listen), RejectReason::Duplicate);
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(-10)), Some(probe.clone()));
+        assert_eq!(rejected(&listen), RejectReason::Refused);
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(10)), Some(probe));
+        replied(&listen);
+    }
+
+    // Account IDs are case insensitive
+    #[derive(Clone)]
+    struct CaseInsensitiveActorFact;
+
+    impl EntityActorProps for CaseInsensitiveActorFact {
+        type Msg = TestMsg;
+
+        fn props(&self, id: String, persistence: PersistenceConf) -> BoxActorProd<Self::Msg> {
+            BankAccountActorFact.props(id, persistence)
+        }
+
+        fn normalize(&self, id: &str) -> String {
+            id.to_lowercase()
+        }
+    }
+
+    #[test]
+    fn normalized_ids() {
+        let (_, em, probe, listen) = manager(CaseInsensitiveActorFact, "BankAccount", |_| {});
+
+        em.tell(CQMsg::Cmd("ACC-1".into(), TestMsg::CreateAccountCmd("Clarissa Mao".into())), Some(probe.clone()));
+        replied(&listen);
+        em.tell(CQMsg::Cmd("acc-1".into(), TestMsg::AddAmountCmd(10)), Some(probe.clone()));
+        replied(&listen);
+
+        em.tell(ControlMsg::DumpInstances, Some(probe));
+        match listen.recv() {
+            ControlMsg::DumpInstancesResult(instances) => {
+                assert_eq!(instances.len(), 1);
+                assert_eq!(instances[0].id, "acc-1");
+            }
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+    }
+
+    #[test]
+    fn fail_fast() {
+        let (_, em, probe, listen) = manager(WakingActorFact, "Waking", |conf| {
+            conf.max_concurrent_wakes = Some(1);
+            conf.fail_fast.capacity = true;
+            conf.fail_fast.waking = true;
+        });
+
+        // "1" is waking, so neither it nor "2" can take commands
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(1)), None);
+        em.tell(CQMsg::Cmd("2".into(), TestMsg::AddAmountCmd(1)), Some(probe.clone()));
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(1)), Some(probe));
+        for expected in &["2", "1"] {
+            match listen.recv() {
+                ControlMsg::CommandRejected(id, reason) => {
+                    assert_eq!(id, *expected);
+                    assert_eq!(reason, RejectReason::Overloaded);
+                }
+                msg => panic!("Unexpected reply {:?}", msg)
+            }
+        }
+    }
+
+    #[test]
+    fn cmd_expecting() {
+        let (_, em, probe, listen) = manager(BankAccountActorFact, "BankAccount", |_| {});
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Julie Mao".into())), Some(probe.clone()));
+        replied(&listen);
+
+        let cmd = ControlMsg::CmdExpecting("1".into(), 1, Box::new(TestMsg::AddAmountCmd(10)));
+        em.tell(cmd, Some(probe.clone()));
+        match replied(&listen) {
+            CommandResult::Accepted(_) => {}
+            result => panic!("Unexpected result {:?}", result)
+        }
+
+        // based on a stale read
+        let cmd = ControlMsg::CmdExpecting("1".into(), 1, Box::new(TestMsg::AddAmountCmd(10)));
+        em.tell(cmd, Some(probe));
+        match listen.recv() {
+            ControlMsg::CommandResult(_, CommandResult::VersionConflict(expected, current)) => {
+                assert_eq!(expected, 1);
+                assert_eq!(current, 2);
+            }
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+    }
+
+    #[test]
+    fn heartbeat() {
+        let (_, em, probe, listen) = manager(BankAccountActorFact, "BankAccount", |conf| {
+            conf.emit_heartbeat = Some(time::Duration::from_millis(50));
+        });
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Shed Garvey".into())), Some(probe.clone()));
+        replied(&listen);
+        em.tell(ControlMsg::Subscribe(None, probe), None);
+
+        match listen.recv() {
+            ControlMsg::Heartbeat(heartbeat) => {
+                assert_eq!(heartbeat.name, "BankAccount");
+                assert_eq!(heartbeat.instances, 1);
+            }
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+    }
+
+    #[test]
+    fn publish_batch_window() {
+        let (_, em, projection, listen) = manager(BankAccountActorFact, "BankAccount", |conf| {
+            conf.publish_batch_window = Some(time::Duration::from_millis(100));
+        });
+        em.tell(ControlMsg::Subscribe(None, projection), None);
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Marco Inaros".into())), None);
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(10)), None);
+        em.tell(CQMsg::Cmd("2".into(), TestMsg::CreateAccountCmd("Filip Inaros".into())), None);
+
+        match listen.recv() {
+            ControlMsg::EventBatch(events) => {
+                let ids: Vec<&str> = events.iter().map(|(id, _)| id.as_str()).collect();
+                assert_eq!(ids.len(), 3);
+                match events[0].1 {
+                    TestMsg::AccountCreatedEvt(_) => {}
+                    ref evt => panic!("Unexpected event {:?}", evt)
+                }
+                assert!(ids.contains(&"2"));
+            }
+            msg => panic!("Unexpected message {:?}", msg)
+        }
+    }
+
+    // polls the future until it completes, without an executor
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+        fn noop_raw() -> RawWaker {
+            fn clone(_: *const ()) -> RawWaker { noop_raw() }
+            fn noop(_: *const ()) {}
+            static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+            RawWaker::new(std::ptr::null(), &VTABLE)
+        }
+
+        let waker = unsafe { Waker::from_raw(noop_raw()) };
+        let mut cx = Context::from_waker(&waker);
+        let mut future = Box::pin(future);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn command_future() {
+        let model: DefaultModel<TestMsg> = DefaultModel::new();
+        let system = ActorSystem::new(&model).unwrap();
+
+        let em = Entity::new(&system,
+                            BankAccountActorFact,
+                            "BankAccount",
+                            None).unwrap();
+        let timeout = time::Duration::from_secs(1);
+
+        let cmd = TestMsg::CreateAccountCmd("Chrisjen Avasarala".into());
+        let result = block_on(Entity::command_future(&system, &em, "1", cmd, timeout));
+        match result {
+            Ok(CommandResult::Accepted(_)) => {}
+            result => panic!("Unexpected result {:?}", result)
+        }
+
+        let cmd = TestMsg::CreateAccountCmd("Errinwright".into());
+        let result = block_on(Entity::command_future(&system, &em, "1", cmd, timeout));
+        match result {
+            Ok(CommandResult::Rejected(_)) => {}
+            result => panic!("Unexpected result {:?}", result)
+        }
+
+        // instances that never reply
+        let silent = Entity::new(&system,
+                                WakingActorFact,
+                                "Waking",
+                                None).unwrap();
+        let cmd = TestMsg::AddAmountCmd(1);
+        let timeout = time::Duration::from_millis(100);
+        let result = block_on(Entity::command_future(&system, &silent, "1", cmd, timeout));
+        assert_eq!(result.unwrap_err(), EntityError::TimedOut);
+    }
+
+    #[test]
+    fn passivation_grace() {
+        let (_, em, probe, listen) = manager(BankAccountActorFact, "BankAccount", |conf| {
+            conf.passivation_grace = Some(time::Duration::from_millis(500));
+        });
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Karal".into())), Some(probe.clone()));
+        replied(&listen);
+
+        // held until the passivated instance has stopped, then woken again
+        em.tell(ControlMsg::Passivate("1".into()), None);
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(10)), Some(probe.clone()));
+        match replied(&listen) {
+            CommandResult::Accepted(_) => {}
+            result => panic!("Unexpected result {:?}", result)
+        }
+
+        em.tell(ControlMsg::Stats, Some(probe));
+        let stats = stats(&listen);
+        assert_eq!(stats.wakes, 2);
+        assert_eq!(stats.passivations, 1);
+    }
+
+    #[test]
+    fn hot_ids() {
+        let (_, em, probe, listen) = manager(BankAccountActorFact, "BankAccount", |_| {});
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Monica Stuart".into())), None);
+        em.tell(CQMsg::Cmd("2".into(), TestMsg::CreateAccountCmd("Okoye".into())), None);
+        em.tell(CQMsg::Cmd("2".into(), TestMsg::AddAmountCmd(10)), None);
+        em.tell(CQMsg::Cmd("2".into(), TestMsg::AddAmountCmd(10)), None);
+        em.tell(CQMsg::Cmd("3".into(), TestMsg::CreateAccountCmd("Havelock".into())), None);
+        em.tell(CQMsg::Cmd("3".into(), TestMsg::AddAmountCmd(10)), None);
+
+        em.tell(ControlMsg::HotIds(2), Some(probe));
+        match listen.recv() {
+            ControlMsg::HotIdsResult(counts) => {
+                assert_eq!(counts, vec![("2".to_string(), 3), ("3".to_string(), 2)]);
+            }
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+    }
+
+    #[test]
+    fn sweep_coordinator() {
+        let model: DefaultModel<TestMsg> = DefaultModel::new();
+        let system = ActorSystem::new(&model).unwrap();
+
+        let coordinator = SweepCoordinator::new(time::Duration::from_millis(100));
+        let mut conf = EntityActorConfig::from(&system.config());
+        conf.sweep_coordinator = Some(coordinator.clone());
+
+        let accounts = Entity::new(&system,
+                            BankAccountActorFact,
+                            "BankAccount",
+                            Some(conf.clone())).unwrap();
+        let silent = Entity::new(&system,
+                            SilentActorFact,
+                            "Silent",
+                            Some(conf)).unwrap();
+        let (probe, listen) = probe_actor(&system, "probe");
+        assert_eq!(coordinator.managers(), 2);
+
+        for em in [accounts, silent].iter() {
+            assert!(eventually(|| {
+                em.tell(ControlMsg::Stats, Some(probe.clone()));
+                stats(&listen).last_sweep_at.is_some()
+            }));
+        }
+
+        Entity::stop(&system, "Silent");
+        assert_eq!(coordinator.managers(), 1);
+    }
+
+    #[cfg(feature = "diagnostics")]
+    #[test]
+    fn dump_state() {
+        let (_, em, probe, listen) = manager(BankAccountActorFact, "BankAccount", |_| {});
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Dimitri Havelock".into())), None);
+        em.tell(CQMsg::Cmd("2".into(), TestMsg::CreateAccountCmd("Sematimba".into())), None);
+        em.tell(CQMsg::Cmd("3".into(), TestMsg::CreateAccountCmd("Lucia Mazur".into())), None);
+
+        em.tell(ControlMsg::DumpState(1, 1), Some(probe));
+        match listen.recv() {
+            ControlMsg::DumpStateResult(dump) => {
+                assert_eq!(dump.total, 3);
+                assert!(dump.unanswered.is_empty());
+                assert_eq!(dump.states.len(), 1);
+                assert_eq!(dump.states[0].0, "2");
+                match dump.states[0].1.as_ref().map(|state| &**state) {
+                    Some(TestMsg::AccountState(account)) => assert_eq!(account.name, "Sematimba"),
+                    state => panic!("Unexpected state {:?}", state)
+                }
+            }
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+    }
+
+    #[test]
+    fn create() {
+        let (_, em, probe, listen) = manager(BankAccountActorFact, "BankAccount", |_| {});
+
+        // racing creates, only the first is routed
+        let cmd = Box::new(TestMsg::CreateAccountCmd("Anna Volovodov".into()));
+        em.tell(ControlMsg::Create("1".into(), cmd.clone()), Some(probe.clone()));
+        em.tell(ControlMsg::Create("1".into(), cmd.clone()), Some(probe.clone()));
+        let (mut accepted, mut rejected) = (0, 0);
+        for _ in 0..2 {
+            match listen.recv() {
+                ControlMsg::CommandResult(_, CommandResult::Accepted(_)) => accepted += 1,
+                ControlMsg::CommandRejected(_, RejectReason::AlreadyExists) => rejected += 1,
+                msg => panic!("Unexpected reply {:?}", msg)
+            }
+        }
+        assert_eq!((accepted, rejected), (1, 1));
+
+        // persisted, but asleep
+        em.tell(ControlMsg::Passivate("1".into()), None);
+        em.tell(ControlMsg::Create("1".into(), cmd), Some(probe));
+        match listen.recv() {
+            ControlMsg::CommandRejected(id, reason) => {
+                assert_eq!(id, "1");
+                assert_eq!(reason, RejectReason::AlreadyExists);
+            }
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+    }
+
+    #[test]
+    fn in_flight_per_instance() {
+        let (_, em, probe, listen) = manager(SilentActorFact, "Silent", |_| {});
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(1)), None);
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(1)), None);
+        em.tell(ControlMsg::DumpInstances, Some(probe));
+        match listen.recv() {
+            ControlMsg::DumpInstancesResult(instances) => {
+                assert_eq!(instances[0].in_flight, 2);
+                assert!(instances[0].oldest_in_flight.is_some());
+            }
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+    }
+
+    // Blocks on every command, e.g. on I/O that never completes,
+    // counting the commands it has started on
+    struct StuckActor(Arc<AtomicUsize>);
+
+    impl Actor for StuckActor {
+        type Msg = TestMsg;
+
+        fn receive(&mut self, _: &Context<TestMsg>, _: TestMsg, _: Option<ActorRef<TestMsg>>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            thread::sleep(time::Duration::from_secs(2));
+        }
+    }
+
+    #[test]
+    fn drain_timeout() {
+        let model: DefaultModel<TestMsg> = DefaultModel::new();
+        let system = ActorSystem::new(&model).unwrap();
+
+        let mut conf = EntityActorConfig::from(&system.config());
+        conf.drain_timeout = Some(time::Duration::from_millis(200));
+
+        let handling = Arc::new(AtomicUsize::new(0));
+        let counter = handling.clone();
+        let em = Entity::from_fn(&system, "Stuck", Some(conf), move |_| {
+            let counter = counter.clone();
+            Props::new(Box::new(move || -> BoxActor<TestMsg> { Box::new(StuckActor(counter.clone())) }))
+        }).unwrap();
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(1)), None);
+        assert!(eventually(|| handling.load(Ordering::SeqCst) == 1));
+
+        // the queued command isn't handled once the instance is force stopped
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(2)), None);
+
+        let started = time::Instant::now();
+        Entity::stop(&system, "Stuck");
+        assert!(started.elapsed() < time::Duration::from_secs(1));
+
+        // the manager stops once the instance's handler returns
+        assert!(eventually(|| Entity::registry(&system).is_empty()));
+        assert!(started.elapsed() < time::Duration::from_secs(3));
+    }
+
+    #[test]
+    fn trace_commands() {
+        let (_, em, probe, listen) = manager(BankAccountActorFact, "BankAccount", |conf| {
+            conf.trace_commands = true;
+        });
+
+        // traced commands are routed and replied to as usual,
+        // whether the instance is woken or running
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Clarissa Mao".into())), Some(probe.clone()));
+        replied(&listen);
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(100)), Some(probe.clone()));
+        replied(&listen);
+
+        em.tell(ControlMsg::DumpInstances, Some(probe));
+        match listen.recv() {
+            ControlMsg::DumpInstancesResult(instances) => assert_eq!(instances[0].in_flight, 0),
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+    }
+
+    // Replays for as long as its faults delay it before becoming ready,
+    // and never replies
+    struct SlowActor {
+        id: String,
+        faults: FaultInjector,
+    }
+
+    impl SlowActor {
+        fn actor((id, faults): (String, FaultInjector)) -> BoxActor<TestMsg> {
+            Box::new(SlowActor { id, faults })
+        }
+    }
+
+    impl Actor for SlowActor {
+        type Msg = TestMsg;
+
+        // as if halfway through replaying its events while delayed
+        fn post_start(&mut self, ctx: &Context<TestMsg>) {
+            Entity::replay_progress(ctx, &self.id, 5, Some(10));
+            self.faults.delay_replay(&self.id);
+            Entity::ready(ctx, &self.id);
+        }
+
+        fn receive(&mut self, _: &Context<TestMsg>, _: TestMsg, _: Option<ActorRef<TestMsg>>) {}
+    }
+
+    #[derive(Clone)]
+    struct SlowActorFact(FaultInjector);
+
+    impl EntityActorProps for SlowActorFact {
+        type Msg = TestMsg;
+
+        fn props(&self, id: String, _: PersistenceConf) -> BoxActorProd<Self::Msg> {
+            Props::new_args(Box::new(SlowActor::actor), (id, self.0.clone()))
+        }
+    }
+
+    #[test]
+    fn init_timeout() {
+        let faults = FaultInjector::new();
+        faults.inject(Some("1"), Fault::DelayReplay(time::Duration::from_millis(500)));
+
+        let (_, em, probe, listen) = manager(SlowActorFact(faults), "Slow", |conf| {
+            conf.init_timeout = Some(time::Duration::from_millis(100));
+        });
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(1)), Some(probe.clone()));
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(2)), Some(probe.clone()));
+        for _ in 0..2 {
+            match listen.recv() {
+                ControlMsg::CommandRejected(id, reason) => {
+                    assert_eq!(id, "1");
+                    assert_eq!(reason, RejectReason::InitTimeout);
+                }
+                msg => panic!("Unexpected reply {:?}", msg)
+            }
+        }
+
+        em.tell(ControlMsg::Stats, Some(probe.clone()));
+        assert_eq!(stats(&listen).instances, 0);
+
+        // instances that are ready in time are unaffected, once the
+        // init timeout has passed
+        em.tell(CQMsg::Cmd("2".into(), TestMsg::AddAmountCmd(1)), None);
+        thread::sleep(time::Duration::from_millis(200));
+        em.tell(ControlMsg::Stats, Some(probe));
+        assert_eq!(stats(&listen).instances, 1);
+    }
+
+    #[test]
+    fn get_or_create() {
+        let (_, em, probe, listen) = manager(BankAccountActorFact, "BankAccount", |_| {});
+
+        // created, then its state
+        let cmd = Box::new(TestMsg::CreateAccountCmd("Elvi Okoye".into()));
+        em.tell(ControlMsg::GetOrCreate("1".into(), cmd.clone()), Some(probe.clone()));
+        match replied(&listen) {
+            CommandResult::Accepted(_) => {}
+            result => panic!("Unexpected result {:?}", result)
+        }
+        match listen.recv() {
+            ControlMsg::State(id, Some(state)) => {
+                assert_eq!(id, "1");
+                match *state {
+                    TestMsg::AccountState(account) => assert_eq!(account.name, "Elvi Okoye"),
+                    state => panic!("Unexpected state {:?}", state)
+                }
+            }
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+
+        // persisted, but asleep, so only its state
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(100)), Some(probe.clone()));
+        replied(&listen);
+        em.tell(ControlMsg::Passivate("1".into()), None);
+        em.tell(ControlMsg::GetOrCreate("1".into(), cmd), Some(probe));
+        match listen.recv() {
+            ControlMsg::State(_, Some(state)) => {
+                match *state {
+                    TestMsg::AccountState(account) => assert_eq!(account.balance, 100),
+                    state => panic!("Unexpected state {:?}", state)
+                }
+            }
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+    }
+
+    struct AuditLog(Mutex<Vec<AuditEntry>>);
+
+    impl AuditSink for AuditLog {
+        fn record(&self, entry: AuditEntry) {
+            self.0.lock().unwrap().push(entry);
+        }
+    }
+
+    #[test]
+    fn audit_sink() {
+        let log = Arc::new(AuditLog(Mutex::new(Vec::new())));
+        let (_, em, probe, listen) = manager(BankAccountActorFact, "BankAccount", |conf| {
+            conf.audit_sink = Some(log.clone());
+        });
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Praxidike Meng".into())), None);
+        em.tell(ControlMsg::Passivate("1".into()), None);
+        em.tell(ControlMsg::Stats, Some(probe));
+        assert_eq!(stats(&listen).passivations, 1);
+
+        let entries = log.0.lock().unwrap();
+        let kinds: Vec<AuditKind> = entries.iter().map(|entry| entry.kind).collect();
+        assert_eq!(kinds, vec![AuditKind::Woken, AuditKind::Passivated]);
+        assert!(entries.iter().all(|entry| entry.entity == "BankAccount" && entry.id == "1"));
+        assert!(entries[0].at <= entries[1].at);
+    }
+
+    #[test]
+    fn query_existing_only() {
+        let (_, em, probe, listen) = manager(BankAccountActorFact, "BankAccount", |conf| {
+            conf.query_existing_only = true;
+        });
+
+        // no instance is woken for the query
+        em.tell(ControlMsg::GetState("1".into()), Some(probe.clone()));
+        match listen.recv() {
+            ControlMsg::CommandRejected(id, reason) => {
+                assert_eq!(id, "1");
+                assert_eq!(reason, RejectReason::NotFound);
+            }
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+        em.tell(ControlMsg::Stats, Some(probe.clone()));
+        assert_eq!(stats(&listen).instances, 0);
+
+        // commands still create it
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Jules-Pierre Mao".into())), Some(probe.clone()));
+        replied(&listen);
+
+        // and queries wake it once it exists
+        em.tell(ControlMsg::Passivate("1".into()), None);
+        em.tell(ControlMsg::GetState("1".into()), Some(probe));
+        match listen.recv() {
+            ControlMsg::State(_, Some(_)) => {}
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+    }
+
+    #[test]
+    fn kill_switch() {
+        let (_, em, probe, listen) = manager(BankAccountActorFact, "BankAccount", |_| {});
+
+        em.tell(ControlMsg::SetAccepting(false), None);
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Marco Inaros".into())), Some(probe.clone()));
+        match listen.recv() {
+            ControlMsg::CommandRejected(id, reason) => {
+                assert_eq!(id, "1");
+                assert_eq!(reason, RejectReason::KillSwitch);
+            }
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+        em.tell(ControlMsg::Stats, Some(probe.clone()));
+        assert_eq!(stats(&listen).instances, 0);
+
+        em.tell(ControlMsg::SetAccepting(true), None);
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Marco Inaros".into())), Some(probe));
+        replied(&listen);
+    }
+
+    #[test]
+    fn replay_to() {
+        let (system, em, probe, listen) = manager(BankAccountActorFact, "BankAccount", |_| {});
+        let (visitor, visitor_listen) = probe_actor(&system, "visitor");
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Bobbie Draper".into())), None);
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(10)), Some(probe.clone()));
+        replied(&listen);
+        em.tell(ControlMsg::Passivate("1".into()), None);
+
+        em.tell(ControlMsg::ReplayTo("1".into(), visitor), None);
+        let mut events = Vec::new();
+        loop {
+            match visitor_listen.recv() {
+                ControlMsg::Event(id, evt) => {
+                    assert_eq!(id, "1");
+                    events.push(*evt);
+                }
+                ControlMsg::Replayed(id, count) => {
+                    assert_eq!(id, "1");
+                    assert_eq!(count, 2);
+                    break;
+                }
+                msg => panic!("Unexpected message {:?}", msg)
+            }
+        }
+        match events[0] {
+            TestMsg::AccountCreatedEvt(_) => {}
+            ref evt => panic!("Unexpected event {:?}", evt)
+        }
+        match events[1] {
+            TestMsg::AmountAddedEvt(10) => {}
+            ref evt => panic!("Unexpected event {:?}", evt)
+        }
+
+        // the instance wasn't woken
+        em.tell(ControlMsg::Stats, Some(probe));
+        assert_eq!(stats(&listen).instances, 0);
+    }
+
+    #[test]
+    fn dry_run() {
+        let (_, em, probe, listen) = manager(BankAccountActorFact, "BankAccount", |_| {});
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Chrisjen Avasarala".into())), Some(probe.clone()));
+        replied(&listen);
+
+        // the would-be events are returned, but not persisted
+        em.tell(ControlMsg::DryRun("1".into(), Box::new(TestMsg::AddAmountCmd(100))), Some(probe.clone()));
+        match listen.recv() {
+            ControlMsg::CommandResult(_, CommandResult::Accepted(events)) => {
+                match events.as_slice() {
+                    [TestMsg::AmountAddedEvt(100)] => {}
+                    events => panic!("Unexpected events {:?}", events)
+                }
+            }
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+
+        em.tell(ControlMsg::GetState("1".into()), Some(probe));
+        match listen.recv() {
+            ControlMsg::State(_, Some(state)) => {
+                match *state {
+                    TestMsg::AccountState(account) => assert_eq!(account.balance, 0),
+                    state => panic!("Unexpected state {:?}", state)
+                }
+            }
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+    }
+
+    #[test]
+    fn error_stats() {
+        let (_, em, probe, listen) = manager(BankAccountActorFact, "BankAccount", |_| {});
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Adolphus Murtry".into())), Some(probe.clone()));
+        replied(&listen);
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Adolphus Murtry".into())), Some(probe.clone()));
+        match replied(&listen) {
+            CommandResult::Rejected(_) => {}
+            result => panic!("Unexpected result {:?}", result)
+        }
+
+        fn error_stats(listen: &impl ProbeReceive<Msg=ControlMsg<TestMsg>>) -> ErrorStats {
+            match listen.recv() {
+                ControlMsg::ErrorStatsResult(stats) => stats,
+                msg => panic!("Unexpected reply {:?}", msg)
+            }
+        }
+
+        em.tell(ControlMsg::ErrorStats, Some(probe.clone()));
+        let stats = error_stats(&listen);
+        assert_eq!(stats.total, 2);
+        assert_eq!(stats.failed, 1);
+        assert_eq!(stats.rate(), 0.5);
+
+        em.tell(ControlMsg::ResetErrorStats, None);
+        em.tell(ControlMsg::ErrorStats, Some(probe));
+        let stats = error_stats(&listen);
+        assert_eq!(stats.total, 0);
+        assert_eq!(stats.rate(), 0.0);
+    }
+
+    #[test]
+    fn restart_limit() {
+        let (_, em, probe, listen) = manager(FailingActorFact, "Failing", |conf| {
+            conf.restart_limit = Some(RestartLimit {
+                max_restarts: 2,
+                within: time::Duration::from_secs(60),
+                cooldown: time::Duration::from_secs(60)
+            });
+        });
+
+        // each failed instance is stopped before the next command
+        for _ in 0..2 {
+            em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(1)), None);
+            assert!(eventually(|| {
+                em.tell(ControlMsg::Stats, Some(probe.clone()));
+                stats(&listen).instances == 0
+            }));
+        }
+
+        // not woken again during the cooldown
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(1)), Some(probe.clone()));
+        match listen.recv() {
+            ControlMsg::CommandRejected(id, reason) => {
+                assert_eq!(id, "1");
+                assert_eq!(reason, RejectReason::CircuitOpen);
+            }
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+
+        // other IDs are unaffected
+        em.tell(CQMsg::Cmd("2".into(), TestMsg::AddAmountCmd(1)), None);
+        em.tell(ControlMsg::Stats, Some(probe));
+        assert_eq!(stats(&listen).wakes, 3);
+    }
+
+    struct SequenceGenerator(AtomicUsize);
+
+    impl IdGenerator for SequenceGenerator {
+        fn generate(&self, entity: &str) -> String {
+            format!("{}-{}", entity, self.0.fetch_add(1, Ordering::SeqCst) + 1)
+        }
+    }
+
+    #[test]
+    fn create_new() {
+        let (system, em, probe, listen) = manager(BankAccountActorFact, "BankAccount", |_| {});
+
+        // UUIDs by default
+        let cmd = Box::new(TestMsg::CreateAccountCmd("Cotyar Ghazi".into()));
+        em.tell(ControlMsg::CreateNew(cmd.clone()), Some(probe.clone()));
+        let assigned = match listen.recv() {
+            ControlMsg::IdAssigned(id) => id,
+            msg => panic!("Unexpected reply {:?}", msg)
+        };
+        assert_eq!(assigned.len(), 36);
+        match listen.recv() {
+            ControlMsg::CommandResult(id, CommandResult::Accepted(_)) => assert_eq!(id, assigned),
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+
+        let mut conf = EntityActorConfig::from(&system.config());
+        conf.id_generator = Some(Arc::new(SequenceGenerator(AtomicUsize::new(0))));
+        let em = Entity::new(&system,
+                            BankAccountActorFact,
+                            "Savings",
+                            Some(conf)).unwrap();
+
+        em.tell(ControlMsg::CreateNew(cmd), Some(probe));
+        match listen.recv() {
+            ControlMsg::IdAssigned(id) => assert_eq!(id, "Savings-1"),
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+        replied(&listen);
+    }
+
+    // persists the accounts of another entity
+    #[derive(Clone)]
+    struct LedgerActorFact;
+
+    impl EntityActorProps for LedgerActorFact {
+        type Msg = TestMsg;
+
+        fn props(&self, id: String, persistence: PersistenceConf) -> BoxActorProd<Self::Msg> {
+            let persistence = Entity::persistence_conf("Checking", &persistence.id);
+            Props::new_args(Box::new(BankAccountActor::new), (id, persistence))
+        }
+    }
+
+    #[test]
+    fn exclusive_keyspace() {
+        let model: DefaultModel<TestMsg> = DefaultModel::new();
+        let system = ActorSystem::new(&model).unwrap();
+
+        let group = EntityGroup::new(&system, "Tycho").unwrap();
+        group.entity(BankAccountActorFact, "Checking", None).unwrap();
+
+        // only warns by default
+        let other = EntityGroup::new(&system, "Ceres").unwrap();
+        other.entity(BankAccountActorFact, "Checking", None).unwrap();
+
+        let mut conf = EntityActorConfig::from(&system.config());
+        conf.exclusive_keyspace = true;
+        match Entity::new(&system, BankAccountActorFact, "Checking", Some(conf.clone())) {
+            Err(CreateError::AlreadyExists(keyspace)) => assert_eq!(keyspace, "Checking"),
+            result => panic!("Unexpected result {:?}", result.map(|_| ()))
+        }
+        Entity::new(&system, BankAccountActorFact, "Savings", Some(conf.clone())).unwrap();
+
+        // the keyspace of the instances is checked as they're woken
+        let em = Entity::new(&system, LedgerActorFact, "Ledger", Some(conf)).unwrap();
+        let (probe, listen) = probe_actor(&system, "probe");
+        em.tell(CQMsg::Cmd("Naomi".into(), TestMsg::AddAmountCmd(10)), Some(probe));
+        match replied(&listen) {
+            CommandResult::Rejected(reason) => assert!(reason.contains("Checking")),
+            result => panic!("Unexpected result {:?}", result)
+        }
+    }
+
+    #[test]
+    fn sweep_now() {
+        // every instance is due as soon as it's idle
+        let (_, em, probe, listen) = manager(BankAccountActorFact, "BankAccount", |conf| {
+            conf.sleep_after_secs = 0;
+        });
+
+        em.tell(ControlMsg::Stats, Some(probe.clone()));
+        let next_sweep_at = stats(&listen).next_sweep_at;
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Anderson Dawes".into())), Some(probe.clone()));
+        em.tell(CQMsg::Cmd("2".into(), TestMsg::CreateAccountCmd("Klaes Ashford".into())), Some(probe.clone()));
+        for _ in 0..2 {
+            replied(&listen);
+        }
+        // idle for longer than `sleep_after_secs`, however coarse the clock
+        thread::sleep(time::Duration::from_millis(10));
+        em.tell(ControlMsg::SweepNow, Some(probe.clone()));
+        match listen.recv() {
+            ControlMsg::Swept(SweepStats { passivated, checking, instances }) => {
+                assert_eq!(passivated, 2);
+                assert_eq!(checking, 0);
+                assert_eq!(instances, 0);
+            }
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+
+        // the scheduled sweep isn't moved
+        em.tell(ControlMsg::Stats, Some(probe));
+        let stats = stats(&listen);
+        assert_eq!(stats.passivations, 2);
+        assert!(stats.last_sweep_at.is_some());
+        let moved = match stats.next_sweep_at.duration_since(next_sweep_at) {
+            Ok(moved) => moved,
+            Err(e) => e.duration()
+        };
+        assert!(moved < time::Duration::from_secs(1));
+    }
+
+    #[test]
+    fn replay_progress() {
+        let faults = FaultInjector::new();
+        faults.inject(Some("1"), Fault::DelayReplay(time::Duration::from_millis(500)));
+
+        let (_, em, probe, listen) = manager(SlowActorFact(faults), "Slow", |_| {});
+
+        let progress = |id: &str| {
+            em.tell(ControlMsg::ReplayProgress(id.into()), Some(probe.clone()));
+            match listen.recv() {
+                ControlMsg::ReplayProgressResult(_, progress) => progress,
+                msg => panic!("Unexpected reply {:?}", msg)
+            }
+        };
+
+        // the replay is delayed halfway through
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(1)), None);
+        assert!(eventually(|| {
+            progress("1").map(|p| p.elapsed >= time::Duration::from_millis(100)).unwrap_or(false)
+        }));
+        match progress("1") {
+            Some(progress) => {
+                assert_eq!(progress.applied, 5);
+                assert_eq!(progress.total, Some(10));
+            }
+            progress => panic!("Unexpected progress {:?}", progress)
+        }
+
+        // nothing to report once ready, or for instances that aren't running
+        assert!(eventually(|| progress("1").is_none()));
+        assert!(progress("2").is_none());
+    }
+
+    #[test]
+    fn state_cache_hand_off() {
+        let (_, em, probe, listen) = manager(BankAccountActorFact, "BankAccount", |conf| {
+            conf.state_cache = Some(StateCache {
+                capacity: 10,
+                ttl: time::Duration::from_secs(60)
+            });
+        });
+
+        let balance = |listen: &dyn ProbeReceive<Msg=ControlMsg<TestMsg>>| {
+            match listen.recv() {
+                ControlMsg::State(_, Some(state)) => match *state {
+                    TestMsg::AccountState(account) => account.balance,
+                    state => panic!("Unexpected state {:?}", state)
+                },
+                msg => panic!("Unexpected reply {:?}", msg)
+            }
+        };
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Camina Drummer".into())), Some(probe.clone()));
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(100)), Some(probe.clone()));
+        for _ in 0..2 {
+            replied(&listen);
+        }
+
+        // cached from the state handed off as the instance stopped,
+        // without having been queried
+        em.tell(ControlMsg::Passivate("1".into()), None);
+        assert!(eventually(|| em.children().count() == 0));
+        em.tell(ControlMsg::GetState("1".into()), Some(probe.clone()));
+        assert_eq!(balance(&listen), 100);
+        em.tell(ControlMsg::Stats, Some(probe.clone()));
+        assert_eq!(stats(&listen).instances, 0);
+
+        // a query doesn't invalidate the cached state, a command does
+        em.tell(ControlMsg::Pause, None);
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::BalanceQry), Some(probe.clone()));
+        em.tell(ControlMsg::GetState("1".into()), Some(probe.clone()));
+        assert_eq!(balance(&listen), 100);
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(5)), Some(probe.clone()));
+        em.tell(ControlMsg::GetState("1".into()), Some(probe.clone()));
+        em.tell(ControlMsg::Resume, None);
+        for _ in 0..2 {
+            replied(&listen);
+        }
+        assert_eq!(balance(&listen), 105);
+    }
+
+    #[test]
+    fn passivation_grace_after_sweep() {
+        let (_, em, probe, listen) = manager(BankAccountActorFact, "BankAccount", |conf| {
+            conf.sleep_after_secs = 0;
+            conf.passivation_grace = Some(time::Duration::from_secs(1));
+        });
+
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::CreateAccountCmd("Carlos de Baca".into())), Some(probe.clone()));
+        replied(&listen);
+        // idle for longer than `sleep_after_secs`, however coarse the clock
+        thread::sleep(time::Duration::from_millis(10));
+
+        // held until the swept instance has stopped, rather than
+        // failing to create it while its name is taken
+        em.tell(ControlMsg::SweepNow, Some(probe.clone()));
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(5)), Some(probe.clone()));
+        match listen.recv() {
+            ControlMsg::Swept(stats) => assert_eq!(stats.passivated, 1),
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+        match listen.recv() {
+            ControlMsg::CommandResult(id, CommandResult::Accepted(events)) => {
+                assert_eq!(id, "1");
+                assert_eq!(events.len(), 1);
+            }
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+    }
+
+    // Fails on commands and rejects queries
+    struct FlakyActor {
+        id: String
+    }
+
+    impl Actor for FlakyActor {
+        type Msg = TestMsg;
+
+        fn receive(&mut self, ctx: &Context<TestMsg>, msg: TestMsg, sender: Option<ActorRef<TestMsg>>) {
+            match msg {
+                TestMsg::BalanceQry => {
+                    Entity::reply(ctx, &self.id, sender, CommandResult::Rejected("No balance".into()));
+                }
+                msg => panic!("Failed to handle {:?}", msg)
+            }
+        }
+    }
+
+    #[derive(Clone)]
+    struct FlakyActorFact;
+
+    impl EntityActorProps for FlakyActorFact {
+        type Msg = TestMsg;
+
+        fn props(&self, id: String, _: PersistenceConf) -> BoxActorProd<Self::Msg> {
+            Props::new(Box::new(move || -> BoxActor<TestMsg> { Box::new(FlakyActor { id: id.clone() }) }))
+        }
+    }
+
+    #[test]
+    fn restart_limit_rejected() {
+        let (_, em, probe, listen) = manager(FlakyActorFact, "Flaky", |conf| {
+            conf.restart_limit = Some(RestartLimit {
+                max_restarts: 2,
+                within: time::Duration::from_secs(60),
+                cooldown: time::Duration::from_secs(60)
+            });
+        });
+
+        // restarted in place after each failure, which is counted
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(1)), None);
+
+        // a rejected command doesn't reset the failures
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::BalanceQry), Some(probe.clone()));
+        match replied(&listen) {
+            CommandResult::Rejected(_) => {}
+            result => panic!("Unexpected result {:?}", result)
+        }
+
+        // the query is handled after the failure, unless the manager
+        // has already counted the failure when it's routed
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::AddAmountCmd(1)), None);
+        em.tell(CQMsg::Cmd("1".into(), TestMsg::BalanceQry), Some(probe.clone()));
+        match listen.recv() {
+            ControlMsg::CommandResult(_, CommandResult::Rejected(_)) => {
+                em.tell(CQMsg::Cmd("1".into(), TestMsg::BalanceQry), Some(probe));
+                match listen.recv() {
+                    ControlMsg::CommandRejected(_, RejectReason::CircuitOpen) => {}
+                    msg => panic!("Unexpected reply {:?}", msg)
+                }
+            }
+            ControlMsg::CommandRejected(_, RejectReason::CircuitOpen) => {}
+            msg => panic!("Unexpected reply {:?}", msg)
+        }
+    }
+}
@@ -0,0 +1,49 @@
+use riker::actors::*;
+use uuid::Uuid;
+
+use crate::{ControlMsg, EntityMsg};
+
+// Loads the events of an instance for the entity manager. riker's event
+// store doesn't tag its results, so each request is sent by an actor of
+// its own, which tags the result with the ID of the request
+pub(crate) struct Loader<Msg: Message> {
+    manager: ActorRef<Msg>,
+    request: Uuid,
+    persistence: PersistenceConf,
+}
+
+impl<Msg: EntityMsg> Loader<Msg> {
+    pub(crate) fn actor((manager, request, persistence): (ActorRef<Msg>, Uuid, PersistenceConf)) -> BoxActor<Msg> {
+        Box::new(Loader { manager, request, persistence })
+    }
+
+    fn loaded(&self, ctx: &Context<Msg>, events: Vec<Msg>) {
+        self.manager.tell(ControlMsg::Loaded(self.request, events), Some(ctx.myself()));
+        ctx.stop(&ctx.myself());
+    }
+}
+
+impl<Msg: EntityMsg> Actor for Loader<Msg> {
+    type Msg = Msg;
+
+    fn pre_start(&mut self, ctx: &Context<Msg>) {
+        match ctx.persistence.event_store {
+            Some(ref es) => {
+                let conf = self.persistence.clone();
+                es.tell(ESMsg::Load(conf.id, conf.keyspace), Some(ctx.myself()));
+            }
+            None => self.loaded(ctx, Vec::new())
+        }
+    }
+
+    fn other_receive(&mut self,
+                    ctx: &Context<Msg>,
+                    msg: ActorMsg<Msg>,
+                    _: Option<ActorRef<Msg>>) {
+        if let ActorMsg::ES(ESMsg::LoadResult(events)) = msg {
+            self.loaded(ctx, events);
+        }
+    }
+
+    fn receive(&mut self, _: &Context<Msg>, _: Msg, _: Option<ActorRef<Msg>>) {}
+}
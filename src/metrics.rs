@@ -0,0 +1,132 @@
+//! Prometheus text rendering of entity manager statistics.
+//!
+//! Requires the `metrics` feature.
+
+use std::fmt::Write;
+use std::sync::mpsc::{channel, Sender};
+use std::time::Duration;
+
+use riker::actors::*;
+
+use crate::{secs, ControlMsg, Entity, EntityMsg, EntityStats, LatencyStats};
+
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+impl Entity {
+    /// Renders the statistics of every entity manager in the system in
+    /// the Prometheus text format. See `render`.
+    ///
+    /// This blocks the calling thread while the managers reply, so it
+    /// must not be called from within an actor. Managers that don't reply
+    /// within a second, e.g. because they are stopping, are left out.
+    pub fn metrics_text<Msg: EntityMsg>(sys: &ActorSystem<Msg>) -> String {
+        let managers: Vec<ActorRef<Msg>> = Entity::registry(sys).into_iter()
+                                                                .map(|(_, manager)| manager)
+                                                                .collect();
+        let count = managers.len();
+
+        let (tx, rx) = channel();
+        let props = Props::new_args(Box::new(StatsCollector::actor), (managers, tx));
+        let mut stats = Vec::new();
+        if let Ok(collector) = sys.tmp_actor_of(props) {
+            while stats.len() < count {
+                match rx.recv_timeout(TIMEOUT) {
+                    Ok(s) => stats.push(s),
+                    Err(_) => break
+                }
+            }
+            sys.stop(&collector);
+        }
+
+        stats.sort_by(|a: &EntityStats, b: &EntityStats| a.name.cmp(&b.name));
+        render(&stats)
+    }
+}
+
+/// Renders statistics in the Prometheus text format.
+///
+/// Each metric has an `entity` label with the name of the entity:
+///
+/// - `cqrs_instances` (gauge): instances currently running
+/// - `cqrs_subscribers` (gauge): event subscriptions
+/// - `cqrs_wakes_total` (counter): instances woken
+/// - `cqrs_passivations_total` (counter): instances passivated
+/// - `cqrs_wake_latency_seconds` (summary): time taken by instances to
+///   become ready after being woken, as `_sum` and `_count`
+/// - `cqrs_persist_latency_seconds` (summary): time taken to persist
+///   events, as `_sum` and `_count`. See `EntityStats::persist_latency`.
+///
+/// Counters start at zero when a manager starts.
+pub fn render(stats: &[EntityStats]) -> String {
+    let mut out = String::new();
+
+    let mut metric = |name: &str, kind: &str, help: &str, value: &dyn Fn(&EntityStats) -> String| {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        for s in stats.iter() {
+            let _ = writeln!(out, "{}{{entity=\"{}\"}} {}", name, escape(&s.name), value(s));
+        }
+    };
+
+    metric("cqrs_instances", "gauge", "Instances currently running",
+            &|s: &EntityStats| s.instances.to_string());
+    metric("cqrs_subscribers", "gauge", "Event subscriptions",
+            &|s: &EntityStats| s.subscribers.to_string());
+    metric("cqrs_wakes_total", "counter", "Instances woken",
+            &|s: &EntityStats| s.wakes.to_string());
+    metric("cqrs_passivations_total", "counter", "Instances passivated",
+            &|s: &EntityStats| s.passivations.to_string());
+
+    let mut summary = |name: &str, help: &str, latency: &dyn Fn(&EntityStats) -> &LatencyStats| {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} summary", name);
+        for s in stats.iter() {
+            let entity = escape(&s.name);
+            let _ = writeln!(out, "{}_sum{{entity=\"{}\"}} {}",
+                                name, entity, secs(latency(s).total()));
+            let _ = writeln!(out, "{}_count{{entity=\"{}\"}} {}",
+                                name, entity, latency(s).count);
+        }
+    };
+
+    summary("cqrs_wake_latency_seconds", "Time taken by instances to become ready after being woken",
+            &|s: &EntityStats| &s.wake_latency);
+    summary("cqrs_persist_latency_seconds", "Time taken to persist events",
+            &|s: &EntityStats| &s.persist_latency);
+
+    out
+}
+
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// Requests the statistics of each manager and forwards the replies
+struct StatsCollector<Msg: Message> {
+    managers: Vec<ActorRef<Msg>>,
+    tx: Sender<EntityStats>,
+}
+
+impl<Msg: EntityMsg> StatsCollector<Msg> {
+    fn actor((managers, tx): (Vec<ActorRef<Msg>>, Sender<EntityStats>)) -> BoxActor<Msg> {
+        Box::new(StatsCollector { managers, tx })
+    }
+}
+
+impl<Msg: EntityMsg> Actor for StatsCollector<Msg> {
+    type Msg = Msg;
+
+    fn pre_start(&mut self, ctx: &Context<Msg>) {
+        for manager in self.managers.iter() {
+            manager.tell(ControlMsg::Stats, Some(ctx.myself()));
+        }
+    }
+
+    fn receive(&mut self, _: &Context<Msg>, msg: Msg, _: Option<ActorRef<Msg>>) {
+        if let Ok(ControlMsg::StatsResult(stats)) = msg.into_control() {
+            let _ = self.tx.send(stats);
+        }
+    }
+}
@@ -0,0 +1,112 @@
+use std::collections::{HashSet, VecDeque};
+
+use riker::actors::*;
+
+use crate::{EntityMsg, RejectReason};
+
+/// What a `CommandMiddleware` does with a command
+#[derive(Clone, Debug)]
+pub enum MiddlewareResult<Msg> {
+    /// Pass the command, which may have been transformed, to the next
+    /// middleware, or route it if this is the last
+    Continue(Msg),
+
+    /// Reject the command. The sender, or the default sender, receives
+    /// `ControlMsg::CommandRejected`. Commands without either are sent to
+    /// dead letters, as they were received by the manager.
+    Reject(RejectReason),
+
+    /// Drop the command without a reply
+    Drop,
+}
+
+/// Inspects, transforms or stops commands before the entity manager
+/// routes them, e.g. for validation, metrics or deduplication.
+///
+/// Middlewares are returned by `EntityActorProps::middleware` and run on
+/// the manager in that order, each receiving the command returned by the
+/// one before it. The first to reject or drop a command stops the chain.
+/// They run before any of the manager's own checks, such as rate limits,
+/// so every command a client sends passes through them, including the
+/// steps of sagas. Commands released after waiting, e.g. for an instance
+/// to wake, don't pass through them again.
+///
+/// Middlewares are created when the manager starts, and again if it is
+/// restarted, so their state doesn't survive a restart. They run on the
+/// manager, which routes no commands while they run, so they should be
+/// fast and must not block.
+pub trait CommandMiddleware<Msg: EntityMsg>: Send {
+    fn handle(&mut self, ctx: &Context<Msg>, id: &str, cmd: Msg) -> MiddlewareResult<Msg>;
+}
+
+/// Logs every command at debug level
+pub struct LoggingMiddleware {
+    name: String,
+}
+
+impl LoggingMiddleware {
+    /// Logs commands of the named entity
+    pub fn new(name: &str) -> Self {
+        LoggingMiddleware {
+            name: name.to_string()
+        }
+    }
+}
+
+impl<Msg: EntityMsg> CommandMiddleware<Msg> for LoggingMiddleware {
+    fn handle(&mut self, _: &Context<Msg>, id: &str, cmd: Msg) -> MiddlewareResult<Msg> {
+        debug!("CQRS: Entity: {}, ID: {}, CMD: {:?}, State: received", self.name, id, cmd);
+        MiddlewareResult::Continue(cmd)
+    }
+}
+
+/// Rejects commands seen recently with `RejectReason::Duplicate`, e.g.
+/// commands retried by a client that didn't receive the reply.
+///
+/// Commands are identified by a key derived by the given function, such
+/// as a request ID carried by the command. Commands without a key are
+/// always passed on. Only the last `capacity` keys are remembered, and
+/// the keys are lost when the manager restarts, so this doesn't replace
+/// idempotent command handling.
+pub struct DedupMiddleware<F> {
+    key: F,
+    capacity: usize,
+    seen: HashSet<String>,
+    order: VecDeque<String>,
+}
+
+impl<F> DedupMiddleware<F> {
+    pub fn new(capacity: usize, key: F) -> Self {
+        DedupMiddleware {
+            key,
+            capacity,
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+}
+
+impl<F, Msg> CommandMiddleware<Msg> for DedupMiddleware<F>
+    where F: Fn(&str, &Msg) -> Option<String> + Send, Msg: EntityMsg
+{
+    fn handle(&mut self, _: &Context<Msg>, id: &str, cmd: Msg) -> MiddlewareResult<Msg> {
+        let key = match (self.key)(id, &cmd) {
+            Some(key) => key,
+            None => return MiddlewareResult::Continue(cmd)
+        };
+
+        if self.seen.contains(&key) {
+            return MiddlewareResult::Reject(RejectReason::Duplicate);
+        }
+
+        self.seen.insert(key.clone());
+        self.order.push_back(key);
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+
+        MiddlewareResult::Continue(cmd)
+    }
+}
@@ -0,0 +1,186 @@
+use std::collections::VecDeque;
+use std::sync::mpsc::{channel, Sender};
+use std::time::Duration;
+
+use riker::actors::*;
+
+use crate::{Entity, EntityMsg};
+
+// longest wait for the event store to answer a single request
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+impl Entity {
+    /// Copies the persisted events of the given instances from one
+    /// keyspace to another, returning the number of instances copied.
+    ///
+    /// riker's event store can't list the IDs in a keyspace or delete
+    /// events, so the IDs must be given and the events are copied rather
+    /// than moved. Events are copied in order, but with the time they
+    /// were copied rather than the time they were first persisted.
+    ///
+    /// Fails without copying anything if a manager of an entity named
+    /// after either keyspace is running. Instances must not persist events
+    /// to either keyspace while they are copied, so any entity using them
+    /// must be stopped with `Entity::stop` first and started again once
+    /// the migration is complete.
+    ///
+    /// An interrupted migration can be run again with the same IDs.
+    /// Instances whose events were all copied are skipped, while an
+    /// instance with only some of its events in the new keyspace fails
+    /// the migration and must be repaired in the event store.
+    ///
+    /// This blocks the calling thread, so it must not be called from
+    /// within an actor.
+    pub fn migrate_keyspace<Msg: EntityMsg>(sys: &ActorSystem<Msg>,
+                                            from: &str,
+                                            to: &str,
+                                            ids: &[String]) -> Result<usize, String> {
+        let running = Entity::registry(sys).into_iter()
+                                            .find(|(name, _)| name == from || name == to);
+        if let Some((name, _)) = running {
+            return Err(format!("Entity manager {} is running", name));
+        }
+
+        let (tx, rx) = channel();
+        let args = (from.to_string(), to.to_string(), ids.to_vec(), tx);
+        let migrator = sys.tmp_actor_of(Props::new_args(Box::new(Migrator::actor), args))
+                            .map_err(|_| "Failed to start the migration".to_string())?;
+
+        let mut migrated = 0;
+        let result = loop {
+            if migrated == ids.len() {
+                break Ok(migrated);
+            }
+
+            match rx.recv_timeout(TIMEOUT) {
+                Ok(Ok(id)) => {
+                    debug!("CQRS: Migrate: ID: {}, copied from {} to {}", id, from, to);
+                    migrated += 1;
+                }
+                Ok(Err(e)) => break Err(e),
+                Err(_) => break Err(format!("Timed out after copying {} of {} instances", migrated, ids.len()))
+            }
+        };
+
+        sys.stop(&migrator);
+        result
+    }
+}
+
+enum Phase {
+    // loading the events already in the new keyspace
+    Target,
+    // loading the events to copy. (Events in the new keyspace)
+    Source(usize),
+    // waiting for copied events to be persisted. (Remaining events)
+    Persisting(usize),
+}
+
+// Copies the events of each ID in turn, reporting each ID once its
+// events are persisted
+struct Migrator<Msg: Message> {
+    from: String,
+    to: String,
+    ids: VecDeque<String>,
+    current: Option<(String, Phase)>,
+    tx: Sender<Result<String, String>>,
+}
+
+impl<Msg: EntityMsg> Migrator<Msg> {
+    fn actor((from, to, ids, tx): (String, String, Vec<String>, Sender<Result<String, String>>)) -> BoxActor<Msg> {
+        Box::new(Migrator {
+            from,
+            to,
+            ids: ids.into_iter().collect(),
+            current: None,
+            tx,
+        })
+    }
+
+    fn next(&mut self, ctx: &Context<Msg>) {
+        if let Some((id, _)) = self.current.take() {
+            let _ = self.tx.send(Ok(id));
+        }
+
+        if let Some(id) = self.ids.pop_front() {
+            self.load(ctx, &id, &self.to);
+            self.current = Some((id, Phase::Target));
+        }
+    }
+
+    fn load(&self, ctx: &Context<Msg>, id: &str, keyspace: &str) {
+        match ctx.persistence.event_store {
+            Some(ref es) => es.tell(ESMsg::Load(id.to_string(), keyspace.to_string()), Some(ctx.myself())),
+            None => {
+                let _ = self.tx.send(Err("No event store".to_string()));
+            }
+        }
+    }
+
+    fn loaded(&mut self, ctx: &Context<Msg>, events: Vec<Msg>) {
+        let (id, phase) = match self.current.take() {
+            Some(current) => current,
+            None => return
+        };
+
+        match phase {
+            Phase::Target => {
+                self.load(ctx, &id, &self.from);
+                self.current = Some((id, Phase::Source(events.len())));
+            }
+            Phase::Source(copied) if copied == events.len() => {
+                // nothing to copy, or already copied by an interrupted migration
+                self.current = Some((id, Phase::Persisting(0)));
+                self.next(ctx);
+            }
+            Phase::Source(0) => {
+                if let Some(ref es) = ctx.persistence.event_store {
+                    for evt in events.iter() {
+                        let msg = ESMsg::Persist(Evt::new(evt.clone()), id.clone(), self.to.clone(), None);
+                        es.tell(msg, Some(ctx.myself()));
+                    }
+                }
+                self.current = Some((id, Phase::Persisting(events.len())));
+            }
+            Phase::Source(copied) => {
+                let e = format!("ID {} has {} of {} events in {}", id, copied, events.len(), self.to);
+                let _ = self.tx.send(Err(e));
+            }
+            Phase::Persisting(_) => {}
+        }
+    }
+}
+
+impl<Msg: EntityMsg> Actor for Migrator<Msg> {
+    type Msg = Msg;
+
+    fn pre_start(&mut self, ctx: &Context<Msg>) {
+        self.next(ctx);
+    }
+
+    fn other_receive(&mut self,
+                    ctx: &Context<Msg>,
+                    msg: ActorMsg<Msg>,
+                    _: Option<ActorRef<Msg>>) {
+        if let ActorMsg::ES(ESMsg::LoadResult(events)) = msg {
+            self.loaded(ctx, events);
+        }
+    }
+
+    // the event store confirms each persisted event
+    fn apply_event(&mut self, ctx: &Context<Msg>, _: Msg) {
+        let done = match self.current {
+            Some((_, Phase::Persisting(ref mut remaining))) => {
+                *remaining = remaining.saturating_sub(1);
+                *remaining == 0
+            }
+            _ => false
+        };
+
+        if done {
+            self.next(ctx);
+        }
+    }
+
+    fn receive(&mut self, _: &Context<Msg>, _: Msg, _: Option<ActorRef<Msg>>) {}
+}
@@ -0,0 +1,752 @@
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+use riker::actors::*;
+use uuid::Uuid;
+
+use crate::saga::SagaOutcome;
+
+/// Messages that control an entity manager, as opposed to domain
+/// commands which are routed to instances using `CQMsg::Cmd`.
+///
+/// Control messages are carried in the system's message type, which
+/// must implement `EntityMsg`. riker's `CQMsg` can't be extended, so
+/// commands sent in other ways than `CQMsg::Cmd`, such as
+/// `ControlMsg::CmdAutoKey`, are control messages too.
+#[derive(Clone, Debug)]
+pub enum ControlMsg<Msg: Message> {
+    /// Passivate the instance with the given ID, if it is running
+    Passivate(String),
+
+    /// Passivate all running instances
+    Clear,
+
+    /// Run the passivation sweep right away, e.g. to reclaim memory under
+    /// pressure, rather than waiting for the next tick. The scheduled
+    /// sweep isn't moved. The sender, if any, receives `Swept`.
+    SweepNow,
+
+    /// Received in response to `SweepNow`
+    Swept(SweepStats),
+
+    /// Sent to an entity manager waiting for its `ResidencyBudget` when
+    /// any manager using the budget passivates an instance
+    BudgetAvailable,
+
+    /// Sent by the entity manager to an instance that is due to be
+    /// passivated, when `EntityActorConfig::passivation_check_timeout`
+    /// is set. The instance replies using `Entity::reply_can_passivate`.
+    CanPassivate(String),
+
+    /// Sent by an instance in response to `CanPassivate`.
+    /// (ID, whether the instance can be passivated)
+    CanPassivateResult(String, bool),
+
+    /// Sent by the entity manager to itself when the wait for
+    /// `CanPassivateResult` from the instance with the given ID ends
+    CanPassivateTimeout(String),
+
+    /// Sent by the entity manager to itself when the grace period of the
+    /// passivated instance with the given ID ends.
+    /// See `EntityActorConfig::passivation_grace`.
+    GraceEnded(String),
+
+    /// Sent by the entity manager to itself when the instance with the
+    /// given ID should be ready. See `EntityActorConfig::init_timeout`.
+    InitTimedOut(String),
+
+    /// Sent to the entity manager with the events it requested from the
+    /// event store. (Request, Events)
+    Loaded(Uuid, Vec<Msg>),
+
+    /// Discard the state of the running instance with the given ID and
+    /// restore it from its persisted events, without passivating it.
+    ///
+    /// The instance is restarted using riker's supervision, so any
+    /// messages it is processing or has not yet processed may be lost or
+    /// handled against the state being replayed. Meant for recovering a
+    /// single misbehaving instance and should be used sparingly. Does
+    /// nothing if the instance is not running.
+    Rehydrate(String),
+
+    /// A command to route to the instance with the given ID, only if
+    /// the instance exists. Otherwise the sender receives `CommandRejected`
+    /// with `RejectReason::NotFound`
+    /// rather than an empty instance being woken.
+    ///
+    /// An instance exists if it is running or has persisted events.
+    /// Checking an instance that is asleep loads all of its events from
+    /// the event store, which are then loaded again when it is woken.
+    /// The check uses the keyspace of `Entity::persistence_conf`, so the
+    /// events of instances that persist to a keyspace of their own aren't
+    /// found, see `EntityActorProps::props`.
+    CmdExisting(String, Box<Msg>),
+
+    /// A command that creates the instance with the given ID, routed only
+    /// if the instance doesn't exist yet. Otherwise the sender receives
+    /// `CommandRejected` with `RejectReason::AlreadyExists`.
+    ///
+    /// Existence is checked as for `CmdExisting`. While the check is in
+    /// progress, further `Create` commands for the ID are rejected, so
+    /// that only one of several racing creates is routed. Plain commands
+    /// aren't held, and a manager can't see creates sent to the same
+    /// entity on another system.
+    Create(String, Box<Msg>),
+
+    /// A command that creates a new instance with an ID generated by the
+    /// manager, e.g. for resources whose ID is assigned by the server.
+    ///
+    /// The sender receives `IdAssigned` with the generated ID, then the
+    /// reply to the command, which is routed as for `Create`. See
+    /// `IdGenerator` for the uniqueness of generated IDs.
+    CreateNew(Box<Msg>),
+
+    /// Received in response to `CreateNew`, before the reply to the
+    /// command. (ID)
+    IdAssigned(String),
+
+    /// Request the state of the instance with the given ID, first routing
+    /// the given create command to it if the instance doesn't exist yet.
+    /// (ID, Create command)
+    ///
+    /// Existence is checked as for `CmdExisting`, and an instance that
+    /// commands are waiting to wake counts as existing. `GetState` is
+    /// routed after the create command, so the state reflects it. If the
+    /// instance is created the sender receives the `CommandResult` of the
+    /// create command and then `State`, otherwise only `State`, so a
+    /// repeated `GetOrCreate` doesn't create the instance again. Requests
+    /// for the same ID are handled in the order they were received.
+    GetOrCreate(String, Box<Msg>),
+
+    /// A command to route to the instance whose ID is derived from the
+    /// command using `EntityActorProps::extract_key`. Commands from which
+    /// no ID can be derived are sent to dead letters.
+    CmdAutoKey(Box<Msg>),
+
+    /// A command to handle only if the instance with the given ID is at
+    /// the expected version, for optimistic concurrency control.
+    /// (ID, Expected version, Command)
+    ///
+    /// The instance receives this message rather than the command, and
+    /// checks the version using `Version::check`. If the instance is at
+    /// another version, e.g. because another command was handled since
+    /// the caller read its state, the sender receives
+    /// `CommandResult::VersionConflict` and the command isn't handled.
+    CmdExpecting(String, u64, Box<Msg>),
+
+    /// A command to handle without persisting or applying its events,
+    /// e.g. to validate it or preview its outcome. (ID, Command)
+    ///
+    /// The instance receives this message rather than the command, and
+    /// replies using `Entity::reply` with the result the command would
+    /// have, such as `CommandResult::Accepted` with the events it would
+    /// persist, without changing its state. Instances that handle their
+    /// commands through `Entity::decide` get this for free. Instances that
+    /// don't support dry runs should reply with `CommandResult::Rejected`.
+    /// An instance that is asleep is woken to handle it.
+    DryRun(String, Box<Msg>),
+
+    /// Route the commands of a saga, one at a time in the given order.
+    /// (ID, Command) for each step.
+    ///
+    /// A step is accepted when its instance replies with an accepted or
+    /// no-op `CommandResult`. If a step is rejected or can't be routed,
+    /// the compensating commands of the accepted steps are sent in reverse
+    /// order, see `EntityActorProps::compensate`. The sender receives
+    /// `SagaResult`. Sagas are eventually consistent, not transactional:
+    /// other commands can observe the effects of steps before they are
+    /// compensated, and compensations are not retried.
+    Saga(Vec<(String, Msg)>),
+
+    /// Received in response to `Saga`
+    SagaResult(SagaOutcome),
+
+    /// Sent by a saga coordinator to itself when the wait for the reply
+    /// to the given step ends. See `EntityActorConfig::saga_step_timeout`.
+    SagaStepTimeout(usize),
+
+    /// Sent by the entity manager to itself when the wait for a reply to
+    /// a command ends. See `EntityActorConfig::command_timeout`.
+    /// (ID, Sequence number of the command)
+    CommandTimeout(String, u64),
+
+    /// Request the current state of the instance with the given ID.
+    ///
+    /// Routed to the instance like a command, so an instance that is
+    /// asleep is woken and replays all of its events before it can reply.
+    /// The instance replies with `State` using `Entity::reply_state`.
+    GetState(String),
+
+    /// Received in response to `GetState`. (ID, State).
+    /// `None` if the instance has no state, e.g. it was never created.
+    State(String, Option<Box<Msg>>),
+
+    /// Sent by an instance to its entity manager as it stops, with the
+    /// state it stops in. See `Entity::hand_off_state`. (ID, State)
+    FinalState(String, Option<Box<Msg>>),
+
+    /// Forward riker's `Identify` to the instance with the given ID.
+    /// The sender receives `Info` once the instance has handled all
+    /// commands sent before, or from the manager if it is not running.
+    Identify(String),
+
+    /// Sent by an instance to the sender of a command once it has
+    /// handled it. (ID, Result). See `Entity::reply`.
+    CommandResult(String, CommandResult<Msg>),
+
+    /// Received before the reply to a command that woke the instance
+    /// with the given ID, i.e. the instance replayed its events before
+    /// handling the command. See `EntityActorConfig::report_cold_starts`.
+    ColdStart(String),
+
+    /// Passivate all instances and stop the entity manager.
+    /// See `Entity::stop`.
+    Stop,
+
+    /// Received in response to `Stop`, once the manager has started
+    /// draining, with its `EntityActorConfig::drain_timeout`
+    Stopping(Option<Duration>),
+
+    /// Sent by the entity manager to itself when its
+    /// `EntityActorConfig::drain_timeout` passes while stopping
+    DrainTimedOut,
+
+    /// Wake the instances with the given IDs, e.g. a known working set
+    /// after a deploy. Wakes are throttled by `max_concurrent_wakes` and
+    /// the residency budget like the wakes of commands, so instances over
+    /// the limits wait to wake. The sender receives `PreloadResult`.
+    PreloadBatch(Vec<String>),
+
+    /// Received in response to `PreloadBatch`. Instances waiting to wake
+    /// are counted as woken, instances that failed to be created are not
+    /// counted. (Woken, Already running)
+    PreloadResult(usize, usize),
+
+    /// Switch the manager to read-only mode, e.g. while the event store is
+    /// unavailable, or back to read-write.
+    ///
+    /// In read-only mode only `GetState` and queries, see
+    /// `EntityActorProps::is_query`, are routed to running instances, and
+    /// `GetState` is answered from the state cache for instances that are
+    /// asleep. Everything else is rejected with `RejectReason::ReadOnly`,
+    /// and no instances are woken. The manager can't detect an unavailable
+    /// event store, so the switch is made by the application.
+    ReadOnly(bool),
+
+    /// Hold commands rather than routing them to instances, e.g. during
+    /// maintenance, until `Resume`. Doesn't passivate running instances.
+    /// See `EntityActorConfig::max_paused_cmds`.
+    Pause,
+
+    /// Route the commands held since `Pause`, in the order they were received
+    Resume,
+
+    /// Switch whether the manager accepts commands, e.g. as a kill switch
+    /// during an incident. While off, every command is rejected right
+    /// away with `RejectReason::KillSwitch` instead of being held as with
+    /// `Pause`, including commands held before the switch as they are
+    /// released, and no instances are woken. Running instances aren't
+    /// passivated and control messages are still handled.
+    SetAccepting(bool),
+
+    /// Request the manager's statistics
+    Stats,
+
+    /// Received in response to `Stats`
+    StatsResult(EntityStats),
+
+    /// Request the rate of commands rejected by their instance over the
+    /// error window. See `EntityActorConfig::error_window`.
+    ErrorStats,
+
+    /// Received in response to `ErrorStats`
+    ErrorStatsResult(ErrorStats),
+
+    /// Clear the counts of the error window, e.g. once an incident is
+    /// resolved, so that the rate reflects only new commands
+    ResetErrorStats,
+
+    /// Received when the manager didn't route a command for the given ID
+    /// to its instance. (ID, Reason)
+    CommandRejected(String, RejectReason),
+
+    /// Request information on each running instance
+    DumpInstances,
+
+    /// Received in response to `DumpInstances`
+    DumpInstancesResult(Vec<InstanceInfo>),
+
+    /// Request the state of running instances, for diagnostics. Instances
+    /// are sorted by ID and the given number are skipped, then at most
+    /// the given number are included, so that large numbers of instances
+    /// can be dumped page by page. (Offset, Limit)
+    ///
+    /// Each instance is sent `GetState` and must reply using
+    /// `Entity::reply_state`. Requires the `diagnostics` feature.
+    #[cfg(feature = "diagnostics")]
+    DumpState(usize, usize),
+
+    /// Received in response to `DumpState`
+    #[cfg(feature = "diagnostics")]
+    DumpStateResult(crate::dump::StateDump<Msg>),
+
+    /// Request a check that the running instances known to the manager
+    /// match its child actors. Requires the `testkit` feature.
+    /// See `testkit::ConsistencyReport`.
+    #[cfg(any(test, feature = "testkit"))]
+    AuditConsistency,
+
+    /// Received in response to `AuditConsistency`
+    #[cfg(any(test, feature = "testkit"))]
+    AuditConsistencyResult(crate::testkit::ConsistencyReport),
+
+    /// Request the most recent commands received by the running instance
+    /// with the given ID. See `EntityActorConfig::command_history`.
+    CommandHistory(String),
+
+    /// Received in response to `CommandHistory`, oldest first. (ID, Commands).
+    /// Empty if the instance is not running or history is disabled.
+    CommandHistoryResult(String, Vec<CommandRecord>),
+
+    /// Request the given number of running instances that received the
+    /// most commands, to find hot instances. Commands are counted from
+    /// when an instance wakes, so the count is reset when it passivates.
+    HotIds(usize),
+
+    /// Received in response to `HotIds`, most commands first.
+    /// (ID, Number of commands) for each instance.
+    HotIdsResult(Vec<(String, u64)>),
+
+    /// Find the IDs of running instances with the given secondary
+    /// index key. See `EntityActorProps::index_keys`.
+    FindByIndex(String),
+
+    /// Received in response to `FindByIndex`. (Index key, IDs)
+    FindByIndexResult(String, Vec<String>),
+
+    /// Sent by an instance to its entity manager once it has finished
+    /// replaying its events. See `Entity::ready`.
+    Ready(String),
+
+    /// Sent to the entity manager when an instance panics, before the
+    /// supervisor strategy restarts or stops it.
+    /// See `EntityActorConfig::restart_limit`.
+    Panicked(String),
+
+    /// Sent by an instance to its entity manager while it replays its
+    /// events. See `Entity::replay_progress`. (ID, Events applied, Total)
+    Replaying(String, u64, Option<u64>),
+
+    /// Request how far the instance with the given ID has replayed its
+    /// events while it wakes
+    ReplayProgress(String),
+
+    /// Received in response to `ReplayProgress`. (ID, Progress).
+    /// `None` if the instance is not waking, i.e. not running or ready.
+    ReplayProgressResult(String, Option<ReplayProgress>),
+
+    /// Sent by an instance to its entity manager when it replies to a
+    /// command. See `Entity::reply`.
+    Replied(String),
+
+    /// Sent by an instance to its entity manager before `Replied` when it
+    /// rejects a command, to count towards `ErrorStats` and so that the
+    /// reply doesn't reset the count of `EntityActorConfig::restart_limit`
+    Failed(String),
+
+    /// Sent by an instance to its entity manager when an event is
+    /// persisted, with the time it took. See `Acks`.
+    EventPersisted(String, Duration),
+
+    /// Subscribe an actor to the events of the instance with the given
+    /// ID, or of all instances if `None`. Subscribers receive
+    /// `ControlMsg::Event` and are unsubscribed when they terminate.
+    Subscribe(Option<String>, ActorRef<Msg>),
+
+    /// Events published within `EntityActorConfig::publish_batch_window`,
+    /// sent instead of `Event`. (ID, Event) for each event, in the order
+    /// they were published.
+    EventBatch(Vec<(String, Msg)>),
+
+    /// Sent by the entity manager to itself when the batch window ends
+    FlushEvents,
+
+    /// Sent periodically by the entity manager to the subscribers of all
+    /// instances, and to its events channel, when
+    /// `EntityActorConfig::emit_heartbeat` is set
+    Heartbeat(Heartbeat),
+
+    /// Sent by the entity manager to itself when the next heartbeat is due
+    EmitHeartbeat,
+
+    /// Replay the persisted events of the instance with the given ID to
+    /// an actor as `ControlMsg::Event`, followed by `ControlMsg::CaughtUp`,
+    /// then subscribe it as with `Subscribe`. No event is missed or
+    /// received twice across the switch, as long as the instance replies
+    /// to commands only once their events are published. See the README.
+    CatchUp(String, ActorRef<Msg>),
+
+    /// Sent to an actor catching up on an instance after the replayed
+    /// events, before any live events. (ID)
+    CaughtUp(String),
+
+    /// Replay the persisted events of the instance with the given ID to
+    /// an actor as `ControlMsg::Event`, followed by `ControlMsg::Replayed`,
+    /// e.g. to verify its history or build a one-off report.
+    ///
+    /// The events are loaded from the event store directly, whether or not
+    /// the instance is running, and the instance isn't woken or otherwise
+    /// affected. Events it hasn't persisted yet aren't included. Events are
+    /// sent as they were stored, see `Entity::decode`. They're loaded from
+    /// the keyspace of `Entity::persistence_conf`, as for `CmdExisting`.
+    ReplayTo(String, ActorRef<Msg>),
+
+    /// Sent to an actor after the events replayed by `ReplayTo`.
+    /// (ID, Number of events)
+    Replayed(String, usize),
+
+    /// Remove all subscriptions of an actor
+    Unsubscribe(ActorRef<Msg>),
+
+    /// An event persisted by the instance with the given ID.
+    /// Sent to the entity manager as each event is persisted, and
+    /// forwarded by the manager to subscribers.
+    Event(String, Box<Msg>),
+
+    /// An event persisted in serialized form. (Format, Bytes).
+    /// See `Entity::persist` and `Serializer`.
+    Serialized(String, Vec<u8>),
+}
+
+/// Why the entity manager rejected a command, received as
+/// `ControlMsg::CommandRejected`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RejectReason {
+    /// The command was sent as `ControlMsg::CmdExisting` and the
+    /// instance doesn't exist
+    NotFound,
+
+    /// The command exceeded the per ID rate limit.
+    /// See `EntityActorConfig::per_id_rate_limit`.
+    RateLimited,
+
+    /// The manager is stopping. Commands without a sender are sent to
+    /// dead letters instead.
+    Draining,
+
+    /// Too many commands are waiting for instances to wake, for the
+    /// instance to reply, or for the manager to resume. Commands without a sender are sent to dead
+    /// letters instead. See `EntityActorConfig::max_pending_cmds`,
+    /// `EntityActorConfig::max_in_flight_per_id` and
+    /// `EntityActorConfig::max_paused_cmds`.
+    Busy,
+
+    /// The instance could not be created to handle the command. Commands
+    /// without a sender are sent to dead letters instead.
+    CreateFailed,
+
+    /// The instance didn't reply to the command in time. It may still
+    /// handle the command. See `EntityActorConfig::reject_timed_out_cmds`.
+    TimedOut,
+
+    /// The manager is in read-only mode and the command would modify or
+    /// wake the instance. See `ControlMsg::ReadOnly`.
+    ReadOnly,
+
+    /// The command was seen recently. See `DedupMiddleware`.
+    Duplicate,
+
+    /// A `CommandMiddleware` refused the command, e.g. because it is invalid
+    Refused,
+
+    /// The command was sent as `ControlMsg::Create` and the instance
+    /// already exists, or is being created
+    AlreadyExists,
+
+    /// The manager is overloaded and couldn't route the command right
+    /// away. Commands without a sender are sent to dead letters instead.
+    /// See `EntityActorConfig::fail_fast`.
+    Overloaded,
+
+    /// The instance wasn't ready in time and was stopped. It may still
+    /// have handled the command. See `EntityActorConfig::init_timeout`.
+    InitTimeout,
+
+    /// The manager isn't accepting commands.
+    /// See `ControlMsg::SetAccepting`.
+    KillSwitch,
+
+    /// The instance failed repeatedly and isn't woken until its cooldown
+    /// ends. See `EntityActorConfig::restart_limit`.
+    CircuitOpen,
+
+    /// The instance ID, once normalized, can't name an actor, which may
+    /// only contain letters, digits, '-' and '_'.
+    /// See `EntityActorProps::normalize` and `EntityActorConfig::child_name`.
+    InvalidId,
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self {
+            RejectReason::NotFound => "instance not found",
+            RejectReason::RateLimited => "rate limited",
+            RejectReason::Draining => "entity manager is stopping",
+            RejectReason::Busy => "entity manager is busy",
+            RejectReason::CreateFailed => "instance could not be created",
+            RejectReason::TimedOut => "instance didn't reply in time",
+            RejectReason::ReadOnly => "entity manager is read-only",
+            RejectReason::Duplicate => "duplicate command",
+            RejectReason::Refused => "refused by middleware",
+            RejectReason::AlreadyExists => "instance already exists",
+            RejectReason::Overloaded => "entity manager is overloaded",
+            RejectReason::InitTimeout => "instance wasn't ready in time",
+            RejectReason::KillSwitch => "entity manager isn't accepting commands",
+            RejectReason::CircuitOpen => "instance is failing repeatedly",
+            RejectReason::InvalidId => "invalid instance ID",
+        };
+        f.write_str(reason)
+    }
+}
+
+/// The outcome of a command, replied by instances using `Entity::reply`
+#[derive(Clone, Debug)]
+pub enum CommandResult<Msg> {
+    /// The command was accepted and produced the given events
+    Accepted(Vec<Msg>),
+
+    /// The command was rejected for the given reason
+    Rejected(String),
+
+    /// The command was handled but produced no events, e.g. a query
+    /// or a command that doesn't change the state
+    NoOp,
+
+    /// The command was sent as `ControlMsg::CmdExpecting` and the instance
+    /// is at another version. (Expected version, Current version)
+    VersionConflict(u64, u64),
+}
+
+/// Statistics of an entity manager
+#[derive(Clone, Debug)]
+pub struct EntityStats {
+    /// Name of the entity
+    pub name: String,
+
+    /// Keyspace of the default persistence configuration passed to
+    /// instances. Instances that return their own `PersistenceConf`
+    /// may persist their events to another keyspace.
+    pub keyspace: String,
+
+    /// Number of instances currently running
+    pub instances: usize,
+
+    /// Number of event subscriptions
+    pub subscribers: usize,
+
+    /// Number of instances woken since the manager started
+    pub wakes: u64,
+
+    /// Number of instances passivated since the manager started,
+    /// including instances put to sleep after a period of inactivity
+    pub passivations: u64,
+
+    /// Time taken by instances to become ready after being woken,
+    /// i.e. the time taken to replay their events
+    pub wake_latency: LatencyStats,
+
+    /// Time taken to persist each event of instances that reply using
+    /// `Acks`, measured from `Acks::reply` to `Acks::persisted`. The call
+    /// to `persist_event` sends the event to the event store straight
+    /// away, so this includes any work the instance does between
+    /// persisting the events and replying, and the time the event store
+    /// takes to confirm them.
+    pub persist_latency: LatencyStats,
+
+    /// When the last passivation sweep ran, if one has run since the
+    /// manager started
+    pub last_sweep_at: Option<SystemTime>,
+
+    /// When the next passivation sweep is scheduled to run. A sweep that
+    /// is late by more than its interval is run on the next message the
+    /// manager receives.
+    pub next_sweep_at: SystemTime,
+}
+
+/// A sign of life of an entity manager, see `ControlMsg::Heartbeat`
+#[derive(Clone, Debug)]
+pub struct Heartbeat {
+    /// Name of the entity
+    pub name: String,
+
+    /// Number of instances currently running
+    pub instances: usize,
+
+    /// When the heartbeat was emitted
+    pub at: SystemTime,
+}
+
+/// Information on a running instance
+#[derive(Clone, Debug)]
+pub struct InstanceInfo {
+    pub id: String,
+
+    /// When the instance was woken
+    pub created_at: SystemTime,
+
+    /// When the instance last received a command
+    pub last_used: SystemTime,
+
+    /// Number of commands routed to the instance that it hasn't replied
+    /// to yet. riker doesn't expose the length of mailboxes, so this
+    /// approximates the backlog of the instance, including the command it
+    /// is handling. Only accurate for instances that reply to every
+    /// command using `Entity::reply` or `Acks`.
+    pub in_flight: usize,
+
+    /// How long the oldest of those commands has waited for a reply
+    pub oldest_in_flight: Option<Duration>,
+}
+
+/// A command received by an instance
+#[derive(Clone, Debug)]
+pub struct CommandRecord {
+    pub received_at: SystemTime,
+
+    /// `Debug` representation of the command
+    pub cmd: String,
+}
+
+/// How far a waking instance has replayed its events, received in
+/// response to `ControlMsg::ReplayProgress`
+#[derive(Clone, Debug)]
+pub struct ReplayProgress {
+    /// Number of events applied, as last reported by the instance.
+    /// Zero until the instance reports its progress.
+    pub applied: u64,
+
+    /// Number of events to replay, if the instance knows it
+    pub total: Option<u64>,
+
+    /// Time since the instance was woken
+    pub elapsed: Duration,
+}
+
+/// Minimum, maximum and average of recorded durations
+#[derive(Clone, Debug, Default)]
+pub struct LatencyStats {
+    /// Number of recorded durations
+    pub count: u64,
+    pub min: Duration,
+    pub max: Duration,
+    total: Duration,
+}
+
+impl LatencyStats {
+    pub fn record(&mut self, latency: Duration) {
+        if self.count == 0 || latency < self.min {
+            self.min = latency;
+        }
+        if latency > self.max {
+            self.max = latency;
+        }
+        self.total += latency;
+        self.count += 1;
+    }
+
+    /// Sum of the recorded durations
+    pub fn total(&self) -> Duration {
+        self.total
+    }
+
+    /// Average of the recorded durations, or zero if nothing was recorded
+    pub fn avg(&self) -> Duration {
+        if self.count == 0 {
+            Duration::from_secs(0)
+        } else {
+            self.total / self.count as u32
+        }
+    }
+}
+
+/// The outcome of a passivation sweep, received in response to
+/// `ControlMsg::SweepNow`
+#[derive(Clone, Debug, Default)]
+pub struct SweepStats {
+    /// Number of instances passivated
+    pub passivated: usize,
+
+    /// Number of instances asked whether they can be passivated, see
+    /// `EntityActorConfig::passivation_check_timeout`. They are passivated
+    /// later if they agree.
+    pub checking: usize,
+
+    /// Number of instances running after the sweep, including those
+    /// being asked
+    pub instances: usize,
+}
+
+/// Commands replied to by the instances of an entity over the error
+/// window, received in response to `ControlMsg::ErrorStats`
+#[derive(Clone, Debug, Default)]
+pub struct ErrorStats {
+    /// Length of the error window
+    pub window: Duration,
+
+    /// Number of commands replied to
+    pub total: u64,
+
+    /// Number of commands replied to with `CommandResult::Rejected`
+    pub failed: u64,
+}
+
+impl ErrorStats {
+    /// Fraction of the commands that failed, or zero if there were none
+    pub fn rate(&self) -> f64 {
+        if self.total == 0 {
+            0.0
+        } else {
+            self.failed as f64 / self.total as f64
+        }
+    }
+}
+
+/// Implemented by message types used with `Entity`.
+///
+/// Since all actors in a system share the same message type, the
+/// message type must be able to carry `ControlMsg`:
+///
+/// ```ignore
+/// #[derive(Clone, Debug)]
+/// pub enum Protocol {
+///     Control(ControlMsg<Protocol>),
+///     CreateAccountCmd(String),
+/// }
+///
+/// impl EntityMsg for Protocol {
+///     fn from_control(msg: ControlMsg<Protocol>) -> Self {
+///         Protocol::Control(msg)
+///     }
+///
+///     fn into_control(self) -> Result<ControlMsg<Protocol>, Self> {
+///         match self {
+///             Protocol::Control(msg) => Ok(msg),
+///             msg => Err(msg),
+///         }
+///     }
+/// }
+/// ```
+pub trait EntityMsg: Message {
+    /// Wraps a control message
+    fn from_control(msg: ControlMsg<Self>) -> Self;
+
+    /// Unwraps a control message, returning the original message
+    /// if it is not a control message
+    fn into_control(self) -> Result<ControlMsg<Self>, Self>;
+}
+
+impl<Msg: EntityMsg> From<ControlMsg<Msg>> for ActorMsg<Msg> {
+    fn from(msg: ControlMsg<Msg>) -> Self {
+        ActorMsg::User(Msg::from_control(msg))
+    }
+}
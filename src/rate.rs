@@ -0,0 +1,43 @@
+use std::time::Instant;
+
+use crate::secs;
+
+/// Token bucket rate limit
+#[derive(Clone, Debug)]
+pub struct RateLimit {
+    /// Maximum number of commands accepted in a burst
+    pub capacity: u32,
+
+    /// Number of commands added back to the bucket each second
+    pub refill_per_sec: u32,
+}
+
+pub(crate) struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(limit: &RateLimit) -> Self {
+        TokenBucket {
+            limit: limit.clone(),
+            tokens: f64::from(limit.capacity),
+            refilled: Instant::now()
+        }
+    }
+
+    pub(crate) fn try_take(&mut self) -> bool {
+        let now = Instant::now();
+        let refill = secs(now.duration_since(self.refilled)) * f64::from(self.limit.refill_per_sec);
+        self.tokens = (self.tokens + refill).min(f64::from(self.limit.capacity));
+        self.refilled = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
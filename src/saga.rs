@@ -0,0 +1,121 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use riker::actors::*;
+
+use crate::{CommandResult, ControlMsg, EntityMsg};
+
+/// The outcome of a saga, received in response to `ControlMsg::Saga`
+#[derive(Clone, Debug)]
+pub enum SagaOutcome {
+    /// Every step was accepted
+    Completed,
+
+    /// The step with the given ID failed for the given reason.
+    /// The compensating commands of the steps accepted before it were
+    /// sent, but not necessarily handled. (ID, Reason)
+    Compensated(String, String),
+}
+
+// A step of a saga and the command that undoes it
+struct Step<Msg> {
+    id: String,
+    cmd: Msg,
+    compensation: Option<Msg>,
+}
+
+// Routes the steps of a saga to the entity manager one at a time,
+// compensating the accepted steps if a step fails
+pub(crate) struct SagaCoordinator<Msg: Message> {
+    manager: ActorRef<Msg>,
+    sender: Option<ActorRef<Msg>>,
+    step_timeout: Option<Duration>,
+    steps: VecDeque<Step<Msg>>,
+    accepted: Vec<Step<Msg>>,
+    current: Option<Step<Msg>>,
+    step: usize,
+}
+
+pub(crate) type SagaArgs<Msg> = (ActorRef<Msg>, Option<ActorRef<Msg>>, Option<Duration>, Vec<(String, Msg, Option<Msg>)>);
+
+impl<Msg: EntityMsg> SagaCoordinator<Msg> {
+    pub(crate) fn actor((manager, sender, step_timeout, steps): SagaArgs<Msg>) -> BoxActor<Msg> {
+        let steps = steps.into_iter()
+                        .map(|(id, cmd, compensation)| Step { id, cmd, compensation })
+                        .collect();
+
+        Box::new(SagaCoordinator {
+            manager,
+            sender,
+            step_timeout,
+            steps,
+            accepted: Vec::new(),
+            current: None,
+            step: 0,
+        })
+    }
+
+    fn next(&mut self, ctx: &Context<Msg>) {
+        if let Some(step) = self.current.take() {
+            self.accepted.push(step);
+        }
+
+        match self.steps.pop_front() {
+            Some(step) => {
+                self.step += 1;
+                self.manager.tell(CQMsg::Cmd(step.id.clone(), step.cmd.clone()), Some(ctx.myself()));
+                if let Some(timeout) = self.step_timeout {
+                    ctx.schedule_once(timeout,
+                                        ctx.myself(),
+                                        None,
+                                        ControlMsg::SagaStepTimeout(self.step));
+                }
+                self.current = Some(step);
+            }
+            None => self.finish(ctx, SagaOutcome::Completed)
+        }
+    }
+
+    fn fail(&mut self, ctx: &Context<Msg>, reason: String) {
+        let id = self.current.take().map(|step| step.id).unwrap_or_default();
+        debug!("CQRS: Saga: step {} failed: {}. Compensating {} steps", id, reason, self.accepted.len());
+
+        // undo in reverse order
+        for step in self.accepted.drain(..).rev() {
+            if let Some(compensation) = step.compensation {
+                self.manager.tell(CQMsg::Cmd(step.id, compensation), None);
+            }
+        }
+
+        self.finish(ctx, SagaOutcome::Compensated(id, reason));
+    }
+
+    fn finish(&mut self, ctx: &Context<Msg>, outcome: SagaOutcome) {
+        if let Some(sender) = self.sender.take() {
+            sender.tell(ControlMsg::SagaResult(outcome), Some(ctx.myself()));
+        }
+        ctx.stop(&ctx.myself());
+    }
+}
+
+impl<Msg: EntityMsg> Actor for SagaCoordinator<Msg> {
+    type Msg = Msg;
+
+    fn pre_start(&mut self, ctx: &Context<Msg>) {
+        self.next(ctx);
+    }
+
+    fn receive(&mut self, ctx: &Context<Msg>, msg: Msg, _: Option<ActorRef<Msg>>) {
+        if self.current.is_none() {
+            return;
+        }
+
+        match msg.into_control() {
+            Ok(ControlMsg::CommandResult(_, CommandResult::Rejected(reason))) => self.fail(ctx, reason),
+            Ok(ControlMsg::CommandResult(..)) => self.next(ctx),
+            Ok(ControlMsg::CommandRejected(_, reason)) => self.fail(ctx, reason.to_string()),
+            Ok(ControlMsg::SagaStepTimeout(step)) if step == self.step => self.fail(ctx, "timed out".into()),
+            _ => {}
+        }
+    }
+}
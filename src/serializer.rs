@@ -0,0 +1,99 @@
+use std::fmt;
+
+use config::Config;
+
+use crate::{ControlMsg, EntityMsg};
+
+/// Converts the events of an entity to and from bytes.
+///
+/// By default an entity has no serializer and events are persisted as the
+/// message type itself, leaving the format to the event store. Instances
+/// that use a serializer persist events using `Entity::persist`, which
+/// stores them as `ControlMsg::Serialized`, and decode them in
+/// `apply_event` using `Entity::decode`. This allows choosing a format per
+/// entity, e.g. JSON for debuggability or bincode for speed.
+///
+/// The format name is persisted with each event. Events written in one
+/// format can't be decoded by a serializer of another format, so changing
+/// the format of an entity that has persisted events requires migrating
+/// them. Within a format, `deserialize` must continue to accept events
+/// written by earlier versions of the message type, since they are replayed
+/// every time an instance wakes.
+pub trait Serializer<Msg>: Send + Sync {
+    /// Name of the format, e.g. "json"
+    fn format(&self) -> &str;
+
+    fn serialize(&self, evt: &Msg) -> Vec<u8>;
+
+    fn deserialize(&self, bytes: &[u8]) -> Result<Msg, String>;
+}
+
+/// Serialization format configured for the named entity, if any.
+///
+/// Reads `cqrs.entities.<name>.serializer`, falling back to
+/// `cqrs.serializer`. The format is selected by the entity's
+/// `EntityActorProps`, which can use this to choose a `Serializer`.
+pub fn serializer_format(config: &Config, name: &str) -> Option<String> {
+    config.get_str(&format!("cqrs.entities.{}.serializer", name))
+            .or_else(|_| config.get_str("cqrs.serializer"))
+            .ok()
+}
+
+/// Maximum size of a serialized event configured for the named entity,
+/// if any.
+///
+/// Reads `cqrs.entities.<name>.max_event_bytes`, falling back to
+/// `cqrs.max_event_bytes`. Events are persisted by the instances, so the
+/// entity's `EntityActorProps` passes the limit to them along with the
+/// serializer, for `Entity::try_persist`.
+pub fn max_event_bytes(config: &Config, name: &str) -> Option<usize> {
+    config.get_int(&format!("cqrs.entities.{}.max_event_bytes", name))
+            .or_else(|_| config.get_int("cqrs.max_event_bytes"))
+            .ok()
+            .map(|max| max as usize)
+}
+
+/// An event that wasn't persisted because its serialized size exceeds
+/// the maximum, see `Entity::try_persist`
+#[derive(Clone, Debug, PartialEq)]
+pub struct EventTooLarge {
+    pub size: usize,
+    pub max: usize,
+}
+
+impl fmt::Display for EventTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "event of {} bytes exceeds the maximum of {} bytes", self.size, self.max)
+    }
+}
+
+pub(crate) fn encode<Msg: EntityMsg>(serializer: &dyn Serializer<Msg>, evt: &Msg) -> Msg {
+    let bytes = serializer.serialize(evt);
+    Msg::from_control(ControlMsg::Serialized(serializer.format().to_string(), bytes))
+}
+
+pub(crate) fn encode_bounded<Msg: EntityMsg>(serializer: &dyn Serializer<Msg>,
+                                            evt: &Msg,
+                                            max: Option<usize>) -> Result<Msg, EventTooLarge> {
+    let bytes = serializer.serialize(evt);
+    match max {
+        Some(max) if bytes.len() > max => Err(EventTooLarge { size: bytes.len(), max }),
+        _ => Ok(Msg::from_control(ControlMsg::Serialized(serializer.format().to_string(), bytes)))
+    }
+}
+
+// Events that were persisted without a serializer are returned as they are,
+// so that a serializer can be adopted by an entity with existing events
+pub(crate) fn decode<Msg: EntityMsg>(serializer: &dyn Serializer<Msg>, evt: Msg) -> Result<Msg, String> {
+    match evt.into_control() {
+        Ok(ControlMsg::Serialized(format, bytes)) => {
+            if format == serializer.format() {
+                serializer.deserialize(&bytes)
+            } else {
+                Err(format!("Event was serialized as {}, expected {}", format, serializer.format()))
+            }
+        }
+        Ok(msg) => Ok(Msg::from_control(msg)),
+        Err(evt) => Ok(evt),
+    }
+}
@@ -0,0 +1,333 @@
+//! Harness for testing entities with deterministic assertions.
+//!
+//! `TestEntity` wraps an entity manager and sends commands synchronously,
+//! collecting the events each command produced and the replies sent to
+//! the sender. Events are collected from the entity's subscription.
+//!
+//! `TestEntity::audit` checks that the instances known to the manager
+//! match its child actors, to catch lifecycle bugs where they diverge.
+//!
+//! `FaultInjector` simulates failures of instance creation, persistence
+//! and replay, to test how an application handles them.
+//!
+//! Requires the `testkit` feature.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+
+use riker::actors::*;
+
+use crate::{CommandResult, ControlMsg, Entity, EntityActorConfig, EntityActorProps, EntityMsg};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The result of a command sent using `TestEntity::cmd`
+#[derive(Clone, Debug)]
+pub struct Outcome<Msg> {
+    /// Events persisted by the instance while handling the command
+    pub events: Vec<Msg>,
+
+    /// The result replied by the instance using `Entity::reply`
+    pub result: Option<CommandResult<Msg>>,
+
+    /// Other messages sent to the sender of the command, including
+    /// control messages such as `ControlMsg::CommandRejected`
+    pub replies: Vec<Msg>,
+}
+
+/// An entity manager driven synchronously from a test
+pub struct TestEntity<Msg: EntityMsg> {
+    entity: ActorRef<Msg>,
+    collector: ActorRef<Msg>,
+    rx: Receiver<Collected<Msg>>,
+    events: HashMap<String, Vec<Msg>>,
+}
+
+impl<Msg: EntityMsg> TestEntity<Msg> {
+    /// Creates the entity manager, as `Entity::new`
+    pub fn new<Pro>(sys: &ActorSystem<Msg>,
+                    instance_fact: Pro,
+                    name: &str,
+                    conf: Option<EntityActorConfig>) -> Result<Self, CreateError>
+        where Pro: EntityActorProps<Msg=Msg> + 'static
+    {
+        let entity = Entity::new(sys, instance_fact, name, conf)?;
+
+        let (tx, rx) = channel();
+        let collector = sys.tmp_actor_of(Props::new_args(Box::new(Collector::actor), tx))?;
+        entity.tell(ControlMsg::Subscribe(None, collector.clone()), None);
+
+        Ok(TestEntity {
+            entity,
+            collector,
+            rx,
+            events: HashMap::new(),
+        })
+    }
+
+    /// The entity manager
+    pub fn entity(&self) -> &ActorRef<Msg> {
+        &self.entity
+    }
+
+    /// Sends a command to the instance with the given ID and waits until
+    /// the instance has handled it.
+    ///
+    /// Panics if the instance or the manager does not respond in time.
+    pub fn cmd(&mut self, id: &str, cmd: Msg) -> Outcome<Msg> {
+        let mut outcome = Outcome {
+            events: Vec::new(),
+            result: None,
+            replies: Vec::new(),
+        };
+
+        self.entity.tell(CQMsg::Cmd(id.to_string(), cmd), Some(self.collector.clone()));
+
+        // the instance responds to `Identify` once it has handled the command
+        // and persisted its events. Events are forwarded by the manager, so
+        // a round trip to the manager ensures they have all been received.
+        self.entity.tell(ControlMsg::Identify(id.to_string()), Some(self.collector.clone()));
+        self.collect(id, &mut outcome, |c| match c {
+            Collected::Info => true,
+            _ => false
+        });
+
+        self.entity.tell(ControlMsg::Stats, Some(self.collector.clone()));
+        self.collect(id, &mut outcome, |c| match c {
+            Collected::Reply(msg) => match msg.clone().into_control() {
+                Ok(ControlMsg::StatsResult(_)) => true,
+                _ => false
+            },
+            _ => false
+        });
+
+        outcome
+    }
+
+    /// Checks that the running instances known to the manager match its
+    /// child actors. See `ConsistencyReport`.
+    ///
+    /// Panics if the manager does not respond in time.
+    pub fn audit(&mut self) -> ConsistencyReport {
+        self.entity.tell(ControlMsg::AuditConsistency, Some(self.collector.clone()));
+        loop {
+            let collected = self.rx.recv_timeout(TIMEOUT)
+                                .expect("TestEntity: timed out waiting for the entity");
+            match collected {
+                Collected::Event(id, evt) => self.events.entry(id).or_default().push(evt),
+                Collected::Reply(msg) => {
+                    if let Ok(ControlMsg::AuditConsistencyResult(report)) = msg.into_control() {
+                        return report;
+                    }
+                }
+                Collected::Info => {}
+            }
+        }
+    }
+
+    /// All events persisted by the instance with the given ID since
+    /// the harness was created
+    pub fn events(&self, id: &str) -> &[Msg] {
+        self.events.get(id).map(|events| events.as_slice()).unwrap_or(&[])
+    }
+
+    fn collect<F>(&mut self, id: &str, outcome: &mut Outcome<Msg>, done: F)
+        where F: Fn(&Collected<Msg>) -> bool
+    {
+        loop {
+            let collected = self.rx.recv_timeout(TIMEOUT)
+                                .expect("TestEntity: timed out waiting for the entity");
+            if done(&collected) {
+                return;
+            }
+
+            match collected {
+                Collected::Event(evt_id, evt) => {
+                    if evt_id == id {
+                        outcome.events.push(evt.clone());
+                    }
+                    self.events.entry(evt_id).or_default().push(evt);
+                }
+                Collected::Reply(msg) => {
+                    match msg.into_control() {
+                        Ok(ControlMsg::CommandResult(ref result_id, ref result)) if result_id == id => {
+                            outcome.result = Some(result.clone());
+                        }
+                        Ok(msg) => outcome.replies.push(Msg::from_control(msg)),
+                        Err(msg) => outcome.replies.push(msg),
+                    }
+                }
+                Collected::Info => {}
+            }
+        }
+    }
+}
+
+/// The result of comparing the running instances known to an entity
+/// manager with its child actors, see `TestEntity::audit`.
+///
+/// Passivated instances are stopped asynchronously, so they are reported
+/// as orphaned until they have stopped.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConsistencyReport {
+    /// Names of child actors that are not running instances
+    pub orphaned: Vec<String>,
+
+    /// IDs of running instances without a child actor
+    pub missing: Vec<String>,
+}
+
+impl ConsistencyReport {
+    pub fn is_consistent(&self) -> bool {
+        self.orphaned.is_empty() && self.missing.is_empty()
+    }
+}
+
+enum Collected<Msg> {
+    Event(String, Msg),
+    Reply(Msg),
+    Info,
+}
+
+// Forwards everything it receives to the harness
+struct Collector<Msg> {
+    tx: Sender<Collected<Msg>>,
+}
+
+impl<Msg: EntityMsg> Collector<Msg> {
+    fn actor(tx: Sender<Collected<Msg>>) -> BoxActor<Msg> {
+        Box::new(Collector { tx })
+    }
+}
+
+impl<Msg: EntityMsg> Actor for Collector<Msg> {
+    type Msg = Msg;
+
+    fn receive(&mut self,
+                _: &Context<Msg>,
+                msg: Msg,
+                _: Option<ActorRef<Msg>>) {
+        let collected = match msg.into_control() {
+            Ok(ControlMsg::Event(id, evt)) => Collected::Event(id, *evt),
+            Ok(msg) => Collected::Reply(Msg::from_control(msg)),
+            Err(msg) => Collected::Reply(msg),
+        };
+        drop(self.tx.send(collected));
+    }
+
+    fn other_receive(&mut self,
+                    _: &Context<Msg>,
+                    msg: ActorMsg<Msg>,
+                    _: Option<ActorRef<Msg>>) {
+        if let ActorMsg::Info(_) = msg {
+            drop(self.tx.send(Collected::Info));
+        }
+    }
+}
+
+/// A failure injected by `FaultInjector`
+#[derive(Clone, Debug)]
+pub enum Fault {
+    /// The manager fails to create the instance. The sender of the command
+    /// receives `ControlMsg::CommandRejected` with `RejectReason::CreateFailed`.
+    Create,
+
+    /// Events of the instance are not persisted.
+    /// See `FaultInjector::persist_event`.
+    Persist,
+
+    /// The instance takes the given time to replay its events.
+    /// See `FaultInjector::delay_replay`.
+    DelayReplay(Duration),
+}
+
+/// Injects faults into an entity, for the given IDs or for all instances.
+///
+/// The manager checks for `Fault::Create` when it is set as
+/// `EntityActorConfig::faults`. Persistence and replay are performed by
+/// the instances, so instances under test check for `Fault::Persist` and
+/// `Fault::DelayReplay` by persisting events with
+/// `FaultInjector::persist_event` and calling `FaultInjector::delay_replay`
+/// from `post_start`. Clones share the same faults, so faults can be
+/// injected and cleared while the entity is running.
+#[derive(Clone, Debug, Default)]
+pub struct FaultInjector {
+    faults: Arc<Mutex<Vec<(Option<String>, Fault)>>>,
+}
+
+impl FaultInjector {
+    pub fn new() -> Self {
+        FaultInjector::default()
+    }
+
+    /// Injects a fault for the instance with the given ID,
+    /// or for all instances if `None`
+    pub fn inject(&self, id: Option<&str>, fault: Fault) {
+        self.faults.lock().unwrap().push((id.map(String::from), fault));
+    }
+
+    /// Removes all faults
+    pub fn clear(&self) {
+        self.faults.lock().unwrap().clear();
+    }
+
+    /// Whether creating the instance with the given ID should fail
+    pub fn fail_create(&self, id: &str) -> bool {
+        self.find(id, |fault| match fault {
+            Fault::Create => Some(()),
+            _ => None
+        }).is_some()
+    }
+
+    /// Whether persisting events of the instance with the given ID should fail
+    pub fn fail_persist(&self, id: &str) -> bool {
+        self.find(id, |fault| match fault {
+            Fault::Persist => Some(()),
+            _ => None
+        }).is_some()
+    }
+
+    /// Time the instance with the given ID should take to replay its events
+    pub fn replay_delay(&self, id: &str) -> Option<Duration> {
+        self.find(id, |fault| match fault {
+            Fault::DelayReplay(delay) => Some(*delay),
+            _ => None
+        })
+    }
+
+    /// Persists an event of the calling instance unless persistence fails
+    /// for the ID, returning whether the event was persisted.
+    ///
+    /// A failed event is never applied, as if the event store had lost it.
+    pub fn persist_event<Msg: EntityMsg>(&self, ctx: &Context<Msg>, id: &str, evt: Msg) -> bool {
+        if self.fail_persist(id) {
+            false
+        } else {
+            ctx.persist_event(evt);
+            true
+        }
+    }
+
+    /// Blocks the calling instance for the replay delay of the ID, if any.
+    /// Call from `post_start`, before `Entity::ready`.
+    pub fn delay_replay(&self, id: &str) {
+        if let Some(delay) = self.replay_delay(id) {
+            thread::sleep(delay);
+        }
+    }
+
+    fn find<T, F>(&self, id: &str, f: F) -> Option<T>
+        where F: Fn(&Fault) -> Option<T>
+    {
+        self.faults
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(fault_id, _)| fault_id.as_ref().map(|fault_id| fault_id == id).unwrap_or(true))
+            .filter_map(|(_, fault)| f(fault))
+            .next()
+    }
+}
@@ -0,0 +1,52 @@
+use riker::actors::*;
+
+use crate::{CommandResult, Entity, EntityMsg};
+
+/// The version of an instance, for optimistic concurrency control of
+/// commands sent as `ControlMsg::CmdExpecting`.
+///
+/// The version is the number of events the instance has applied,
+/// including the events replayed when it woke, so an instance without
+/// events is at version 0. Instances call `Version::applied` from
+/// `apply_event`, so the version is current whenever the instance
+/// receives a command, for the reason given on `Acks`.
+///
+/// Callers learn the version from the instance's state, e.g. by
+/// including `Version::current` in the state returned to `GetState`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Version {
+    current: u64,
+}
+
+impl Version {
+    pub fn new() -> Self {
+        Version::default()
+    }
+
+    /// The number of events applied by the instance
+    pub fn current(&self) -> u64 {
+        self.current
+    }
+
+    /// Notifies that an event was applied. Call from `apply_event`.
+    pub fn applied(&mut self) {
+        self.current += 1;
+    }
+
+    /// Whether the instance is at the expected version. Otherwise replies
+    /// to the sender with `CommandResult::VersionConflict`, and the
+    /// command must not be handled.
+    pub fn check<Msg: EntityMsg>(&self,
+                                ctx: &Context<Msg>,
+                                id: &str,
+                                expected: u64,
+                                sender: &Option<ActorRef<Msg>>) -> bool {
+        if expected == self.current {
+            return true;
+        }
+
+        debug!("CQRS: ID: {}, State: expected version {}, at version {}", id, expected, self.current);
+        Entity::reply(ctx, id, sender.clone(), CommandResult::VersionConflict(expected, self.current));
+        false
+    }
+}